@@ -26,6 +26,7 @@ pub fn default_configuration() -> schema::RustbaseConfig {
                 .unwrap()
                 .to_path_buf(),
             dustdata: None,
+            implicit_create: None,
         },
     }
 }
@@ -26,6 +26,8 @@ pub struct Tls {
 pub struct Storage {
     pub path: std::path::PathBuf,
     pub dustdata: Option<DustDataStorageConfig>,
+    /// whether writing to a database that does not exist creates it (default `true`)
+    pub implicit_create: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -0,0 +1,1122 @@
+use bson::Bson;
+use std::fmt::{self, Display, Formatter, Write};
+
+use super::literal;
+use super::parser::{
+    path_to_string, ASTNode, Aggregate, Condition, DropPolicy, Guard, Keywords, ListOptions,
+    Operator, PathSegment, Verbs, WatchScope,
+};
+
+impl ASTNode {
+    /// `to_rbql` writes the node back as canonical RBQL
+    ///
+    /// Parsing the result gives back the same node, as long as its values can be written in RBQL
+    /// (values bound from parameters may not, e.g. 32-bit integers or regular expressions).
+    /// Passwords are written as is, use `Display` to hide them.
+    ///
+    /// Returns:
+    ///
+    /// The RBQL text of the node.
+    pub fn to_rbql(&self) -> String {
+        let mut rbql = String::new();
+        write_node(&mut rbql, self).unwrap();
+
+        rbql
+    }
+}
+
+/// Canonical RBQL with the values of `password` assignments hidden, safe to log
+impl Display for ASTNode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.redacted().to_rbql())
+    }
+}
+
+impl Display for Keywords {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let keyword = match self {
+            Keywords::Insert => "insert",
+            Keywords::Get => "get",
+            Keywords::Update => "update",
+            Keywords::Upsert => "upsert",
+            Keywords::Delete => "delete",
+            Keywords::List => "list",
+            Keywords::Incr => "incr",
+            Keywords::Decr => "decr",
+        };
+
+        write!(f, "{}", keyword)
+    }
+}
+
+impl Display for Verbs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Verbs::User => write!(f, "user"),
+            Verbs::Database => write!(f, "database"),
+        }
+    }
+}
+
+impl Display for Operator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let operator = match self {
+            Operator::Eq => "=",
+            Operator::Ne => "!=",
+            Operator::Lt => "<",
+            Operator::Le => "<=",
+            Operator::Gt => ">",
+            Operator::Ge => ">=",
+        };
+
+        write!(f, "{}", operator)
+    }
+}
+
+fn write_node<W: Write>(w: &mut W, node: &ASTNode) -> fmt::Result {
+    match node {
+        ASTNode::ExplainExpression { statement } => {
+            write!(w, "explain ")?;
+            write_node(w, statement)
+        }
+
+        ASTNode::UseExpression { database } => {
+            write!(w, "use ")?;
+            write_node(w, database)
+        }
+
+        ASTNode::WhoamiExpression => write!(w, "whoami"),
+
+        ASTNode::BeginExpression => write!(w, "begin"),
+
+        ASTNode::CommitExpression => write!(w, "commit"),
+
+        ASTNode::RollbackExpression => write!(w, "rollback"),
+
+        ASTNode::CreateExpression {
+            verb,
+            ident,
+            options,
+        } => {
+            write!(w, "create {} ", verb)?;
+            write_node(w, ident)?;
+
+            if let Some(options) = options {
+                write!(w, " with ")?;
+                write_node(w, options)?;
+            }
+
+            Ok(())
+        }
+
+        ASTNode::RenameExpression { ident, to } => {
+            write!(w, "rename ")?;
+            write_node(w, ident)?;
+            write!(w, " to ")?;
+            write_node(w, to)
+        }
+
+        ASTNode::CopyExpression {
+            ident,
+            to,
+            database,
+        } => {
+            write!(w, "copy ")?;
+            write_node(w, ident)?;
+            write!(w, " to ")?;
+            write_node(w, to)?;
+
+            if let Some(database) = database {
+                write!(w, " in ")?;
+                write_node(w, database)?;
+            }
+
+            Ok(())
+        }
+
+        ASTNode::CreateSchemaExpression { database, schema } => {
+            write!(w, "create schema on ")?;
+            write_node(w, database)?;
+            write!(w, " with ")?;
+            write_node(w, schema)
+        }
+
+        ASTNode::DeleteSchemaExpression { database } => {
+            write!(w, "delete schema on ")?;
+            write_node(w, database)
+        }
+
+        ASTNode::CreateIndexExpression {
+            database,
+            field,
+            unique,
+        } => {
+            write!(
+                w,
+                "create {}index on ",
+                if *unique { "unique " } else { "" }
+            )?;
+            write_node(w, database)?;
+            write!(w, "({})", path_to_string(field))
+        }
+
+        ASTNode::DropIndexExpression { database, field } => {
+            write!(w, "drop index on ")?;
+            write_node(w, database)?;
+            write!(w, "({})", path_to_string(field))
+        }
+
+        ASTNode::RebuildIndexExpression { database, field } => {
+            write!(w, "rebuild index on ")?;
+            write_node(w, database)?;
+            write!(w, "({})", path_to_string(field))
+        }
+
+        ASTNode::MoveExpression { ident, database } => {
+            write!(w, "move ")?;
+            write_node(w, ident)?;
+            write!(w, " to ")?;
+            write_node(w, database)
+        }
+
+        ASTNode::ExpireExpression { ident, seconds } => {
+            write!(w, "expire ")?;
+            write_node(w, ident)?;
+            write!(w, " {}", seconds)
+        }
+
+        ASTNode::PersistExpression { ident } => {
+            write!(w, "persist ")?;
+            write_node(w, ident)
+        }
+
+        ASTNode::WatchExpression { scope, after } => {
+            write!(w, "watch ")?;
+
+            match scope {
+                WatchScope::Key(key) => write!(w, "{}", key)?,
+                WatchScope::Prefix(prefix) => {
+                    write!(w, "prefix ")?;
+                    write_string(w, prefix)?;
+                }
+                WatchScope::Database => write!(w, "database")?,
+            }
+
+            if let Some(after) = after {
+                write!(w, " after {}", after)?;
+            }
+
+            Ok(())
+        }
+
+        ASTNode::UnwatchExpression { watch } => match watch {
+            Some(watch) => write!(w, "unwatch {}", watch),
+            None => write!(w, "unwatch"),
+        },
+
+        ASTNode::PublishExpression { channel, message } => {
+            write!(w, "publish ")?;
+            write_string(w, channel)?;
+            write!(w, " ")?;
+            write_node(w, message)
+        }
+
+        ASTNode::SubscribeExpression {
+            pattern,
+            buffer,
+            drop,
+        } => {
+            write!(w, "subscribe ")?;
+            write_string(w, pattern)?;
+
+            if let Some(buffer) = buffer {
+                write!(w, " buffer {}", buffer)?;
+            }
+
+            match drop {
+                Some(DropPolicy::Oldest) => write!(w, " drop oldest"),
+                Some(DropPolicy::Newest) => write!(w, " drop newest"),
+                None => Ok(()),
+            }
+        }
+
+        ASTNode::UnsubscribeExpression { subscription } => match subscription {
+            Some(subscription) => write!(w, "unsubscribe {}", subscription),
+            None => write!(w, "unsubscribe"),
+        },
+
+        ASTNode::TtlExpression { ident } => {
+            write!(w, "ttl ")?;
+            write_node(w, ident)
+        }
+
+        ASTNode::AssignmentExpression { ident, value } => {
+            write!(w, "{} = ", ident)?;
+            write_node(w, value)
+        }
+
+        ASTNode::MonadicExpression {
+            keyword,
+            verb,
+            expr,
+        } => {
+            write!(w, "{} {}", keyword, verb)?;
+
+            for node in expr.iter().flatten() {
+                write!(w, " ")?;
+                write_node(w, node)?;
+            }
+
+            Ok(())
+        }
+
+        ASTNode::IntoExpression {
+            keyword,
+            json,
+            ident,
+            guard,
+            expire,
+        } => {
+            write!(w, "{} ", keyword)?;
+            write_node(w, json)?;
+            write!(w, " into ")?;
+            write_node(w, ident)?;
+
+            match guard {
+                Some(Guard::Absent) => write!(w, " if not exists")?,
+                Some(Guard::Equals(expected)) => {
+                    write!(w, " if ")?;
+                    write_bson(w, expected)?;
+                }
+                None => {}
+            }
+
+            if let Some(seconds) = expire {
+                write!(w, " expire {}", seconds)?;
+            }
+
+            Ok(())
+        }
+
+        ASTNode::SingleExpression { keyword, ident } => {
+            write!(w, "{}", keyword)?;
+
+            if let Some(ident) = ident {
+                write!(w, " ")?;
+                write_node(w, ident)?;
+            }
+
+            Ok(())
+        }
+
+        ASTNode::MultiExpression { keyword, idents } => {
+            write!(w, "{} ", keyword)?;
+
+            for (i, ident) in idents.iter().enumerate() {
+                if i > 0 {
+                    write!(w, ", ")?;
+                }
+
+                write_node(w, ident)?;
+            }
+
+            Ok(())
+        }
+
+        ASTNode::ListExpression { keyword, options } => {
+            write!(w, "{}", keyword)?;
+            write_list_options(w, options)
+        }
+
+        ASTNode::MultiIntoExpression { keyword, entries } => {
+            write!(w, "{} ", keyword)?;
+
+            for (i, (json, ident)) in entries.iter().enumerate() {
+                if i > 0 {
+                    write!(w, ", ")?;
+                }
+
+                write_node(w, json)?;
+                write!(w, " into ")?;
+                write_node(w, ident)?;
+            }
+
+            Ok(())
+        }
+
+        ASTNode::PathExpression {
+            ident,
+            path,
+            projection,
+        } => {
+            write!(w, "{}", ident)?;
+
+            for segment in path {
+                write!(w, ".{}", segment)?;
+            }
+
+            if let Some(projection) = projection {
+                let paths = projection
+                    .iter()
+                    .map(|path| path_to_string(path))
+                    .collect::<Vec<_>>();
+
+                write!(w, " {{{}}}", paths.join(", "))?;
+            }
+
+            Ok(())
+        }
+
+        ASTNode::WhereExpression { keyword, condition } => {
+            write!(w, "{} where ", keyword)?;
+            write_condition(w, condition)
+        }
+
+        ASTNode::AggregateExpression {
+            function,
+            field,
+            condition,
+            group_by,
+        } => {
+            write_aggregate(w, *function, field.as_deref(), condition.as_ref())?;
+
+            if let Some(group_by) = group_by {
+                write!(w, " group by {}", path_to_string(group_by))?;
+            }
+
+            Ok(())
+        }
+
+        ASTNode::PatchExpression {
+            keyword,
+            ident,
+            patch,
+        } => {
+            write!(w, "{} ", keyword)?;
+            write_node(w, ident)?;
+            write!(w, " with ")?;
+            write_node(w, patch)
+        }
+
+        ASTNode::CounterExpression { keyword, ident, by } => {
+            write!(w, "{} ", keyword)?;
+            write_node(w, ident)?;
+
+            if let Some(by) = by {
+                write!(w, " by ")?;
+                write_node(w, by)?;
+            }
+
+            Ok(())
+        }
+
+        ASTNode::Bson(value) => write_bson(w, value),
+        ASTNode::Identifier(ident) => write!(w, "{}", ident),
+    }
+}
+
+fn write_aggregate<W: Write>(
+    w: &mut W,
+    function: Aggregate,
+    field: Option<&[PathSegment]>,
+    condition: Option<&Condition>,
+) -> fmt::Result {
+    write!(w, "{}", function)?;
+
+    if let Some(field) = field {
+        write!(w, " {}", path_to_string(field))?;
+    }
+
+    if let Some(condition) = condition {
+        write!(w, " where ")?;
+        write_condition(w, condition)?;
+    }
+
+    Ok(())
+}
+
+fn write_list_options<W: Write>(w: &mut W, options: &ListOptions) -> fmt::Result {
+    let clauses = [
+        ("prefix", &options.prefix),
+        ("from", &options.from),
+        ("to", &options.to),
+        ("after", &options.after),
+        ("cursor", &options.cursor),
+    ];
+
+    for (clause, value) in clauses {
+        if let Some(value) = value {
+            write!(w, " {} ", clause)?;
+            write_string(w, value)?;
+        }
+    }
+
+    if let Some(limit) = options.limit {
+        write!(w, " limit {}", limit)?;
+    }
+
+    if options.with_values {
+        write!(w, " with values")?;
+    }
+
+    Ok(())
+}
+
+// `and` binds tighter than `or`, so only nested conditions of the same kind need parentheses to
+// keep their shape
+fn write_condition<W: Write>(w: &mut W, condition: &Condition) -> fmt::Result {
+    match condition {
+        Condition::And(conditions) => {
+            for (i, condition) in conditions.iter().enumerate() {
+                if i > 0 {
+                    write!(w, " and ")?;
+                }
+
+                match condition {
+                    Condition::And(_) | Condition::Or(_) => {
+                        write!(w, "(")?;
+                        write_condition(w, condition)?;
+                        write!(w, ")")?;
+                    }
+                    _ => write_condition(w, condition)?,
+                }
+            }
+
+            Ok(())
+        }
+
+        Condition::Or(conditions) => {
+            for (i, condition) in conditions.iter().enumerate() {
+                if i > 0 {
+                    write!(w, " or ")?;
+                }
+
+                match condition {
+                    Condition::Or(_) => {
+                        write!(w, "(")?;
+                        write_condition(w, condition)?;
+                        write!(w, ")")?;
+                    }
+                    _ => write_condition(w, condition)?,
+                }
+            }
+
+            Ok(())
+        }
+
+        Condition::Compare {
+            field,
+            operator,
+            value,
+        } => {
+            write!(w, "{} {} ", path_to_string(field), operator)?;
+            write_bson(w, value)
+        }
+
+        Condition::In { field, values } => {
+            write!(w, "{} in ", path_to_string(field))?;
+            write_bson(w, &Bson::Array(values.clone()))
+        }
+
+        Condition::Exists { field } => write!(w, "{} exists", path_to_string(field)),
+    }
+}
+
+fn write_string<W: Write>(w: &mut W, s: &str) -> fmt::Result {
+    // JSON escapes are the escapes of RBQL strings
+    write!(w, "{}", serde_json::Value::String(s.to_string()))
+}
+
+fn write_bson<W: Write>(w: &mut W, value: &Bson) -> fmt::Result {
+    match value {
+        Bson::Null => write!(w, "null"),
+        Bson::Boolean(b) => write!(w, "{}", b),
+        Bson::Int32(i) => write!(w, "{}", i),
+        Bson::Int64(i) => write!(w, "{}", i),
+        // `Debug` always writes a fraction or an exponent, so the number is read back as a double
+        Bson::Double(f) if f.is_finite() => write!(w, "{:?}", f),
+        Bson::String(s) => write_string(w, s),
+
+        Bson::Array(values) => {
+            write!(w, "[")?;
+
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    write!(w, ", ")?;
+                }
+
+                write_bson(w, value)?;
+            }
+
+            write!(w, "]")
+        }
+
+        Bson::Document(document) => {
+            write!(w, "{{")?;
+
+            for (i, (key, value)) in document.iter().enumerate() {
+                if i > 0 {
+                    write!(w, ", ")?;
+                }
+
+                write_string(w, key)?;
+                write!(w, ": ")?;
+                write_bson(w, value)?;
+            }
+
+            write!(w, "}}")
+        }
+
+        Bson::DateTime(date) => match date.try_to_rfc3339_string() {
+            Ok(date) => write!(w, "date(\"{}\")", date),
+            Err(_) => write_extjson(w, value),
+        },
+
+        Bson::ObjectId(oid) => write!(w, "oid(\"{}\")", oid.to_hex()),
+
+        Bson::Binary(binary) if binary.subtype == bson::spec::BinarySubtype::Generic => {
+            let extjson = value.clone().into_canonical_extjson();
+
+            match extjson["$binary"]["base64"].as_str() {
+                Some(base64) => write!(w, "bin(\"{}\")", base64),
+                None => write_extjson(w, value),
+            }
+        }
+
+        Bson::Decimal128(decimal) => {
+            write!(w, "decimal(\"{}\")", literal::format_decimal128(decimal))
+        }
+
+        _ => write_extjson(w, value),
+    }
+}
+
+// values RBQL has no literal for are written as their extended JSON document
+fn write_extjson<W: Write>(w: &mut W, value: &Bson) -> fmt::Result {
+    write!(w, "{}", value.clone().into_relaxed_extjson())
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{doc, Bson, DateTime, Document};
+    use proptest::prelude::*;
+
+    use crate::query::literal;
+    use crate::query::parser::{
+        parse, ASTNode, Aggregate, Condition, DropPolicy, Guard, Keywords, ListOptions, Operator,
+        PathSegment, Verbs, WatchScope,
+    };
+
+    // the words of the grammar, a key named like one of them may be read as the word
+    const WORDS: &str =
+        "insert get delete update upsert list incr decr user database explain use whoami \
+        begin commit rollback create schema index unique drop rebuild rename copy move \
+        expire persist ttl watch unwatch publish subscribe unsubscribe into if not \
+        exists with values on to in by where and or group count sum min max avg prefix \
+        from after cursor limit buffer oldest newest true false null date oid bin \
+        decimal";
+
+    fn reparse(rbql: &str) -> ASTNode {
+        let mut statements = match parse(rbql, &Document::new()) {
+            Ok(statements) => statements,
+            Err(e) => panic!("{} does not parse: {}", rbql, e.message),
+        };
+
+        assert_eq!(statements.len(), 1, "{}", rbql);
+
+        statements.pop().unwrap().ast
+    }
+
+    fn ident() -> impl Strategy<Value = String> {
+        "[a-zA-Z][a-zA-Z0-9_]{0,7}".prop_filter("a word of the grammar", |ident| {
+            !WORDS.split_whitespace().any(|word| word == ident)
+        })
+    }
+
+    // strings with the characters RBQL has to escape
+    fn string() -> impl Strategy<Value = String> {
+        let c = prop_oneof![
+            any::<char>(),
+            Just('"'),
+            Just('\\'),
+            Just('/'),
+            Just('\n'),
+            Just('\t'),
+            Just('\u{8}'),
+            Just('\u{0}'),
+            Just('é'),
+            Just('😀'),
+        ];
+
+        prop::collection::vec(c, 0..10).prop_map(String::from_iter)
+    }
+
+    fn path() -> impl Strategy<Value = Vec<PathSegment>> {
+        let segment = prop_oneof![
+            ident().prop_map(PathSegment::Field),
+            any::<usize>().prop_map(PathSegment::Index),
+        ];
+
+        (ident(), prop::collection::vec(segment, 0..3)).prop_map(|(first, rest)| {
+            std::iter::once(PathSegment::Field(first))
+                .chain(rest)
+                .collect()
+        })
+    }
+
+    fn decimal128() -> impl Strategy<Value = Bson> {
+        (any::<bool>(), 0..10u128.pow(34), -6176..=6111i32).prop_map(
+            |(negative, coefficient, exponent)| {
+                let sign = if negative { "-" } else { "" };
+                let literal = format!("{}{}E{}", sign, coefficient, exponent);
+
+                Bson::Decimal128(literal::parse_decimal128(&literal).unwrap())
+            },
+        )
+    }
+
+    // the values RBQL has a literal for, 32-bit integers and the other BSON types are only bound
+    fn value() -> impl Strategy<Value = Bson> {
+        let leaf = prop_oneof![
+            Just(Bson::Null),
+            any::<bool>().prop_map(Bson::Boolean),
+            any::<i64>().prop_map(Bson::Int64),
+            any::<f64>()
+                .prop_filter("finite", |f| f.is_finite())
+                .prop_map(Bson::Double),
+            string().prop_map(Bson::String),
+            (-62135596800000..253402300800000i64)
+                .prop_map(|millis| Bson::DateTime(DateTime::from_millis(millis))),
+            any::<[u8; 12]>()
+                .prop_map(|bytes| Bson::ObjectId(bson::oid::ObjectId::from_bytes(bytes))),
+            prop::collection::vec(any::<u8>(), 0..16).prop_map(|bytes| {
+                Bson::Binary(bson::Binary {
+                    subtype: bson::spec::BinarySubtype::Generic,
+                    bytes,
+                })
+            }),
+            decimal128(),
+        ];
+
+        leaf.prop_recursive(3, 24, 4, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..4).prop_map(Bson::Array),
+                prop::collection::vec((string(), inner), 0..4)
+                    .prop_map(|pairs| Bson::Document(pairs.into_iter().collect())),
+            ]
+        })
+    }
+
+    fn document() -> impl Strategy<Value = Bson> {
+        prop::collection::vec((string(), value()), 0..4)
+            .prop_map(|pairs| Bson::Document(pairs.into_iter().collect::<Document>()))
+    }
+
+    fn key() -> impl Strategy<Value = Box<ASTNode>> {
+        ident().prop_map(|ident| Box::new(ASTNode::Identifier(ident)))
+    }
+
+    // sequence numbers, seconds and limits are written as 64-bit integers
+    fn positive() -> impl Strategy<Value = u64> {
+        1..=i64::MAX as u64
+    }
+
+    fn condition() -> impl Strategy<Value = Condition> {
+        let operator = prop_oneof![
+            Just(Operator::Eq),
+            Just(Operator::Ne),
+            Just(Operator::Lt),
+            Just(Operator::Le),
+            Just(Operator::Gt),
+            Just(Operator::Ge),
+        ];
+
+        let leaf = prop_oneof![
+            (path(), operator, value()).prop_map(|(field, operator, value)| Condition::Compare {
+                field,
+                operator,
+                value,
+            }),
+            (path(), prop::collection::vec(value(), 0..3))
+                .prop_map(|(field, values)| Condition::In { field, values }),
+            path().prop_map(|field| Condition::Exists { field }),
+        ];
+
+        // a single condition is never wrapped in `and` or `or` once parsed
+        leaf.prop_recursive(3, 12, 3, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 2..4).prop_map(Condition::And),
+                prop::collection::vec(inner, 2..4).prop_map(Condition::Or),
+            ]
+        })
+    }
+
+    fn list_options() -> impl Strategy<Value = ListOptions> {
+        (
+            prop::option::of(string()),
+            prop::option::of(string()),
+            prop::option::of(string()),
+            prop::option::of(string()),
+            prop::option::of(string()),
+            prop::option::of(positive()),
+            any::<bool>(),
+        )
+            .prop_map(
+                |(prefix, from, to, after, cursor, limit, with_values)| ListOptions {
+                    prefix,
+                    from,
+                    to,
+                    after,
+                    cursor,
+                    limit: limit.map(|limit| limit as usize),
+                    with_values,
+                },
+            )
+            // `list` without clauses is a plain `list`
+            .prop_filter("no clause", |options| *options != ListOptions::default())
+    }
+
+    fn assignment(ident: impl Strategy<Value = String>) -> impl Strategy<Value = ASTNode> {
+        (ident, value()).prop_map(|(ident, value)| ASTNode::AssignmentExpression {
+            ident,
+            value: Box::new(ASTNode::Bson(value)),
+        })
+    }
+
+    fn user() -> impl Strategy<Value = ASTNode> {
+        let keyword = prop_oneof![
+            Just(Keywords::Insert),
+            Just(Keywords::Update),
+            Just(Keywords::Delete),
+            Just(Keywords::List),
+        ];
+
+        let verb = prop_oneof![Just(Verbs::User), Just(Verbs::Database)];
+
+        let expr = prop_oneof![
+            assignment(prop_oneof![ident(), Just("password".to_string())]),
+            ident().prop_map(ASTNode::Identifier),
+        ];
+
+        (
+            keyword,
+            verb,
+            prop::option::of(prop::collection::vec(expr, 1..4)),
+        )
+            .prop_map(|(keyword, verb, expr)| ASTNode::MonadicExpression {
+                keyword,
+                verb,
+                expr,
+            })
+    }
+
+    fn key_path() -> impl Strategy<Value = ASTNode> {
+        let projection = prop::option::of(prop::collection::vec(path(), 1..3));
+
+        (ident(), path(), projection).prop_map(|(ident, mut path, projection)| {
+            path.remove(0);
+
+            // a key read as a whole is a plain identifier
+            if path.is_empty() && projection.is_none() {
+                return ASTNode::Identifier(ident);
+            }
+
+            ASTNode::PathExpression {
+                ident,
+                path,
+                projection,
+            }
+        })
+    }
+
+    fn data() -> impl Strategy<Value = ASTNode> {
+        let into_keyword = prop_oneof![
+            Just(Keywords::Insert),
+            Just(Keywords::Update),
+            Just(Keywords::Upsert),
+        ];
+
+        let guard = prop_oneof![Just(Guard::Absent), value().prop_map(Guard::Equals)];
+
+        let aggregate = prop_oneof![
+            Just(Aggregate::Count),
+            Just(Aggregate::Sum),
+            Just(Aggregate::Min),
+            Just(Aggregate::Max),
+            Just(Aggregate::Avg),
+        ];
+
+        prop_oneof![
+            (
+                into_keyword,
+                value(),
+                key(),
+                prop::option::of(guard),
+                prop::option::of(positive())
+            )
+                .prop_map(|(keyword, json, ident, guard, expire)| {
+                    ASTNode::IntoExpression {
+                        keyword,
+                        json: Box::new(ASTNode::Bson(json)),
+                        ident,
+                        guard,
+                        expire,
+                    }
+                }),
+            (
+                prop_oneof![
+                    Just(Keywords::Get),
+                    Just(Keywords::Delete),
+                    Just(Keywords::List)
+                ],
+                prop::option::of(key_path())
+            )
+                .prop_map(|(keyword, ident)| ASTNode::SingleExpression {
+                    keyword,
+                    ident: ident.map(Box::new),
+                }),
+            (
+                prop_oneof![Just(Keywords::Get), Just(Keywords::Delete)],
+                prop::collection::vec(ident().prop_map(ASTNode::Identifier), 2..4)
+            )
+                .prop_map(|(keyword, idents)| ASTNode::MultiExpression { keyword, idents }),
+            prop::collection::vec((value(), ident()), 2..4).prop_map(|entries| {
+                ASTNode::MultiIntoExpression {
+                    keyword: Keywords::Insert,
+                    entries: entries
+                        .into_iter()
+                        .map(|(json, ident)| (ASTNode::Bson(json), ASTNode::Identifier(ident)))
+                        .collect(),
+                }
+            }),
+            list_options().prop_map(|options| ASTNode::ListExpression {
+                keyword: Keywords::List,
+                options,
+            }),
+            condition().prop_map(|condition| ASTNode::WhereExpression {
+                keyword: Keywords::Get,
+                condition,
+            }),
+            (
+                aggregate,
+                prop::option::of(path()),
+                prop::option::of(condition()),
+                prop::option::of(path())
+            )
+                .prop_map(|(function, field, condition, group_by)| {
+                    // only count goes without a field
+                    let field = match function {
+                        Aggregate::Count => field,
+                        _ => Some(
+                            field.unwrap_or_else(|| vec![PathSegment::Field("field".to_string())]),
+                        ),
+                    };
+
+                    ASTNode::AggregateExpression {
+                        function,
+                        field,
+                        condition,
+                        group_by,
+                    }
+                }),
+            (key(), document()).prop_map(|(ident, patch)| ASTNode::PatchExpression {
+                keyword: Keywords::Update,
+                ident,
+                patch: Box::new(ASTNode::Bson(patch)),
+            }),
+            (
+                prop_oneof![Just(Keywords::Incr), Just(Keywords::Decr)],
+                key(),
+                prop::option::of(prop_oneof![
+                    any::<i64>().prop_map(Bson::Int64),
+                    any::<f64>()
+                        .prop_filter("finite", |f| f.is_finite())
+                        .prop_map(Bson::Double),
+                ])
+            )
+                .prop_map(|(keyword, ident, by)| ASTNode::CounterExpression {
+                    keyword,
+                    ident,
+                    by: by.map(|by| Box::new(ASTNode::Bson(by))),
+                }),
+        ]
+    }
+
+    fn admin() -> impl Strategy<Value = ASTNode> {
+        let watch_scope = prop_oneof![
+            ident().prop_map(WatchScope::Key),
+            string().prop_map(WatchScope::Prefix),
+            Just(WatchScope::Database),
+        ];
+
+        let drop = prop_oneof![Just(DropPolicy::Oldest), Just(DropPolicy::Newest)];
+
+        let index =
+            (key(), path(), any::<u8>()).prop_map(|(database, field, kind)| match kind % 3 {
+                0 => ASTNode::CreateIndexExpression {
+                    database,
+                    field,
+                    unique: kind % 2 == 0,
+                },
+                1 => ASTNode::DropIndexExpression { database, field },
+                _ => ASTNode::RebuildIndexExpression { database, field },
+            });
+
+        prop_oneof![
+            key().prop_map(|database| ASTNode::UseExpression { database }),
+            Just(ASTNode::WhoamiExpression),
+            Just(ASTNode::BeginExpression),
+            Just(ASTNode::CommitExpression),
+            Just(ASTNode::RollbackExpression),
+            (
+                prop_oneof![Just(Verbs::User), Just(Verbs::Database)],
+                key(),
+                prop::option::of(document())
+            )
+                .prop_map(|(verb, ident, options)| ASTNode::CreateExpression {
+                    verb,
+                    ident,
+                    options: options.map(|options| Box::new(ASTNode::Bson(options))),
+                }),
+            (key(), document()).prop_map(|(database, schema)| {
+                ASTNode::CreateSchemaExpression {
+                    database,
+                    schema: Box::new(ASTNode::Bson(schema)),
+                }
+            }),
+            key().prop_map(|database| ASTNode::DeleteSchemaExpression { database }),
+            index,
+            (key(), key()).prop_map(|(ident, to)| ASTNode::RenameExpression { ident, to }),
+            (key(), key(), prop::option::of(key())).prop_map(|(ident, to, database)| {
+                ASTNode::CopyExpression {
+                    ident,
+                    to,
+                    database,
+                }
+            }),
+            (key(), key())
+                .prop_map(|(ident, database)| ASTNode::MoveExpression { ident, database }),
+            (key(), positive())
+                .prop_map(|(ident, seconds)| ASTNode::ExpireExpression { ident, seconds }),
+            key().prop_map(|ident| ASTNode::PersistExpression { ident }),
+            key().prop_map(|ident| ASTNode::TtlExpression { ident }),
+            (watch_scope, prop::option::of(0..=i64::MAX as u64))
+                .prop_map(|(scope, after)| ASTNode::WatchExpression { scope, after }),
+            prop::option::of(0..=i64::MAX as u64)
+                .prop_map(|watch| ASTNode::UnwatchExpression { watch }),
+            (string(), value()).prop_map(|(channel, message)| ASTNode::PublishExpression {
+                channel,
+                message: Box::new(ASTNode::Bson(message)),
+            }),
+            (
+                string(),
+                prop::option::of(positive()),
+                prop::option::of(drop)
+            )
+                .prop_map(|(pattern, buffer, drop)| ASTNode::SubscribeExpression {
+                    pattern,
+                    buffer: buffer.map(|buffer| buffer as usize),
+                    drop,
+                }),
+            prop::option::of(0..=i64::MAX as u64)
+                .prop_map(|subscription| ASTNode::UnsubscribeExpression { subscription }),
+            assignment(ident()),
+            user(),
+        ]
+    }
+
+    fn statement() -> impl Strategy<Value = ASTNode> {
+        let statement = prop_oneof![data(), admin()];
+
+        prop_oneof![
+            4 => statement.clone(),
+            1 => statement.prop_map(|statement| ASTNode::ExplainExpression {
+                statement: Box::new(statement),
+            }),
+        ]
+    }
+
+    // statements whose values are all bound to placeholders, with the parameters binding them
+    fn bound() -> impl Strategy<Value = (String, Document)> {
+        (value(), ident(), positive(), string(), value(), path()).prop_flat_map(
+            |(json, key, seconds, prefix, expected, field)| {
+                let field = match &field[0] {
+                    PathSegment::Field(field) => field.clone(),
+                    PathSegment::Index(_) => unreachable!(),
+                };
+
+                let params = doc! {
+                    "json": json,
+                    "1": key,
+                    "seconds": seconds as i64,
+                    "prefix": prefix,
+                    "expected": expected,
+                    "field": field,
+                };
+
+                prop_oneof![
+                    Just("insert $json into $1 if $expected expire $seconds".to_string()),
+                    Just("upsert $json into $1 if not exists".to_string()),
+                    Just("list prefix $prefix from $prefix limit $seconds with values".to_string()),
+                    Just("watch prefix $prefix after $seconds".to_string()),
+                    Just("get where $field = $json or $field in [$expected, $json]".to_string()),
+                    Just("publish $prefix $json".to_string()),
+                    Just("insert user username = $1 password = $json".to_string()),
+                ]
+                .prop_map(move |query| (query, params.clone()))
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn to_rbql_parses_back_to_the_same_statement(ast in statement()) {
+            prop_assert_eq!(reparse(&ast.to_rbql()), ast);
+        }
+
+        #[test]
+        fn bound_values_are_written_as_literals((query, params) in bound()) {
+            let ast = parse(&query, &params).unwrap().pop().unwrap().ast;
+
+            prop_assert_eq!(reparse(&ast.to_rbql()), ast);
+        }
+
+        #[test]
+        fn display_parses_back_with_passwords_hidden(ast in statement()) {
+            let rbql = ast.to_string();
+
+            prop_assert_eq!(reparse(&rbql), ast.redacted());
+        }
+
+        #[test]
+        fn passwords_are_hidden(password in string()) {
+            let ast = ASTNode::MonadicExpression {
+                keyword: Keywords::Insert,
+                verb: Verbs::User,
+                expr: Some(vec![
+                    ASTNode::AssignmentExpression {
+                        ident: "username".to_string(),
+                        value: Box::new(ASTNode::Bson(Bson::String("bob".to_string()))),
+                    },
+                    ASTNode::AssignmentExpression {
+                        ident: "password".to_string(),
+                        value: Box::new(ASTNode::Bson(Bson::String(password))),
+                    },
+                ]),
+            };
+
+            let explained = ASTNode::ExplainExpression {
+                statement: Box::new(ast.clone()),
+            };
+
+            prop_assert_eq!(
+                ast.to_string(),
+                r#"insert user username = "bob" password = "***""#
+            );
+            prop_assert_eq!(
+                explained.to_string(),
+                r#"explain insert user username = "bob" password = "***""#
+            );
+        }
+    }
+}
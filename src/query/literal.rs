@@ -0,0 +1,453 @@
+use super::{QueryError, QueryErrorType, Result};
+use bson::oid::ObjectId;
+use bson::{Bson, DateTime, Decimal128};
+
+// IEEE 754-2008 decimal128 limits (BID encoding)
+const DECIMAL128_MAX_DIGITS: usize = 34;
+const DECIMAL128_EXPONENT_BIAS: i32 = 6176;
+const DECIMAL128_MAX_EXPONENT: i32 = 6111;
+const DECIMAL128_MIN_EXPONENT: i32 = -6176;
+
+fn literal_error(message: String) -> QueryError {
+    QueryError::new(QueryErrorType::InvalidLiteral, message)
+}
+
+/// `parse_number` turns a number literal into a `Bson` value
+///
+/// Integers are stored as `Int64`, anything with a fraction or an exponent is stored as `Double`.
+/// Integers that do not fit in 64 bits are rejected instead of being silently rounded.
+///
+/// Arguments:
+///
+/// * `literal`: The number as written in the query.
+///
+/// Returns:
+///
+/// A `Bson::Int64` or `Bson::Double`.
+pub fn parse_number(literal: &str) -> Result<Bson> {
+    if literal.contains(['.', 'e', 'E']) {
+        let number: f64 = literal
+            .parse()
+            .map_err(|_| literal_error(format!("invalid number {}", literal)))?;
+
+        if !number.is_finite() {
+            return Err(literal_error(format!("number {} is out of range", literal)));
+        }
+
+        return Ok(Bson::Double(number));
+    }
+
+    match literal.parse::<i64>() {
+        Ok(number) => Ok(Bson::Int64(number)),
+        Err(_) => Err(literal_error(format!(
+            "integer {} is out of range, use decimal(\"{}\") instead",
+            literal, literal
+        ))),
+    }
+}
+
+/// `unescape` resolves the escape sequences of a string literal
+///
+/// Arguments:
+///
+/// * `literal`: The content of the string literal, without the quotes.
+///
+/// Returns:
+///
+/// The unescaped string.
+pub fn unescape(literal: &str) -> Result<String> {
+    let mut unescaped = String::with_capacity(literal.len());
+    let mut chars = literal.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        let escaped = match chars.next() {
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('/') => '/',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('u') => {
+                let high = read_code_unit(&mut chars)?;
+
+                let code = if (0xD800..0xDC00).contains(&high) {
+                    // a high surrogate must be followed by a low surrogate
+                    if chars.next() != Some('\\') || chars.next() != Some('u') {
+                        return Err(literal_error("unpaired surrogate in string".to_string()));
+                    }
+
+                    let low = read_code_unit(&mut chars)?;
+
+                    if !(0xDC00..0xE000).contains(&low) {
+                        return Err(literal_error("unpaired surrogate in string".to_string()));
+                    }
+
+                    0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                } else {
+                    high
+                };
+
+                char::from_u32(code)
+                    .ok_or_else(|| literal_error("unpaired surrogate in string".to_string()))?
+            }
+            _ => return Err(literal_error("invalid escape sequence".to_string())),
+        };
+
+        unescaped.push(escaped);
+    }
+
+    Ok(unescaped)
+}
+
+fn read_code_unit(chars: &mut std::str::Chars) -> Result<u32> {
+    let hex: String = chars.by_ref().take(4).collect();
+
+    if hex.len() != 4 {
+        return Err(literal_error("invalid unicode escape".to_string()));
+    }
+
+    u32::from_str_radix(&hex, 16).map_err(|_| literal_error("invalid unicode escape".to_string()))
+}
+
+/// `parse_typed` builds the value of a typed literal such as `date("...")` or `oid("...")`
+///
+/// Arguments:
+///
+/// * `constructor`: The name of the constructor (`date`, `oid`, `bin` or `decimal`).
+/// * `argument`: The unescaped string passed to the constructor.
+///
+/// Returns:
+///
+/// The `Bson` value of the literal.
+pub fn parse_typed(constructor: &str, argument: &str) -> Result<Bson> {
+    match constructor {
+        "date" => DateTime::parse_rfc3339_str(argument)
+            .map(Bson::DateTime)
+            .map_err(|_| literal_error(format!("invalid RFC 3339 date {}", argument))),
+
+        "oid" => ObjectId::parse_str(argument)
+            .map(Bson::ObjectId)
+            .map_err(|_| literal_error(format!("invalid ObjectId {}", argument))),
+
+        "bin" => {
+            let extjson = serde_json::json!({
+                "$binary": {
+                    "base64": argument,
+                    "subType": "00",
+                }
+            });
+
+            match Bson::try_from(extjson) {
+                Ok(binary @ Bson::Binary(_)) => Ok(binary),
+                _ => Err(literal_error(format!("invalid base64 {}", argument))),
+            }
+        }
+
+        "decimal" => parse_decimal128(argument).map(Bson::Decimal128),
+
+        _ => Err(literal_error(format!(
+            "unknown constructor {}",
+            constructor
+        ))),
+    }
+}
+
+/// `parse_decimal128` encodes a decimal string as an IEEE 754-2008 decimal128 (BID encoding)
+///
+/// The value is stored exactly, so literals with more than 34 significant digits are rejected.
+///
+/// Arguments:
+///
+/// * `literal`: The decimal number, e.g. `-12.50` or `1e-3`.
+///
+/// Returns:
+///
+/// A `Decimal128`.
+pub fn parse_decimal128(literal: &str) -> Result<Decimal128> {
+    let invalid = || literal_error(format!("invalid decimal {}", literal));
+
+    let (negative, unsigned) = match literal.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, literal.strip_prefix('+').unwrap_or(literal)),
+    };
+
+    let (mantissa, exponent) = match unsigned.find(['e', 'E']) {
+        Some(index) => {
+            let exponent: i32 = unsigned[index + 1..].parse().map_err(|_| invalid())?;
+            (&unsigned[..index], exponent)
+        }
+        None => (unsigned, 0),
+    };
+
+    let (integer, fraction) = match mantissa.split_once('.') {
+        Some((integer, fraction)) => (integer, fraction),
+        None => (mantissa, ""),
+    };
+
+    if integer.is_empty() && fraction.is_empty() {
+        return Err(invalid());
+    }
+
+    let digits: String = integer.chars().chain(fraction.chars()).collect();
+
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+
+    let significant = digits.trim_start_matches('0');
+
+    if significant.len() > DECIMAL128_MAX_DIGITS {
+        return Err(literal_error(format!(
+            "decimal {} has more than {} significant digits",
+            literal, DECIMAL128_MAX_DIGITS
+        )));
+    }
+
+    let coefficient: u128 = if significant.is_empty() {
+        0
+    } else {
+        significant.parse().map_err(|_| invalid())?
+    };
+
+    let exponent = exponent
+        .checked_sub(fraction.len() as i32)
+        .ok_or_else(invalid)?;
+
+    if !(DECIMAL128_MIN_EXPONENT..=DECIMAL128_MAX_EXPONENT).contains(&exponent) {
+        return Err(literal_error(format!(
+            "decimal {} is out of range",
+            literal
+        )));
+    }
+
+    let biased_exponent = (exponent + DECIMAL128_EXPONENT_BIAS) as u128;
+
+    let mut bits = (biased_exponent << 113) | coefficient;
+
+    if negative {
+        bits |= 1 << 127;
+    }
+
+    Ok(Decimal128::from_bytes(bits.to_le_bytes()))
+}
+
+/// `format_decimal128` writes a decimal128 (BID encoding) as a decimal string
+///
+/// The digits of the coefficient are all kept, so `parse_decimal128` gives back the same value.
+///
+/// Arguments:
+///
+/// * `decimal`: The decimal to write.
+///
+/// Returns:
+///
+/// The decimal, e.g. `-12.50`, `0.005` or `1E3`.
+pub fn format_decimal128(decimal: &Decimal128) -> String {
+    let bits = u128::from_le_bytes(decimal.bytes());
+    let sign = if bits >> 127 == 1 { "-" } else { "" };
+
+    match (bits >> 122) & 0x1f {
+        0x1f => return "NaN".to_string(),
+        0x1e => return format!("{}Infinity", sign),
+        _ => {}
+    }
+
+    // with the `11` combination prefix the coefficient is always too large, so the value is zero
+    let (biased_exponent, coefficient) = if (bits >> 125) & 0b11 == 0b11 {
+        ((bits >> 111) & 0x3fff, 0)
+    } else {
+        ((bits >> 113) & 0x3fff, bits & ((1 << 113) - 1))
+    };
+
+    let exponent = biased_exponent as i32 - DECIMAL128_EXPONENT_BIAS;
+
+    let digits = if coefficient.to_string().len() > DECIMAL128_MAX_DIGITS {
+        "0".to_string()
+    } else {
+        coefficient.to_string()
+    };
+
+    if exponent > 0 {
+        return format!("{}{}E{}", sign, digits, exponent);
+    }
+
+    let scale = exponent.unsigned_abs() as usize;
+
+    if scale == 0 {
+        return format!("{}{}", sign, digits);
+    }
+
+    let digits = format!("{:0>width$}", digits, width = scale + 1);
+    let (integer, fraction) = digits.split_at(digits.len() - scale);
+
+    format!("{}{}.{}", sign, integer, fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(literal: &str) -> String {
+        let decimal = parse_decimal128(literal).unwrap();
+        let formatted = format_decimal128(&decimal);
+
+        assert_eq!(
+            parse_decimal128(&formatted).unwrap().bytes(),
+            decimal.bytes(),
+            "{} is written as {}",
+            literal,
+            formatted
+        );
+
+        formatted
+    }
+
+    fn bits(literal: &str) -> u128 {
+        u128::from_le_bytes(parse_decimal128(literal).unwrap().bytes())
+    }
+
+    #[test]
+    fn decimals_are_encoded_as_bid() {
+        assert_eq!(bits("1"), 0x3040_0000_0000_0000_0000_0000_0000_0001);
+        assert_eq!(bits("-1"), 0xb040_0000_0000_0000_0000_0000_0000_0001);
+        assert_eq!(bits("12.50"), 0x303c_0000_0000_0000_0000_0000_0000_04e2);
+        assert_eq!(bits("1e3"), 0x3046_0000_0000_0000_0000_0000_0000_0001);
+    }
+
+    #[test]
+    fn decimals_keep_their_digits() {
+        assert_eq!(decimal("-12.50"), "-12.50");
+        assert_eq!(decimal("+12.50"), "12.50");
+        assert_eq!(decimal("0.005"), "0.005");
+        assert_eq!(decimal(".5"), "0.5");
+        assert_eq!(decimal("5."), "5");
+        assert_eq!(decimal("1e3"), "1E3");
+        assert_eq!(decimal("1.5E-3"), "0.0015");
+        assert_eq!(decimal("0"), "0");
+        assert_eq!(decimal("0.00"), "0.00");
+        assert_eq!(decimal("000123"), "123");
+    }
+
+    #[test]
+    fn negative_zero_keeps_its_sign() {
+        assert_eq!(decimal("-0"), "-0");
+        assert_eq!(decimal("-0.0"), "-0.0");
+        assert_ne!(bits("-0"), bits("0"));
+    }
+
+    #[test]
+    fn decimals_at_the_exponent_limits() {
+        assert_eq!(decimal("1e6111"), "1E6111");
+        assert_eq!(decimal("-1e6111"), "-1E6111");
+        assert_eq!(decimal("1e-6176"), format!("0.{}1", "0".repeat(6175)));
+
+        assert!(parse_decimal128("1e6112").is_err());
+        assert!(parse_decimal128("1e-6177").is_err());
+        assert!(parse_decimal128("0.1e-6176").is_err());
+        assert!(parse_decimal128("1e99999999999").is_err());
+    }
+
+    #[test]
+    fn decimals_have_at_most_34_significant_digits() {
+        let digits = "9".repeat(34);
+
+        assert_eq!(decimal(&digits), digits);
+        assert_eq!(decimal(&format!("-0.{}", digits)), format!("-0.{}", digits));
+        assert_eq!(decimal(&format!("0000{}", digits)), digits);
+
+        assert!(parse_decimal128(&format!("{}9", digits)).is_err());
+        assert!(parse_decimal128(&format!("1.{}", digits)).is_err());
+    }
+
+    #[test]
+    fn invalid_decimals_are_rejected() {
+        for literal in [
+            "", "-", ".", "e3", "1e", "1.2.3", "--1", "1,5", "0x10", "NaN",
+        ] {
+            assert!(parse_decimal128(literal).is_err(), "{}", literal);
+        }
+    }
+
+    #[test]
+    fn special_decimals_are_formatted() {
+        let special = |bits: u128| format_decimal128(&Decimal128::from_bytes(bits.to_le_bytes()));
+
+        assert_eq!(special(0x7c00 << 112), "NaN");
+        assert_eq!(special(0x7800 << 112), "Infinity");
+        assert_eq!(special(0xf800 << 112), "-Infinity");
+        // a coefficient above 34 digits can't be encoded, such a value is zero
+        assert_eq!(special((0b11 << 125) | (6177 << 111) | 1), "0E1");
+        assert_eq!(special((6176 << 113) | 10u128.pow(34)), "0");
+    }
+
+    #[test]
+    fn surrogate_pairs_are_joined() {
+        assert_eq!(unescape(r"\ud83d\ude00").unwrap(), "😀");
+        assert_eq!(unescape(r"a\ud834\udd1eb").unwrap(), "a𝄞b");
+        assert_eq!(unescape(r"\u00e9\u0041").unwrap(), "éA");
+    }
+
+    #[test]
+    fn unpaired_surrogates_are_rejected() {
+        for literal in [
+            r"\ud83d",
+            r"\ud83dx",
+            r"\ud83d\n",
+            r"\ud83d\u0041",
+            r"\ud83d\ud83d",
+            r"\ude00",
+            r"\ude00\ud83d",
+        ] {
+            assert!(unescape(literal).is_err(), "{}", literal);
+        }
+    }
+
+    #[test]
+    fn escapes_are_resolved() {
+        assert_eq!(
+            unescape(r#"\"\\\/\b\f\n\r\t"#).unwrap(),
+            "\"\\/\u{8}\u{c}\n\r\t"
+        );
+
+        for literal in [r"\x", r"\u12", r"\u12g4", "\\"] {
+            assert!(unescape(literal).is_err(), "{}", literal);
+        }
+    }
+
+    #[test]
+    fn integers_out_of_64_bits_are_rejected() {
+        assert_eq!(
+            parse_number("9223372036854775807").unwrap(),
+            Bson::Int64(i64::MAX)
+        );
+        assert_eq!(
+            parse_number("-9223372036854775808").unwrap(),
+            Bson::Int64(i64::MIN)
+        );
+
+        assert!(parse_number("9223372036854775808").is_err());
+        assert!(parse_number("-9223372036854775809").is_err());
+        assert!(parse_number("100000000000000000000000").is_err());
+    }
+
+    #[test]
+    fn numbers_with_a_fraction_or_an_exponent_are_doubles() {
+        assert_eq!(parse_number("1.5").unwrap(), Bson::Double(1.5));
+        assert_eq!(parse_number("1e3").unwrap(), Bson::Double(1000.0));
+        assert_eq!(parse_number("-2E-2").unwrap(), Bson::Double(-0.02));
+        assert_eq!(
+            parse_number("9223372036854775808.0").unwrap(),
+            Bson::Double(9223372036854775808.0)
+        );
+
+        assert!(parse_number("1e309").is_err());
+        assert!(parse_number("-1e309").is_err());
+    }
+}
@@ -1,3 +1,8 @@
+use pest::Span;
+use serde::{Deserialize, Serialize};
+
+mod format;
+mod literal;
 pub mod parser;
 
 type Result<T> = std::result::Result<T, QueryError>;
@@ -6,7 +11,114 @@ type Result<T> = std::result::Result<T, QueryError>;
 pub enum QueryErrorType {
     SyntaxError,
     UnexpectedToken,
+    InvalidLiteral,
+    InvalidParameter,
 }
 
 #[derive(Debug)]
-pub struct QueryError(pub QueryErrorType, pub String); // type and message
+pub struct QueryError {
+    pub error_type: QueryErrorType,
+    pub message: String,
+    pub diagnostic: Option<QueryDiagnostic>,
+}
+
+impl QueryError {
+    pub fn new(error_type: QueryErrorType, message: String) -> Self {
+        Self {
+            error_type,
+            message,
+            diagnostic: None,
+        }
+    }
+
+    /// Points the error at a part of the query, unless a more precise part is already known
+    fn at(mut self, span: &Span) -> Self {
+        if self.diagnostic.is_none() {
+            let position = span.start_pos();
+            let (line, column) = position.line_col();
+
+            self.diagnostic = Some(QueryDiagnostic::locate(
+                line,
+                column,
+                position.line_of(),
+                span.start(),
+                span.end(),
+            ));
+        }
+
+        self
+    }
+}
+
+/// Where an error is in a query, in a form editors can underline
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QueryDiagnostic {
+    /// 1-based line of the start of the faulty part
+    pub line: usize,
+    /// 1-based column (in characters) of the start of the faulty part
+    pub column: usize,
+    /// byte offset of the start of the faulty part
+    pub start: usize,
+    /// byte offset of the end of the faulty part (exclusive)
+    pub end: usize,
+    /// what the parser would have accepted, empty if the query is syntactically valid
+    pub expected: Vec<String>,
+    /// the faulty line with the faulty part underlined
+    pub snippet: String,
+}
+
+impl QueryDiagnostic {
+    /// `new` builds the diagnostic of the bytes `start..end` of a query
+    ///
+    /// Arguments:
+    ///
+    /// * `input`: The query.
+    /// * `start`: The byte offset of the start of the faulty part.
+    /// * `end`: The byte offset of the end of the faulty part.
+    ///
+    /// Returns:
+    ///
+    /// A `QueryDiagnostic` without expected tokens.
+    pub fn new(input: &str, start: usize, end: usize) -> Self {
+        let start = start.min(input.len());
+        let line_start = input[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = input[start..].find('\n').map_or(input.len(), |i| start + i);
+
+        let line = input[..start].matches('\n').count() + 1;
+        let column = input[line_start..start].chars().count() + 1;
+
+        Self::locate(line, column, &input[line_start..line_end], start, end)
+    }
+
+    fn locate(line: usize, column: usize, text: &str, start: usize, end: usize) -> Self {
+        let text = text.trim_end_matches(['\r', '\n']);
+
+        // the caret never goes past the end of the line, but always shows at least one character
+        let offset: usize = text.chars().take(column - 1).map(char::len_utf8).sum();
+        let width = text[offset.min(text.len())..]
+            .char_indices()
+            .take_while(|(i, _)| *i < end.saturating_sub(start))
+            .count()
+            .max(1);
+
+        let gutter = line.to_string();
+
+        let snippet = format!(
+            "{} | {}\n{} | {}{}",
+            gutter,
+            text,
+            " ".repeat(gutter.len()),
+            " ".repeat(column - 1),
+            "^".repeat(width),
+        );
+
+        Self {
+            line,
+            column,
+            start,
+            end,
+            expected: Vec::new(),
+            snippet,
+        }
+    }
+}
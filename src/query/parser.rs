@@ -1,26 +1,138 @@
-use super::{QueryError, QueryErrorType, Result};
+use super::literal;
+use super::{QueryDiagnostic, QueryError, QueryErrorType, Result};
 use bson::{Bson, Document};
+use pest::error::{ErrorVariant, InputLocation};
 use pest::iterators::Pair;
 use pest::Parser;
+use serde::Serialize;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum Keywords {
     Insert,
     Get,
     Update,
+    Upsert,
     Delete,
     List,
+    Incr,
+    Decr,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum Verbs {
     User,
     Database,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum ASTNode {
     // expressions
+    ExplainExpression {
+        statement: Box<ASTNode>,
+    },
+
+    UseExpression {
+        database: Box<ASTNode>,
+    },
+
+    WhoamiExpression,
+
+    BeginExpression,
+
+    CommitExpression,
+
+    RollbackExpression,
+
+    CreateExpression {
+        verb: Verbs,
+        ident: Box<ASTNode>,
+        options: Option<Box<ASTNode>>,
+    },
+
+    CreateSchemaExpression {
+        database: Box<ASTNode>,
+        schema: Box<ASTNode>,
+    },
+
+    DeleteSchemaExpression {
+        database: Box<ASTNode>,
+    },
+
+    CreateIndexExpression {
+        database: Box<ASTNode>,
+        field: Vec<PathSegment>,
+        // two keys can't have the same value in the field
+        unique: bool,
+    },
+
+    DropIndexExpression {
+        database: Box<ASTNode>,
+        field: Vec<PathSegment>,
+    },
+
+    RebuildIndexExpression {
+        database: Box<ASTNode>,
+        field: Vec<PathSegment>,
+    },
+
+    RenameExpression {
+        ident: Box<ASTNode>,
+        to: Box<ASTNode>,
+    },
+
+    // the copy goes to the current database if `database` is `None`
+    CopyExpression {
+        ident: Box<ASTNode>,
+        to: Box<ASTNode>,
+        database: Option<Box<ASTNode>>,
+    },
+
+    MoveExpression {
+        ident: Box<ASTNode>,
+        database: Box<ASTNode>,
+    },
+
+    ExpireExpression {
+        ident: Box<ASTNode>,
+        seconds: u64,
+    },
+
+    PersistExpression {
+        ident: Box<ASTNode>,
+    },
+
+    TtlExpression {
+        ident: Box<ASTNode>,
+    },
+
+    // pushes the changes after the `after` sequence number first, if it is set
+    WatchExpression {
+        scope: WatchScope,
+        after: Option<u64>,
+    },
+
+    // stops every watch of the connection if `watch` is `None`
+    UnwatchExpression {
+        watch: Option<u64>,
+    },
+
+    PublishExpression {
+        channel: String,
+        message: Box<ASTNode>,
+    },
+
+    // `pattern` is a glob matched against the channels messages are published on
+    SubscribeExpression {
+        pattern: String,
+        buffer: Option<usize>,
+        drop: Option<DropPolicy>,
+    },
+
+    // stops every subscription of the connection if `subscription` is `None`
+    UnsubscribeExpression {
+        subscription: Option<u64>,
+    },
+
     AssignmentExpression {
         ident: String,
         value: Box<ASTNode>,
@@ -36,6 +148,9 @@ pub enum ASTNode {
         keyword: Keywords,
         json: Box<ASTNode>,
         ident: Box<ASTNode>,
+        guard: Option<Guard>,
+        // seconds after which the written key expires
+        expire: Option<u64>,
     },
 
     SingleExpression {
@@ -43,39 +158,633 @@ pub enum ASTNode {
         ident: Option<Box<ASTNode>>,
     },
 
+    MultiExpression {
+        keyword: Keywords,
+        idents: Vec<ASTNode>,
+    },
+
+    ListExpression {
+        keyword: Keywords,
+        options: ListOptions,
+    },
+
+    // (value, key) pairs
+    MultiIntoExpression {
+        keyword: Keywords,
+        entries: Vec<(ASTNode, ASTNode)>,
+    },
+
+    PathExpression {
+        ident: String,
+        path: Vec<PathSegment>,
+        projection: Option<Vec<Vec<PathSegment>>>,
+    },
+
+    WhereExpression {
+        keyword: Keywords,
+        condition: Condition,
+    },
+
+    AggregateExpression {
+        function: Aggregate,
+        field: Option<Vec<PathSegment>>,
+        condition: Option<Condition>,
+        group_by: Option<Vec<PathSegment>>,
+    },
+
+    PatchExpression {
+        keyword: Keywords,
+        ident: Box<ASTNode>,
+        patch: Box<ASTNode>,
+    },
+
+    CounterExpression {
+        keyword: Keywords,
+        ident: Box<ASTNode>,
+        by: Option<Box<ASTNode>>,
+    },
+
     Bson(Bson),
     Identifier(String),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+impl std::fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Aggregate::Count => write!(f, "count"),
+            Aggregate::Sum => write!(f, "sum"),
+            Aggregate::Min => write!(f, "min"),
+            Aggregate::Max => write!(f, "max"),
+            Aggregate::Avg => write!(f, "avg"),
+        }
+    }
+}
+
+/// The clauses of a `list` scan, keys are listed in sorted order
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ListOptions {
+    /// only keys starting with this prefix
+    pub prefix: Option<String>,
+    /// only keys greater than or equal to this one
+    pub from: Option<String>,
+    /// only keys strictly less than this one
+    pub to: Option<String>,
+    /// only keys strictly greater than this one
+    pub after: Option<String>,
+    /// the continuation cursor returned by a previous scan
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+    pub with_values: bool,
+}
+
+/// The keys a `watch` is notified of the changes of, in the database it is run on
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum WatchScope {
+    /// `watch <key>`
+    Key(String),
+    /// `watch prefix "<prefix>"`, the keys starting with the prefix
+    Prefix(String),
+    /// `watch database`, every key
+    Database,
+}
+
+/// The messages a subscription drops once its buffer is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum DropPolicy {
+    /// `drop oldest`, the buffered messages make room for the new one
+    #[default]
+    Oldest,
+    /// `drop newest`, the new message is dropped
+    Newest,
+}
+
+/// The condition a conditional write checks against the current value of the key
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum Guard {
+    /// `if not exists`
+    Absent,
+    /// `if <value>`, the current value must be equal to the given one
+    Equals(Bson),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum Condition {
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+
+    Compare {
+        field: Vec<PathSegment>,
+        operator: Operator,
+        value: Bson,
+    },
+
+    In {
+        field: Vec<PathSegment>,
+        values: Vec<Bson>,
+    },
+
+    Exists {
+        field: Vec<PathSegment>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum PathSegment {
+    Field(String),
+    Index(usize),
+}
+
+impl std::fmt::Display for PathSegment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathSegment::Field(field) => write!(f, "{}", field),
+            PathSegment::Index(index) => write!(f, "{}", index),
+        }
+    }
+}
+
+impl ASTNode {
+    /// `redacted` copies the node with the values of its `password` assignments hidden, so it can be
+    /// shown back to the user or logged
+    pub fn redacted(&self) -> ASTNode {
+        match self {
+            ASTNode::AssignmentExpression { ident, .. } if ident == "password" => {
+                ASTNode::AssignmentExpression {
+                    ident: ident.clone(),
+                    value: Box::new(ASTNode::Bson(Bson::String("***".to_string()))),
+                }
+            }
+
+            ASTNode::MonadicExpression {
+                keyword,
+                verb,
+                expr,
+            } => ASTNode::MonadicExpression {
+                keyword: keyword.clone(),
+                verb: verb.clone(),
+                expr: expr
+                    .as_ref()
+                    .map(|expr| expr.iter().map(ASTNode::redacted).collect()),
+            },
+
+            ASTNode::ExplainExpression { statement } => ASTNode::ExplainExpression {
+                statement: Box::new(statement.redacted()),
+            },
+
+            _ => self.clone(),
+        }
+    }
+}
+
+/// `path_to_string` joins the segments of a path with dots, as they are written in a query
+pub fn path_to_string(path: &[PathSegment]) -> String {
+    path.iter()
+        .map(|segment| segment.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
 #[derive(pest_derive::Parser)]
 #[grammar = "query/grammar/rustbase.pest"]
 struct RustbaseParser;
 
-pub fn parse(input: &str) -> Result<Vec<ASTNode>> {
+/// A statement of a query, with where it was written in the query
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub ast: ASTNode,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// `parse` parses a query, replacing its placeholders with the given parameters
+///
+/// Parameters are bound while the AST is built, so their values are never parsed as RBQL.
+///
+/// Arguments:
+///
+/// * `input`: The query.
+/// * `params`: The values of the placeholders, keyed by name (`$name`) or position (`$1`).
+///
+/// Returns:
+///
+/// The statements of the query. Errors carry a `QueryDiagnostic` pointing at the faulty part.
+pub fn parse(input: &str, params: &Document) -> Result<Vec<Statement>> {
     let pairs = match RustbaseParser::parse(Rule::program, input) {
         Ok(e) => e,
         Err(e) => {
-            return Err(QueryError(QueryErrorType::SyntaxError, e.to_string()));
+            return Err(syntax_error(input, e));
         }
     };
 
-    let mut ast = Vec::new();
+    let mut statements = Vec::new();
 
     for pair in pairs {
         match pair.as_rule() {
             Rule::EOI => break,
-            Rule::expr => ast.push(build_expr(pair.into_inner().next().unwrap())?),
+            Rule::expr => {
+                let span = pair.as_span();
+
+                let ast = build_expr(pair.into_inner().next().unwrap(), params)
+                    .map_err(|e| e.at(&span))?;
+
+                // the span of a statement ends with the whitespace before the next one
+                statements.push(Statement {
+                    ast,
+                    start: span.start(),
+                    end: span.start() + span.as_str().trim_end().len(),
+                });
+            }
             _ => {
                 unreachable!();
             }
         }
     }
 
-    Ok(ast)
+    Ok(statements)
+}
+
+fn syntax_error(input: &str, error: pest::error::Error<Rule>) -> QueryError {
+    let (start, end) = match error.location {
+        InputLocation::Pos(pos) => (pos, pos),
+        InputLocation::Span(span) => span,
+    };
+
+    let mut diagnostic = QueryDiagnostic::new(input, start, end);
+
+    if let ErrorVariant::ParsingError { positives, .. } = &error.variant {
+        diagnostic.expected = positives.iter().map(describe_rule).collect();
+    }
+
+    let message = match diagnostic.expected.len() {
+        0 => format!(
+            "unexpected input at line {}, column {}",
+            diagnostic.line, diagnostic.column
+        ),
+        _ => format!(
+            "expected {} at line {}, column {}",
+            diagnostic.expected.join(" or "),
+            diagnostic.line,
+            diagnostic.column
+        ),
+    };
+
+    QueryError {
+        error_type: QueryErrorType::SyntaxError,
+        message,
+        diagnostic: Some(diagnostic),
+    }
+}
+
+/// The name of a rule as shown to the user in syntax errors
+fn describe_rule(rule: &Rule) -> String {
+    match rule {
+        Rule::EOI => "end of query",
+        Rule::expr => "statement",
+        Rule::ident => "identifier",
+        Rule::keyword | Rule::counterKeyword => "keyword",
+        Rule::verb => "'user' or 'database'",
+        Rule::useKeyword => "'use'",
+        Rule::whoamiKeyword => "'whoami'",
+        Rule::beginKeyword => "'begin'",
+        Rule::commitKeyword => "'commit'",
+        Rule::rollbackKeyword => "'rollback'",
+        Rule::createKeyword => "'create'",
+        Rule::schemaKeyword => "'schema'",
+        Rule::indexKeyword => "'index'",
+        Rule::uniqueKeyword => "'unique'",
+        Rule::dropKeyword => "'drop'",
+        Rule::rebuildKeyword => "'rebuild'",
+        Rule::deleteKeyword => "'delete'",
+        Rule::renameKeyword => "'rename'",
+        Rule::copyKeyword => "'copy'",
+        Rule::moveKeyword => "'move'",
+        Rule::expireKeyword => "'expire'",
+        Rule::persistKeyword => "'persist'",
+        Rule::ttlKeyword => "'ttl'",
+        Rule::watchKeyword => "'watch'",
+        Rule::unwatchKeyword => "'unwatch'",
+        Rule::resumeClause => "'after'",
+        Rule::publishKeyword => "'publish'",
+        Rule::subscribeKeyword => "'subscribe'",
+        Rule::unsubscribeKeyword => "'unsubscribe'",
+        Rule::bufferClause => "'buffer'",
+        Rule::dropClause => "'drop'",
+        Rule::dropPolicy => "'oldest' or 'newest'",
+        Rule::placeholder => "placeholder",
+        Rule::operator => "operator",
+        Rule::path => "path",
+        Rule::guard => "'if'",
+        Rule::expireClause => "'expire'",
+        Rule::aggregate => "aggregate",
+        Rule::groupBy => "'group by'",
+        Rule::prefixClause => "'prefix'",
+        Rule::fromClause => "'from'",
+        Rule::toClause => "'to'",
+        Rule::afterClause => "'after'",
+        Rule::cursorClause => "'cursor'",
+        Rule::limitClause => "'limit'",
+        Rule::withValues => "'with values'",
+        Rule::absent => "'not exists'",
+        Rule::index => "index",
+        Rule::projection => "projection",
+        Rule::condition => "condition",
+        Rule::object => "object",
+        Rule::array => "array",
+        Rule::pair => "key-value pair",
+        Rule::typed | Rule::constructor => "typed literal",
+        Rule::string => "string",
+        Rule::number => "number",
+        Rule::boolean => "boolean",
+        Rule::null => "null",
+        _ => return format!("{:?}", rule),
+    }
+    .to_string()
 }
 
-fn build_expr(pair: Pair<Rule>) -> Result<ASTNode> {
+fn build_expr(pair: Pair<Rule>, params: &Document) -> Result<ASTNode> {
     match pair.as_rule() {
+        Rule::explainExpr => {
+            let statement = pair.into_inner().nth(1).unwrap();
+
+            Ok(ASTNode::ExplainExpression {
+                statement: Box::new(build_expr(statement, params)?),
+            })
+        }
+
+        Rule::useExpr => {
+            let database = pair.into_inner().nth(1).unwrap();
+
+            Ok(ASTNode::UseExpression {
+                database: Box::new(build_key(database, params)?),
+            })
+        }
+
+        Rule::whoamiExpr => Ok(ASTNode::WhoamiExpression),
+
+        Rule::beginExpr => Ok(ASTNode::BeginExpression),
+
+        Rule::commitExpr => Ok(ASTNode::CommitExpression),
+
+        Rule::rollbackExpr => Ok(ASTNode::RollbackExpression),
+
+        Rule::createExpr => {
+            let mut inner_rules = pair.into_inner().skip(1);
+            let verb = inner_rules.next().unwrap();
+            let ident = inner_rules.next().unwrap();
+            let options = inner_rules.next();
+
+            Ok(ASTNode::CreateExpression {
+                verb: match verb.as_str() {
+                    "database" => Verbs::Database,
+                    "user" => Verbs::User,
+                    _ => {
+                        return Err(QueryError::new(
+                            QueryErrorType::UnexpectedToken,
+                            "invalid verb".to_string(),
+                        )
+                        .at(&verb.as_span()))
+                    }
+                },
+                ident: Box::new(build_key(ident, params)?),
+                options: match options {
+                    Some(options) => Some(Box::new(build_term(options, params)?)),
+                    None => None,
+                },
+            })
+        }
+
+        Rule::createSchemaExpr => {
+            let mut inner_rules = pair.into_inner().skip(2);
+            let database = inner_rules.next().unwrap();
+            let schema = inner_rules.next().unwrap();
+
+            Ok(ASTNode::CreateSchemaExpression {
+                database: Box::new(build_key(database, params)?),
+                schema: Box::new(build_term(schema, params)?),
+            })
+        }
+
+        Rule::deleteSchemaExpr => {
+            let database = pair.into_inner().nth(2).unwrap();
+
+            Ok(ASTNode::DeleteSchemaExpression {
+                database: Box::new(build_key(database, params)?),
+            })
+        }
+
+        Rule::createIndexExpr | Rule::dropIndexExpr | Rule::rebuildIndexExpr => {
+            let rule = pair.as_rule();
+
+            let mut inner_rules = pair.into_inner().skip(1).peekable();
+
+            let unique = inner_rules
+                .next_if(|pair| pair.as_rule() == Rule::uniqueKeyword)
+                .is_some();
+
+            let mut inner_rules = inner_rules.skip(1);
+            let database = Box::new(build_key(inner_rules.next().unwrap(), params)?);
+            let field = build_path(inner_rules.next().unwrap(), params)?;
+
+            Ok(match rule {
+                Rule::createIndexExpr => ASTNode::CreateIndexExpression {
+                    database,
+                    field,
+                    unique,
+                },
+                Rule::dropIndexExpr => ASTNode::DropIndexExpression { database, field },
+                _ => ASTNode::RebuildIndexExpression { database, field },
+            })
+        }
+
+        Rule::renameExpr => {
+            let mut inner_rules = pair.into_inner().skip(1);
+            let ident = inner_rules.next().unwrap();
+            let to = inner_rules.next().unwrap();
+
+            Ok(ASTNode::RenameExpression {
+                ident: Box::new(build_key(ident, params)?),
+                to: Box::new(build_key(to, params)?),
+            })
+        }
+
+        Rule::copyExpr => {
+            let mut inner_rules = pair.into_inner().skip(1);
+            let ident = inner_rules.next().unwrap();
+            let to = inner_rules.next().unwrap();
+            let database = inner_rules.next();
+
+            Ok(ASTNode::CopyExpression {
+                ident: Box::new(build_key(ident, params)?),
+                to: Box::new(build_key(to, params)?),
+                database: match database {
+                    Some(database) => Some(Box::new(build_key(database, params)?)),
+                    None => None,
+                },
+            })
+        }
+
+        Rule::moveExpr => {
+            let mut inner_rules = pair.into_inner().skip(1);
+            let ident = inner_rules.next().unwrap();
+            let database = inner_rules.next().unwrap();
+
+            Ok(ASTNode::MoveExpression {
+                ident: Box::new(build_key(ident, params)?),
+                database: Box::new(build_key(database, params)?),
+            })
+        }
+
+        Rule::expireExpr => {
+            let mut inner_rules = pair.into_inner().skip(1);
+            let ident = inner_rules.next().unwrap();
+            let seconds = inner_rules.next().unwrap();
+
+            Ok(ASTNode::ExpireExpression {
+                ident: Box::new(build_key(ident, params)?),
+                seconds: build_seconds(seconds, params)?,
+            })
+        }
+
+        Rule::persistExpr => {
+            let ident = pair.into_inner().nth(1).unwrap();
+
+            Ok(ASTNode::PersistExpression {
+                ident: Box::new(build_key(ident, params)?),
+            })
+        }
+
+        Rule::ttlExpr => {
+            let ident = pair.into_inner().nth(1).unwrap();
+
+            Ok(ASTNode::TtlExpression {
+                ident: Box::new(build_key(ident, params)?),
+            })
+        }
+
+        Rule::watchExpr => {
+            let mut inner_rules = pair.into_inner().skip(1);
+            let scope = inner_rules.next().unwrap();
+
+            let scope = match scope.as_rule() {
+                Rule::watchDatabase => WatchScope::Database,
+
+                Rule::prefixClause => {
+                    let span = scope.as_span();
+
+                    match parse_to_bson(scope.into_inner().next().unwrap(), params)? {
+                        Bson::String(prefix) => WatchScope::Prefix(prefix),
+                        _ => {
+                            return Err(QueryError::new(
+                                QueryErrorType::InvalidParameter,
+                                format!("{} expects a string", span.as_str()),
+                            )
+                            .at(&span))
+                        }
+                    }
+                }
+
+                _ => match build_key(scope, params)? {
+                    ASTNode::Identifier(key) => WatchScope::Key(key),
+                    _ => unreachable!(),
+                },
+            };
+
+            let after = match inner_rules.next() {
+                Some(after) => Some(build_sequence(after.into_inner().next().unwrap(), params)?),
+                None => None,
+            };
+
+            Ok(ASTNode::WatchExpression { scope, after })
+        }
+
+        Rule::unwatchExpr => {
+            let watch = match pair.into_inner().nth(1) {
+                Some(watch) => Some(build_sequence(watch, params)?),
+                None => None,
+            };
+
+            Ok(ASTNode::UnwatchExpression { watch })
+        }
+
+        Rule::publishExpr => {
+            let mut inner_rules = pair.into_inner().skip(1);
+            let channel = build_channel(inner_rules.next().unwrap(), params)?;
+            let message = inner_rules.next().unwrap();
+
+            Ok(ASTNode::PublishExpression {
+                channel,
+                message: Box::new(build_term(message, params)?),
+            })
+        }
+
+        Rule::subscribeExpr => {
+            let mut inner_rules = pair.into_inner().skip(1);
+            let pattern = build_channel(inner_rules.next().unwrap(), params)?;
+
+            let mut buffer = None;
+            let mut drop = None;
+
+            for clause in inner_rules {
+                let span = clause.as_span();
+                let rule = clause.as_rule();
+                let value = clause.into_inner().next().unwrap();
+
+                if rule == Rule::dropClause {
+                    drop = Some(match value.as_str() {
+                        "newest" => DropPolicy::Newest,
+                        _ => DropPolicy::Oldest,
+                    });
+
+                    continue;
+                }
+
+                buffer = match as_limit(&parse_to_bson(value, params)?) {
+                    Some(buffer) => Some(buffer),
+                    None => {
+                        return Err(QueryError::new(
+                            QueryErrorType::InvalidParameter,
+                            "buffer must be a positive integer".to_string(),
+                        )
+                        .at(&span))
+                    }
+                };
+            }
+
+            Ok(ASTNode::SubscribeExpression {
+                pattern,
+                buffer,
+                drop,
+            })
+        }
+
+        Rule::unsubscribeExpr => {
+            let subscription = match pair.into_inner().nth(1) {
+                Some(subscription) => Some(build_sequence(subscription, params)?),
+                None => None,
+            };
+
+            Ok(ASTNode::UnsubscribeExpression { subscription })
+        }
+
         Rule::assgmtExpr => {
             let mut inner_rules = pair.into_inner();
             let ident = inner_rules.next().unwrap();
@@ -83,7 +792,7 @@ fn build_expr(pair: Pair<Rule>) -> Result<ASTNode> {
 
             Ok(ASTNode::AssignmentExpression {
                 ident: ident.as_str().to_string(),
-                value: Box::new(build_expr(value)?),
+                value: Box::new(build_expr(value, params)?),
             })
         }
 
@@ -98,8 +807,8 @@ fn build_expr(pair: Pair<Rule>) -> Result<ASTNode> {
             if expr.is_some() {
                 for pair in pair.into_inner() {
                     match pair.as_rule() {
-                        Rule::expr => exprs.push(build_expr(pair)?),
-                        Rule::ident => exprs.push(build_term(pair)?),
+                        Rule::expr => exprs.push(build_expr(pair, params)?),
+                        Rule::ident => exprs.push(build_term(pair, params)?),
                         _ => {
                             continue;
                         }
@@ -112,21 +821,24 @@ fn build_expr(pair: Pair<Rule>) -> Result<ASTNode> {
                     "insert" => Keywords::Insert,
                     "delete" => Keywords::Delete,
                     "update" => Keywords::Update,
+                    "list" => Keywords::List,
                     _ => {
-                        return Err(QueryError(
+                        return Err(QueryError::new(
                             QueryErrorType::UnexpectedToken,
                             "invalid keyword".to_string(),
-                        ))
+                        )
+                        .at(&keyword.as_span()))
                     }
                 },
                 verb: match verb.as_str() {
                     "user" => Verbs::User,
                     "database" => Verbs::Database,
                     _ => {
-                        return Err(QueryError(
+                        return Err(QueryError::new(
                             QueryErrorType::UnexpectedToken,
                             "invalid verb".to_string(),
-                        ))
+                        )
+                        .at(&verb.as_span()))
                     }
                 },
                 expr: if exprs.is_empty() { None } else { Some(exprs) },
@@ -139,26 +851,41 @@ fn build_expr(pair: Pair<Rule>) -> Result<ASTNode> {
             let json = inner_rules.next().unwrap();
             let ident = inner_rules.next().unwrap();
 
+            let mut guard = None;
+            let mut expire = None;
+
+            for clause in inner_rules {
+                match clause.as_rule() {
+                    Rule::guard => guard = Some(build_guard(clause, params)?),
+                    _ => expire = Some(build_seconds(clause.into_inner().next().unwrap(), params)?),
+                }
+            }
+
             Ok(ASTNode::IntoExpression {
                 keyword: match keyword.as_str() {
                     "insert" => Keywords::Insert,
                     "update" => Keywords::Update,
+                    "upsert" => Keywords::Upsert,
                     _ => {
-                        return Err(QueryError(
+                        return Err(QueryError::new(
                             QueryErrorType::UnexpectedToken,
                             "invalid keyword".to_string(),
-                        ))
+                        )
+                        .at(&keyword.as_span()))
                     }
                 },
-                json: Box::new(build_term(json)?),
-                ident: Box::new(build_term(ident)?),
+                json: Box::new(build_term(json, params)?),
+                ident: Box::new(build_key(ident, params)?),
+                guard,
+                expire,
             })
         }
 
         Rule::sglExpr => {
             let mut inner_rules = pair.into_inner();
             let keyword = inner_rules.next().unwrap();
-            let ident = inner_rules.next();
+            let path = inner_rules.next();
+            let projection = inner_rules.next();
 
             Ok(ASTNode::SingleExpression {
                 keyword: match keyword.as_str() {
@@ -166,22 +893,255 @@ fn build_expr(pair: Pair<Rule>) -> Result<ASTNode> {
                     "delete" => Keywords::Delete,
                     "list" => Keywords::List,
                     _ => {
-                        return Err(QueryError(
+                        return Err(QueryError::new(
                             QueryErrorType::UnexpectedToken,
                             "invalid keyword".to_string(),
-                        ))
+                        )
+                        .at(&keyword.as_span()))
                     }
                 },
-                ident: if let Some(ident) = ident {
-                    Some(Box::new(build_term(ident)?))
+                ident: if let Some(path) = path {
+                    Some(Box::new(build_key_path(path, projection, params)?))
                 } else {
                     None
                 },
             })
         }
 
-        Rule::terms => Ok(build_term(pair)?),
-        Rule::expr => Ok(build_expr(pair.into_inner().next().unwrap())?),
+        Rule::multiExpr => {
+            let mut inner_rules = pair.into_inner();
+            let keyword = inner_rules.next().unwrap();
+
+            Ok(ASTNode::MultiExpression {
+                keyword: match keyword.as_str() {
+                    "get" => Keywords::Get,
+                    "delete" => Keywords::Delete,
+                    _ => {
+                        return Err(QueryError::new(
+                            QueryErrorType::UnexpectedToken,
+                            format!("{} does not accept several keys", keyword.as_str()),
+                        )
+                        .at(&keyword.as_span()))
+                    }
+                },
+                idents: inner_rules
+                    .map(|ident| build_key(ident, params))
+                    .collect::<Result<Vec<_>>>()?,
+            })
+        }
+
+        Rule::listExpr => {
+            let mut inner_rules = pair.into_inner();
+            let keyword = inner_rules.next().unwrap();
+
+            let mut options = ListOptions::default();
+
+            for clause in inner_rules {
+                let rule = clause.as_rule();
+
+                if rule == Rule::withValues {
+                    options.with_values = true;
+                    continue;
+                }
+
+                let span = clause.as_span();
+                let value = parse_to_bson(clause.into_inner().next().unwrap(), params)?;
+
+                if rule == Rule::limitClause {
+                    options.limit = match as_limit(&value) {
+                        Some(limit) => Some(limit),
+                        None => {
+                            return Err(QueryError::new(
+                                QueryErrorType::InvalidParameter,
+                                "limit must be a positive integer".to_string(),
+                            )
+                            .at(&span))
+                        }
+                    };
+
+                    continue;
+                }
+
+                let value = match value {
+                    Bson::String(value) => value,
+                    _ => {
+                        return Err(QueryError::new(
+                            QueryErrorType::InvalidParameter,
+                            format!("{} expects a string", span.as_str()),
+                        )
+                        .at(&span))
+                    }
+                };
+
+                match rule {
+                    Rule::prefixClause => options.prefix = Some(value),
+                    Rule::fromClause => options.from = Some(value),
+                    Rule::toClause => options.to = Some(value),
+                    Rule::afterClause => options.after = Some(value),
+                    Rule::cursorClause => options.cursor = Some(value),
+                    _ => unreachable!(),
+                }
+            }
+
+            Ok(ASTNode::ListExpression {
+                keyword: match keyword.as_str() {
+                    "list" => Keywords::List,
+                    _ => {
+                        return Err(QueryError::new(
+                            QueryErrorType::UnexpectedToken,
+                            "invalid keyword".to_string(),
+                        )
+                        .at(&keyword.as_span()))
+                    }
+                },
+                options,
+            })
+        }
+
+        Rule::multiIntoExpr => {
+            let mut inner_rules = pair.into_inner();
+            let keyword = inner_rules.next().unwrap();
+
+            let mut entries = Vec::new();
+
+            while let Some(json) = inner_rules.next() {
+                let ident = inner_rules.next().unwrap();
+
+                entries.push((build_term(json, params)?, build_key(ident, params)?));
+            }
+
+            Ok(ASTNode::MultiIntoExpression {
+                keyword: match keyword.as_str() {
+                    "insert" => Keywords::Insert,
+                    _ => {
+                        return Err(QueryError::new(
+                            QueryErrorType::UnexpectedToken,
+                            format!("{} does not accept several keys", keyword.as_str()),
+                        )
+                        .at(&keyword.as_span()))
+                    }
+                },
+                entries,
+            })
+        }
+
+        Rule::whereExpr => {
+            let mut inner_rules = pair.into_inner();
+            let keyword = inner_rules.next().unwrap();
+            let condition = inner_rules.next().unwrap();
+
+            Ok(ASTNode::WhereExpression {
+                keyword: match keyword.as_str() {
+                    "get" => Keywords::Get,
+                    _ => {
+                        return Err(QueryError::new(
+                            QueryErrorType::UnexpectedToken,
+                            "invalid keyword".to_string(),
+                        )
+                        .at(&keyword.as_span()))
+                    }
+                },
+                condition: build_condition(condition, params)?,
+            })
+        }
+
+        Rule::aggregateExpr => {
+            let span = pair.as_span();
+            let mut inner_rules = pair.into_inner();
+            let function = inner_rules.next().unwrap();
+
+            let mut field = None;
+            let mut condition = None;
+            let mut group_by = None;
+
+            for clause in inner_rules {
+                match clause.as_rule() {
+                    Rule::path => field = Some(build_path(clause, params)?),
+                    Rule::condition => condition = Some(build_condition(clause, params)?),
+                    Rule::groupBy => {
+                        group_by = Some(build_path(clause.into_inner().next().unwrap(), params)?)
+                    }
+                    _ => unreachable!(),
+                }
+            }
+
+            let function = match function.as_str() {
+                "count" => Aggregate::Count,
+                "sum" => Aggregate::Sum,
+                "min" => Aggregate::Min,
+                "max" => Aggregate::Max,
+                "avg" => Aggregate::Avg,
+                _ => unreachable!(),
+            };
+
+            // only count can go without a field, it then counts the values themselves
+            if field.is_none() && !matches!(function, Aggregate::Count) {
+                return Err(QueryError::new(
+                    QueryErrorType::UnexpectedToken,
+                    format!("{} needs a field", function),
+                )
+                .at(&span));
+            }
+
+            Ok(ASTNode::AggregateExpression {
+                function,
+                field,
+                condition,
+                group_by,
+            })
+        }
+
+        Rule::patchExpr => {
+            let mut inner_rules = pair.into_inner();
+            let keyword = inner_rules.next().unwrap();
+            let ident = inner_rules.next().unwrap();
+            let patch = inner_rules.next().unwrap();
+
+            Ok(ASTNode::PatchExpression {
+                keyword: match keyword.as_str() {
+                    "update" => Keywords::Update,
+                    _ => {
+                        return Err(QueryError::new(
+                            QueryErrorType::UnexpectedToken,
+                            "invalid keyword".to_string(),
+                        )
+                        .at(&keyword.as_span()))
+                    }
+                },
+                ident: Box::new(build_key(ident, params)?),
+                patch: Box::new(build_term(patch, params)?),
+            })
+        }
+
+        Rule::counterExpr => {
+            let mut inner_rules = pair.into_inner();
+            let keyword = inner_rules.next().unwrap();
+            let ident = inner_rules.next().unwrap();
+            let by = inner_rules.next();
+
+            Ok(ASTNode::CounterExpression {
+                keyword: match keyword.as_str() {
+                    "incr" => Keywords::Incr,
+                    "decr" => Keywords::Decr,
+                    _ => {
+                        return Err(QueryError::new(
+                            QueryErrorType::UnexpectedToken,
+                            "invalid keyword".to_string(),
+                        )
+                        .at(&keyword.as_span()))
+                    }
+                },
+                ident: Box::new(build_key(ident, params)?),
+                by: if let Some(by) = by {
+                    Some(Box::new(build_term(by, params)?))
+                } else {
+                    None
+                },
+            })
+        }
+
+        Rule::terms => Ok(build_term(pair, params)?),
+        Rule::expr => Ok(build_expr(pair.into_inner().next().unwrap(), params)?),
 
         _ => {
             unreachable!()
@@ -189,45 +1149,317 @@ fn build_expr(pair: Pair<Rule>) -> Result<ASTNode> {
     }
 }
 
-fn build_term(pair: Pair<Rule>) -> Result<ASTNode> {
+fn as_limit(value: &Bson) -> Option<usize> {
+    let limit = match value {
+        Bson::Int32(limit) => *limit as i64,
+        Bson::Int64(limit) => *limit,
+        _ => return None,
+    };
+
+    if limit > 0 {
+        usize::try_from(limit).ok()
+    } else {
+        None
+    }
+}
+
+fn build_seconds(pair: Pair<Rule>, params: &Document) -> Result<u64> {
+    let span = pair.as_span();
+
+    match as_limit(&parse_to_bson(pair, params)?) {
+        Some(seconds) => Ok(seconds as u64),
+        None => Err(QueryError::new(
+            QueryErrorType::InvalidParameter,
+            "expire must be a positive number of seconds".to_string(),
+        )
+        .at(&span)),
+    }
+}
+
+// sequence numbers and watch and subscription ids start at 1, 0 being before the first change
+fn build_sequence(pair: Pair<Rule>, params: &Document) -> Result<u64> {
+    let span = pair.as_span();
+
+    match parse_to_bson(pair, params)? {
+        Bson::Int32(n) if n >= 0 => Ok(n as u64),
+        Bson::Int64(n) if n >= 0 => Ok(n as u64),
+        _ => Err(QueryError::new(
+            QueryErrorType::InvalidParameter,
+            format!("{} must be a non-negative integer", span.as_str()),
+        )
+        .at(&span)),
+    }
+}
+
+fn build_channel(pair: Pair<Rule>, params: &Document) -> Result<String> {
+    let span = pair.as_span();
+
+    match parse_to_bson(pair, params)? {
+        Bson::String(channel) => Ok(channel),
+        _ => Err(QueryError::new(
+            QueryErrorType::InvalidParameter,
+            format!("{} must be a string", span.as_str()),
+        )
+        .at(&span)),
+    }
+}
+
+fn build_guard(pair: Pair<Rule>, params: &Document) -> Result<Guard> {
+    let expected = pair.into_inner().next().unwrap();
+
+    match expected.as_rule() {
+        Rule::absent => Ok(Guard::Absent),
+        _ => Ok(Guard::Equals(parse_to_bson(expected, params)?)),
+    }
+}
+
+fn build_condition(pair: Pair<Rule>, params: &Document) -> Result<Condition> {
     match pair.as_rule() {
-        Rule::number | Rule::string | Rule::boolean | Rule::null | Rule::array | Rule::object => {
-            Ok(ASTNode::Bson(parse_to_bson(pair)))
+        Rule::condition | Rule::conjunction => {
+            let rule = pair.as_rule();
+
+            let mut conditions = pair
+                .into_inner()
+                .map(|pair| build_condition(pair, params))
+                .collect::<Result<Vec<_>>>()?;
+
+            if conditions.len() == 1 {
+                return Ok(conditions.pop().unwrap());
+            }
+
+            if rule == Rule::condition {
+                Ok(Condition::Or(conditions))
+            } else {
+                Ok(Condition::And(conditions))
+            }
         }
+
+        Rule::exists => Ok(Condition::Exists {
+            field: build_path(pair.into_inner().next().unwrap(), params)?,
+        }),
+
+        Rule::inList => {
+            let mut inner_rules = pair.into_inner();
+            let field = build_path(inner_rules.next().unwrap(), params)?;
+
+            let list = inner_rules.next().unwrap();
+            let span = list.as_span();
+
+            let values = match parse_to_bson(list, params)? {
+                Bson::Array(values) => values,
+                _ => {
+                    return Err(QueryError::new(
+                        QueryErrorType::InvalidParameter,
+                        "in must be followed by an array".to_string(),
+                    )
+                    .at(&span))
+                }
+            };
+
+            Ok(Condition::In { field, values })
+        }
+
+        Rule::comparison => {
+            let mut inner_rules = pair.into_inner();
+            let field = build_path(inner_rules.next().unwrap(), params)?;
+            let operator = inner_rules.next().unwrap();
+            let value = parse_to_bson(inner_rules.next().unwrap(), params)?;
+
+            Ok(Condition::Compare {
+                field,
+                operator: match operator.as_str() {
+                    "=" => Operator::Eq,
+                    "!=" => Operator::Ne,
+                    "<" => Operator::Lt,
+                    "<=" => Operator::Le,
+                    ">" => Operator::Gt,
+                    ">=" => Operator::Ge,
+                    _ => unreachable!(),
+                },
+                value,
+            })
+        }
+
+        _ => {
+            unreachable!()
+        }
+    }
+}
+
+/// A key followed by nothing else is a plain identifier, anything more reads inside its value
+fn build_key_path(
+    path: Pair<Rule>,
+    projection: Option<Pair<Rule>>,
+    params: &Document,
+) -> Result<ASTNode> {
+    let mut inner_rules = path.into_inner();
+
+    let ident = match build_key(inner_rules.next().unwrap(), params)? {
+        ASTNode::Identifier(ident) => ident,
+        _ => unreachable!(),
+    };
+
+    let path = inner_rules.map(build_segment).collect::<Vec<_>>();
+
+    if path.is_empty() && projection.is_none() {
+        return Ok(ASTNode::Identifier(ident));
+    }
+
+    let projection = if let Some(projection) = projection {
+        Some(
+            projection
+                .into_inner()
+                .map(|path| build_path(path, params))
+                .collect::<Result<Vec<_>>>()?,
+        )
+    } else {
+        None
+    };
+
+    Ok(ASTNode::PathExpression {
+        ident,
+        path,
+        projection,
+    })
+}
+
+fn build_path(pair: Pair<Rule>, params: &Document) -> Result<Vec<PathSegment>> {
+    pair.into_inner()
+        .map(|segment| match segment.as_rule() {
+            Rule::placeholder => match bind(&segment, params)? {
+                Bson::String(field) if !field.is_empty() => Ok(PathSegment::Field(field.clone())),
+                _ => Err(QueryError::new(
+                    QueryErrorType::InvalidParameter,
+                    format!("{} must be a field name", segment.as_str()),
+                )
+                .at(&segment.as_span())),
+            },
+            _ => Ok(build_segment(segment)),
+        })
+        .collect()
+}
+
+fn build_segment(segment: Pair<Rule>) -> PathSegment {
+    match segment.as_rule() {
+        Rule::index => match segment.as_str().parse() {
+            Ok(index) => PathSegment::Index(index),
+            // too large to be an array index, so it can only be a field name
+            Err(_) => PathSegment::Field(segment.as_str().to_string()),
+        },
+        _ => PathSegment::Field(segment.as_str().to_string()),
+    }
+}
+
+/// Keys are identifiers, either written in the query or bound to a placeholder
+fn build_key(pair: Pair<Rule>, params: &Document) -> Result<ASTNode> {
+    match pair.as_rule() {
         Rule::ident => Ok(ASTNode::Identifier(pair.as_str().to_string())),
-        Rule::terms => Ok(build_term(pair.into_inner().next().unwrap())?),
+        Rule::placeholder => match bind(&pair, params)? {
+            Bson::String(key) if is_ident(key) => Ok(ASTNode::Identifier(key.clone())),
+            _ => Err(QueryError::new(
+                QueryErrorType::InvalidParameter,
+                format!(
+                    "{} must be a key (a letter followed by letters, digits or '_')",
+                    pair.as_str()
+                ),
+            )
+            .at(&pair.as_span())),
+        },
         _ => {
             unreachable!()
         }
     }
 }
 
-fn parse_to_bson(pair: Pair<Rule>) -> Bson {
+/// `is_ident` checks if a string could have been written as an identifier in a query
+pub fn is_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() => {}
+        _ => return false,
+    }
+
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn bind<'a>(placeholder: &Pair<Rule>, params: &'a Document) -> Result<&'a Bson> {
+    let name = &placeholder.as_str()[1..];
+
+    params.get(name).ok_or_else(|| {
+        QueryError::new(
+            QueryErrorType::InvalidParameter,
+            format!("parameter {} is not bound", placeholder.as_str()),
+        )
+        .at(&placeholder.as_span())
+    })
+}
+
+fn build_term(pair: Pair<Rule>, params: &Document) -> Result<ASTNode> {
     match pair.as_rule() {
+        Rule::number
+        | Rule::string
+        | Rule::boolean
+        | Rule::null
+        | Rule::array
+        | Rule::object
+        | Rule::typed
+        | Rule::placeholder => Ok(ASTNode::Bson(parse_to_bson(pair, params)?)),
+        Rule::ident => Ok(ASTNode::Identifier(pair.as_str().to_string())),
+        Rule::terms => Ok(build_term(pair.into_inner().next().unwrap(), params)?),
+        _ => {
+            unreachable!()
+        }
+    }
+}
+
+fn parse_to_bson(pair: Pair<Rule>, params: &Document) -> Result<Bson> {
+    let span = pair.as_span();
+
+    let value = match pair.as_rule() {
         Rule::object => {
             let mut doc = Document::new();
             for pair in pair.into_inner() {
                 let mut inner_rules = pair.into_inner();
-                let key = parse_to_bson(inner_rules.next().unwrap());
+                let key = parse_to_bson(inner_rules.next().unwrap(), params)?;
 
-                let value = parse_to_bson(inner_rules.next().unwrap());
+                let value = parse_to_bson(inner_rules.next().unwrap(), params)?;
                 doc.insert(key.as_str().unwrap(), value);
             }
-            Bson::Document(doc)
+            Ok(Bson::Document(doc))
         }
         Rule::array => {
             let mut arr = Vec::new();
             for pair in pair.into_inner() {
-                arr.push(parse_to_bson(pair));
+                arr.push(parse_to_bson(pair, params)?);
             }
-            Bson::Array(arr)
+            Ok(Bson::Array(arr))
         }
-        Rule::string => Bson::String(pair.into_inner().next().unwrap().as_str().to_string()),
-        Rule::number => Bson::Int64(pair.as_str().parse().unwrap()),
-        Rule::boolean => Bson::Boolean(pair.as_str().parse().unwrap()),
-        Rule::null => Bson::Null,
+        Rule::typed => {
+            let mut inner_rules = pair.into_inner();
+            let constructor = inner_rules.next().unwrap();
+            let argument = parse_to_bson(inner_rules.next().unwrap(), params)?;
+
+            match argument.as_str() {
+                Some(argument) => literal::parse_typed(constructor.as_str(), argument),
+                None => Err(QueryError::new(
+                    QueryErrorType::InvalidParameter,
+                    format!("{} expects a string", constructor.as_str()),
+                )),
+            }
+        }
+        Rule::placeholder => Ok(bind(&pair, params)?.clone()),
+        Rule::string => {
+            literal::unescape(pair.into_inner().next().unwrap().as_str()).map(Bson::String)
+        }
+        Rule::number => literal::parse_number(pair.as_str()),
+        Rule::boolean => Ok(Bson::Boolean(pair.as_str().parse().unwrap())),
+        Rule::null => Ok(Bson::Null),
         _ => {
             unreachable!();
         }
-    }
+    };
+
+    // literal errors point at the literal they come from
+    value.map_err(|e| e.at(&span))
 }
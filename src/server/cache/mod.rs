@@ -1,9 +1,12 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Clone, Debug)]
 pub struct Cache {
-    index: HashMap<String, usize>,
-    cache: VecDeque<bson::Bson>,
+    // the place of each key in `cache`, which doesn't change until the key is removed
+    index: HashMap<String, u64>,
+    // the values with their keys, oldest first
+    cache: BTreeMap<u64, (String, bson::Bson)>,
+    next_place: u64,
     cache_size: usize,
     max_size: usize,
 }
@@ -12,7 +15,8 @@ impl Cache {
     pub fn new(max_cache_size: usize) -> Self {
         Cache {
             index: HashMap::new(),
-            cache: VecDeque::new(),
+            cache: BTreeMap::new(),
+            next_place: 0,
             cache_size: 0,
             max_size: max_cache_size,
         }
@@ -24,7 +28,7 @@ impl Cache {
         }
 
         let index = self.index.get(key).unwrap();
-        let value = self.cache.get(*index).unwrap();
+        let (_, value) = self.cache.get(index).unwrap();
         Some(value)
     }
 
@@ -38,9 +42,10 @@ impl Cache {
 
         self.manage_cache(value_size);
 
-        self.cache.push_back(value);
-        let index = self.cache.len() - 1;
-        self.index.insert(key, index);
+        let index = self.next_place;
+        self.next_place += 1;
+        self.index.insert(key.clone(), index);
+        self.cache.insert(index, (key, value));
 
         self.cache_size += value_size;
 
@@ -55,7 +60,7 @@ impl Cache {
         }
 
         let index = self.index.remove(key).unwrap();
-        let value = self.cache.remove(index).unwrap();
+        let (_, value) = self.cache.remove(&index).unwrap();
         let value_size = std::mem::size_of_val(&value);
 
         self.cache_size -= value_size;
@@ -65,11 +70,13 @@ impl Cache {
 
     fn manage_cache(&mut self, size_to_insert: usize) {
         while self.cache_size + size_to_insert > self.max_size {
-            let value = self.cache.pop_front();
-
-            if value.is_none() {
-                break;
-            }
+            let value = match self.cache.pop_first() {
+                Some((_, (key, value))) => {
+                    self.index.remove(&key);
+                    value
+                }
+                None => break,
+            };
 
             let value_size = std::mem::size_of_val(&value);
             println!("[Cache] removing {} bytes", value_size);
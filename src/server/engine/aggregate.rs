@@ -0,0 +1,197 @@
+use bson::{Bson, Document};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::query::parser::{Aggregate, PathSegment};
+
+use super::document::{self, resolve_path};
+use super::filter;
+use super::interface::TransactionError;
+
+/// `Aggregation` folds the values of a database into an aggregate, optionally grouped by a field
+pub struct Aggregation {
+    function: Aggregate,
+    field: Option<Vec<PathSegment>>,
+    group_by: Option<Vec<PathSegment>>,
+    groups: Vec<(Bson, Accumulator)>,
+    // position of each group in `groups`, keyed by the extended JSON of the group key
+    index: HashMap<String, usize>,
+}
+
+#[derive(Default)]
+struct Accumulator {
+    count: i64,
+    sum: Option<Bson>,
+    total: f64,
+    best: Option<Bson>,
+}
+
+impl Aggregation {
+    pub fn new(
+        function: Aggregate,
+        field: Option<Vec<PathSegment>>,
+        group_by: Option<Vec<PathSegment>>,
+    ) -> Self {
+        Self {
+            function,
+            field,
+            group_by,
+            groups: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    /// `add` folds a value into the aggregate of its group
+    ///
+    /// Values without the aggregated field are skipped, and so are non-numeric fields for `sum` and
+    /// `avg`. Values without the grouping field are grouped under `null`.
+    ///
+    /// Arguments:
+    ///
+    /// * `value`: A value of the database.
+    pub fn add(&mut self, value: &Bson) -> Result<(), TransactionError> {
+        let group = match &self.group_by {
+            Some(group_by) => resolve_path(value, group_by).cloned().unwrap_or(Bson::Null),
+            None => Bson::Null,
+        };
+
+        let field = match &self.field {
+            Some(field) => match resolve_path(value, field) {
+                Some(field) => field,
+                None => return Ok(()),
+            },
+            None => value,
+        };
+
+        let function = self.function;
+        let accumulator = self.group(group);
+
+        match function {
+            Aggregate::Count => accumulator.count += 1,
+
+            Aggregate::Sum => {
+                if filter::as_f64(field).is_some() {
+                    let sum = accumulator.sum.take().unwrap_or(Bson::Int64(0));
+                    accumulator.sum = Some(document::add(&sum, field)?);
+                }
+            }
+
+            Aggregate::Avg => {
+                if let Some(number) = filter::as_f64(field) {
+                    accumulator.total += number;
+                    accumulator.count += 1;
+                }
+            }
+
+            Aggregate::Min | Aggregate::Max => {
+                let wanted = match function {
+                    Aggregate::Min => Ordering::Less,
+                    _ => Ordering::Greater,
+                };
+
+                let better = match &accumulator.best {
+                    _ if *field == Bson::Null => false,
+                    Some(best) => order(field, best) == wanted,
+                    None => true,
+                };
+
+                if better {
+                    accumulator.best = Some(field.clone());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `finish` builds the result of the aggregation
+    ///
+    /// Returns:
+    ///
+    /// `{"<function>": result}`, or `{"groups": [{"key": ..., "<function>": result}, ...]}` with
+    /// `group by`, the groups being in the order they were first seen.
+    pub fn finish(self) -> Document {
+        let function = self.function;
+        let name = function.to_string();
+
+        if self.group_by.is_none() {
+            let accumulator = self.groups.into_iter().next().map(|(_, a)| a);
+            let result = result(function, accumulator.unwrap_or_default());
+
+            let mut document = Document::new();
+            document.insert(name, result);
+
+            return document;
+        }
+
+        let groups = self
+            .groups
+            .into_iter()
+            .map(|(key, accumulator)| {
+                let mut group = Document::new();
+                group.insert("key", key);
+                group.insert(name.clone(), result(function, accumulator));
+
+                Bson::Document(group)
+            })
+            .collect::<Vec<_>>();
+
+        let mut document = Document::new();
+        document.insert("groups", groups);
+
+        document
+    }
+
+    fn group(&mut self, key: Bson) -> &mut Accumulator {
+        let hash = key.clone().into_relaxed_extjson().to_string();
+
+        let position = match self.index.get(&hash) {
+            Some(position) => *position,
+            None => {
+                self.groups.push((key, Accumulator::default()));
+                self.index.insert(hash, self.groups.len() - 1);
+
+                self.groups.len() - 1
+            }
+        };
+
+        &mut self.groups[position].1
+    }
+}
+
+fn result(function: Aggregate, accumulator: Accumulator) -> Bson {
+    match function {
+        Aggregate::Count => Bson::Int64(accumulator.count),
+        Aggregate::Sum => accumulator.sum.unwrap_or(Bson::Int64(0)),
+        Aggregate::Avg if accumulator.count > 0 => {
+            Bson::Double(accumulator.total / accumulator.count as f64)
+        }
+        Aggregate::Avg => Bson::Null,
+        Aggregate::Min | Aggregate::Max => accumulator.best.unwrap_or(Bson::Null),
+    }
+}
+
+// values of different kinds are ordered like MongoDB does, e.g. numbers before strings
+fn order(a: &Bson, b: &Bson) -> Ordering {
+    filter::compare(a, b).unwrap_or_else(|| type_rank(a).cmp(&type_rank(b)))
+}
+
+fn type_rank(value: &Bson) -> u8 {
+    match value {
+        Bson::MinKey => 0,
+        Bson::Null | Bson::Undefined => 1,
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => 2,
+        Bson::String(_) | Bson::Symbol(_) => 3,
+        Bson::Document(_) => 4,
+        Bson::Array(_) => 5,
+        Bson::Binary(_) => 6,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::DateTime(_) => 9,
+        Bson::Timestamp(_) => 10,
+        Bson::RegularExpression(_) => 11,
+        Bson::DbPointer(_) => 12,
+        Bson::JavaScriptCode(_) | Bson::JavaScriptCodeWithScope(_) => 13,
+        Bson::MaxKey => 14,
+    }
+}
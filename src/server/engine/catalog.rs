@@ -0,0 +1,133 @@
+use bson::Document;
+use dustdata::DustData;
+use dustdata::Error as DustDataError;
+use serde::{Deserialize, Serialize};
+
+use super::validation::Schema;
+
+// the catalog lives in the system database, next to the users, under keys no username can have
+const DATABASE_PREFIX: &str = "_database.";
+const SCHEMA_PREFIX: &str = "_schema.";
+
+/// The options of a database, set by `create database ... with {...}`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseOptions {
+    /// size of the memtable in bytes before it is flushed to disk, the server one if `None`
+    pub flush_threshold: Option<usize>,
+    #[serde(default)]
+    pub cache: CachePolicy,
+    /// size on disk in bytes after which writes are refused
+    pub max_size: Option<u64>,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// Whether the values read from a database are kept in the cache
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CachePolicy {
+    #[default]
+    ReadThrough,
+    Disabled,
+}
+
+impl DatabaseOptions {
+    /// `from_document` reads the options written in a `create database` statement
+    ///
+    /// Arguments:
+    ///
+    /// * `options`: The options, e.g. `{"read_only": true}`.
+    ///
+    /// Returns:
+    ///
+    /// The options, or a message saying which one is invalid.
+    pub fn from_document(options: Document) -> Result<Self, String> {
+        bson::from_document(options).map_err(|e| format!("invalid database options: {}", e))
+    }
+}
+
+/// `is_reserved` checks if a key holds metadata rather than data: the catalog in the system
+/// database, and the expiries of keys in the other databases
+pub fn is_reserved(key: &str) -> bool {
+    key.starts_with('_')
+}
+
+/// `get` reads the options of a database from the catalog
+///
+/// Arguments:
+///
+/// * `system_db`: The system database.
+/// * `database`: The name of the database.
+///
+/// Returns:
+///
+/// The options of the database, `None` if it was not created with `create database`.
+pub fn get(system_db: &DustData, database: &str) -> Result<Option<DatabaseOptions>, DustDataError> {
+    let options = system_db.get(&format!("{}{}", DATABASE_PREFIX, database))?;
+
+    Ok(options.and_then(|options| bson::from_bson(options).ok()))
+}
+
+/// `insert` records the options of a new database in the catalog
+pub fn insert(
+    system_db: &mut DustData,
+    database: &str,
+    options: &DatabaseOptions,
+) -> Result<(), DustDataError> {
+    system_db.insert(
+        &format!("{}{}", DATABASE_PREFIX, database),
+        bson::to_bson(options).unwrap(),
+    )
+}
+
+/// `remove` forgets the options of a deleted database, if it had any
+pub fn remove(system_db: &mut DustData, database: &str) -> Result<(), DustDataError> {
+    let key = format!("{}{}", DATABASE_PREFIX, database);
+
+    if system_db.get(&key)?.is_some() {
+        system_db.delete(&key)?;
+    }
+
+    Ok(())
+}
+
+/// `get_schema` reads the schema of a database from the catalog
+///
+/// Returns:
+///
+/// The schema of the database, `None` if its values are not validated.
+pub fn get_schema(system_db: &DustData, database: &str) -> Result<Option<Schema>, DustDataError> {
+    let schema = system_db.get(&format!("{}{}", SCHEMA_PREFIX, database))?;
+
+    Ok(schema.and_then(|schema| bson::from_bson(schema).ok()))
+}
+
+/// `insert_schema` records the schema of a database in the catalog
+pub fn insert_schema(
+    system_db: &mut DustData,
+    database: &str,
+    schema: &Schema,
+) -> Result<(), DustDataError> {
+    system_db.insert(
+        &format!("{}{}", SCHEMA_PREFIX, database),
+        bson::to_bson(schema).unwrap(),
+    )
+}
+
+/// `remove_schema` stops validating the values of a database
+///
+/// Returns:
+///
+/// Whether the database had a schema.
+pub fn remove_schema(system_db: &mut DustData, database: &str) -> Result<bool, DustDataError> {
+    let key = format!("{}{}", SCHEMA_PREFIX, database);
+
+    if system_db.get(&key)?.is_some() {
+        system_db.delete(&key)?;
+
+        return Ok(true);
+    }
+
+    Ok(false)
+}
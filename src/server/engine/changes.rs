@@ -0,0 +1,247 @@
+use bson::Bson;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use crate::query::parser::WatchScope;
+use crate::server::wirewave::mailbox::Mailbox;
+use crate::server::wirewave::server::{ResHeader, Response, Status};
+
+use super::interface::TransactionError;
+
+/// How many of the last changes are kept for the watches resumed after a reconnect
+pub const HISTORY_SIZE: usize = 1024;
+
+/// A write to a key, as pushed to the watches of the key
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    /// the sequence number of the change, counted from 1 since the server started
+    pub seq: u64,
+    pub database: String,
+    pub key: String,
+    pub operation: Operation,
+    /// the value written, `None` for deletions
+    pub value: Option<Bson>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Insert,
+    Update,
+    Delete,
+    /// the key was purged after it expired
+    Expire,
+}
+
+impl Change {
+    /// `new` describes a write, numbered once it is emitted
+    pub fn new(database: &str, key: &str, operation: Operation, value: Option<Bson>) -> Self {
+        Self {
+            seq: 0,
+            database: database.to_string(),
+            key: key.to_string(),
+            operation,
+            value,
+        }
+    }
+}
+
+struct Watch {
+    database: String,
+    scope: WatchScope,
+    mailbox: Arc<Mailbox>,
+    // the last change pushed to the connection, which a lagging watch resumes after
+    last_seq: u64,
+}
+
+impl Watch {
+    fn matches(&self, change: &Change) -> bool {
+        if change.database != self.database {
+            return false;
+        }
+
+        match &self.scope {
+            WatchScope::Key(key) => change.key == *key,
+            WatchScope::Prefix(prefix) => change.key.starts_with(prefix.as_str()),
+            WatchScope::Database => true,
+        }
+    }
+}
+
+/// The changes written to the databases and the watches they are pushed to
+#[derive(Default)]
+pub struct ChangeHub {
+    last_seq: u64,
+    history: VecDeque<Arc<Change>>,
+    watches: HashMap<u64, Watch>,
+    last_watch: u64,
+}
+
+impl ChangeHub {
+    /// `emit` numbers a change and pushes it to the watches of its key
+    ///
+    /// A watch whose connection can't keep up is stopped, and told so with a frame whose body
+    /// has `lagged` set and the `seq` to resume after.
+    pub fn emit(&mut self, mut change: Change) {
+        self.last_seq += 1;
+        change.seq = self.last_seq;
+
+        let change = Arc::new(change);
+
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+
+        self.history.push_back(change.clone());
+
+        let mut stopped = Vec::new();
+
+        for (id, watch) in self.watches.iter_mut() {
+            if !watch.matches(&change) {
+                continue;
+            }
+
+            if watch.mailbox.push(event_frame(*id, &change)) {
+                watch.last_seq = change.seq;
+                continue;
+            }
+
+            if !watch.mailbox.is_closed() {
+                let body = bson::doc! {
+                    "watch": *id as i64,
+                    "lagged": true,
+                    "seq": watch.last_seq as i64,
+                };
+
+                watch.mailbox.push_anyway(frame(body));
+            }
+
+            stopped.push(*id);
+        }
+
+        for id in stopped {
+            self.watches.remove(&id);
+        }
+    }
+
+    /// `watch` starts pushing the changes of a database to a connection
+    ///
+    /// Arguments:
+    ///
+    /// * `database`: The database of the keys.
+    /// * `scope`: The keys to push the changes of.
+    /// * `after`: The last change the connection has seen, the kept changes after it are pushed
+    ///   first. `None` to push only the next changes.
+    /// * `mailbox`: The frames pushed to the connection.
+    ///
+    /// Returns:
+    ///
+    /// The id of the watch and the sequence number of the last change.
+    pub fn watch(
+        &mut self,
+        database: String,
+        scope: WatchScope,
+        after: Option<u64>,
+        mailbox: &Arc<Mailbox>,
+    ) -> Result<(u64, u64), TransactionError> {
+        if let Some(after) = after {
+            if after > self.last_seq {
+                return Err(TransactionError::ExternalError(
+                    Status::InvalidQuery,
+                    format!(
+                        "change {} was never reached, the last one is {} (was the server restarted?)",
+                        after, self.last_seq
+                    ),
+                ));
+            }
+
+            let oldest = self.history.front().map_or(self.last_seq + 1, |c| c.seq);
+
+            if oldest > after + 1 {
+                return Err(TransactionError::ExternalError(
+                    Status::NotFound,
+                    format!(
+                        "the changes after {} are no longer kept, the oldest kept is {}",
+                        after, oldest
+                    ),
+                ));
+            }
+        }
+
+        self.last_watch += 1;
+
+        let id = self.last_watch;
+        let watch = Watch {
+            database,
+            scope,
+            mailbox: mailbox.clone(),
+            last_seq: self.last_seq,
+        };
+
+        // at most `HISTORY_SIZE` frames, so the mailbox is allowed to overflow
+        if let Some(after) = after {
+            for change in self.history.iter().filter(|c| c.seq > after) {
+                if watch.matches(change) {
+                    mailbox.push_anyway(event_frame(id, change));
+                }
+            }
+        }
+
+        self.watches.insert(id, watch);
+
+        Ok((id, self.last_seq))
+    }
+
+    /// `unwatch` stops watches of a connection
+    ///
+    /// Arguments:
+    ///
+    /// * `mailbox`: The frames pushed to the connection.
+    /// * `id`: The watch to stop, `None` for all of them.
+    ///
+    /// Returns:
+    ///
+    /// The number of stopped watches.
+    pub fn unwatch(
+        &mut self,
+        mailbox: &Arc<Mailbox>,
+        id: Option<u64>,
+    ) -> Result<usize, TransactionError> {
+        let before = self.watches.len();
+
+        self.watches.retain(|watch_id, watch| {
+            !Arc::ptr_eq(&watch.mailbox, mailbox) || id.is_some_and(|id| id != *watch_id)
+        });
+
+        let stopped = before - self.watches.len();
+
+        if let (Some(id), 0) = (id, stopped) {
+            return Err(TransactionError::ExternalError(
+                Status::NotFound,
+                format!("watch {} not found", id),
+            ));
+        }
+
+        Ok(stopped)
+    }
+}
+
+fn event_frame(id: u64, change: &Change) -> Response {
+    let mut body = bson::to_document(change).unwrap();
+    body.insert("watch", id as i64);
+
+    frame(body)
+}
+
+fn frame(body: bson::Document) -> Response {
+    Response {
+        body: Some(Bson::Document(body)),
+        header: ResHeader {
+            is_error: false,
+            messages: None,
+            status: Status::Event,
+            diagnostic: None,
+        },
+    }
+}
@@ -0,0 +1,206 @@
+use bson::Bson;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::query::parser::DropPolicy;
+use crate::server::wirewave::mailbox::{Mailbox, MAILBOX_SIZE};
+use crate::server::wirewave::server::{ResHeader, Response, Status};
+use crate::utils;
+
+use super::interface::TransactionError;
+
+/// How many messages a subscription keeps while its connection is busy, unless it sets `buffer`
+pub const SUBSCRIPTION_BUFFER: usize = 1024;
+
+struct Subscription {
+    pattern: String,
+    buffer: usize,
+    drop: DropPolicy,
+    mailbox: Arc<Mailbox>,
+    // the messages dropped since the last one pushed, reported with the next one
+    dropped: u64,
+}
+
+/// The subscriptions of the connections to the channels messages are published on
+#[derive(Default)]
+pub struct ChannelHub {
+    subscriptions: HashMap<u64, Subscription>,
+    last_subscription: u64,
+}
+
+impl ChannelHub {
+    /// `publish` pushes a message to the subscriptions whose pattern matches its channel
+    ///
+    /// A subscription whose buffer is full drops a message, as set by its drop policy.
+    ///
+    /// Arguments:
+    ///
+    /// * `channel`: The channel the message is published on.
+    /// * `message`: The message.
+    ///
+    /// Returns:
+    ///
+    /// The number of subscriptions the message was pushed to.
+    pub fn publish(&mut self, channel: &str, message: &Bson) -> usize {
+        let mut pushed = 0;
+        let mut closed = Vec::new();
+
+        for (id, subscription) in self.subscriptions.iter_mut() {
+            if !utils::glob_match(&subscription.pattern, channel) {
+                continue;
+            }
+
+            let mailbox = subscription.mailbox.clone();
+
+            if mailbox.is_closed() {
+                closed.push(*id);
+                continue;
+            }
+
+            if mailbox.pending(*id) >= subscription.buffer {
+                match subscription.drop {
+                    DropPolicy::Newest => {
+                        subscription.dropped += 1;
+                        continue;
+                    }
+                    // the message after the dropped one reports it, with the ones it reported
+                    DropPolicy::Oldest => mailbox.drop_oldest(*id, |oldest, next| {
+                        let dropped = 1 + dropped_before(&oldest);
+
+                        match next {
+                            Some(next) => add_dropped(next, dropped),
+                            None => subscription.dropped += dropped,
+                        }
+                    }),
+                }
+            }
+
+            let body = bson::doc! {
+                "subscription": *id as i64,
+                "channel": channel,
+                "message": message.clone(),
+                "dropped": subscription.dropped as i64,
+            };
+
+            // the other frames of the connection filled its mailbox
+            if !mailbox.push_owned(*id, frame(body)) {
+                subscription.dropped += 1;
+                continue;
+            }
+
+            subscription.dropped = 0;
+            pushed += 1;
+        }
+
+        for id in closed {
+            self.subscriptions.remove(&id);
+        }
+
+        pushed
+    }
+
+    /// `subscribe` starts pushing the messages published on some channels to a connection
+    ///
+    /// Arguments:
+    ///
+    /// * `pattern`: The glob the channels match, see [`utils::glob_match`].
+    /// * `buffer`: The messages kept while the connection is busy, `SUBSCRIPTION_BUFFER` if `None`.
+    /// * `drop`: The messages dropped once the buffer is full.
+    /// * `mailbox`: The frames pushed to the connection.
+    ///
+    /// Returns:
+    ///
+    /// The id of the subscription.
+    pub fn subscribe(
+        &mut self,
+        pattern: String,
+        buffer: Option<usize>,
+        drop: DropPolicy,
+        mailbox: &Arc<Mailbox>,
+    ) -> Result<u64, TransactionError> {
+        let buffer = buffer.unwrap_or(SUBSCRIPTION_BUFFER);
+
+        if buffer > MAILBOX_SIZE {
+            return Err(TransactionError::ExternalError(
+                Status::InvalidQuery,
+                format!("buffer can be at most {} messages", MAILBOX_SIZE),
+            ));
+        }
+
+        self.last_subscription += 1;
+
+        let subscription = Subscription {
+            pattern,
+            buffer,
+            drop,
+            mailbox: mailbox.clone(),
+            dropped: 0,
+        };
+
+        self.subscriptions
+            .insert(self.last_subscription, subscription);
+
+        Ok(self.last_subscription)
+    }
+
+    /// `unsubscribe` stops subscriptions of a connection
+    ///
+    /// Arguments:
+    ///
+    /// * `mailbox`: The frames pushed to the connection.
+    /// * `id`: The subscription to stop, `None` for all of them.
+    ///
+    /// Returns:
+    ///
+    /// The number of stopped subscriptions.
+    pub fn unsubscribe(
+        &mut self,
+        mailbox: &Arc<Mailbox>,
+        id: Option<u64>,
+    ) -> Result<usize, TransactionError> {
+        let before = self.subscriptions.len();
+
+        self.subscriptions.retain(|subscription_id, subscription| {
+            !Arc::ptr_eq(&subscription.mailbox, mailbox)
+                || id.is_some_and(|id| id != *subscription_id)
+        });
+
+        let stopped = before - self.subscriptions.len();
+
+        if let (Some(id), 0) = (id, stopped) {
+            return Err(TransactionError::ExternalError(
+                Status::NotFound,
+                format!("subscription {} not found", id),
+            ));
+        }
+
+        Ok(stopped)
+    }
+}
+
+fn dropped_before(frame: &Response) -> u64 {
+    match &frame.body {
+        Some(Bson::Document(body)) => body.get_i64("dropped").unwrap_or(0) as u64,
+        _ => 0,
+    }
+}
+
+fn add_dropped(frame: &mut Response, dropped: u64) {
+    if let Some(Bson::Document(body)) = &mut frame.body {
+        let dropped = body.get_i64("dropped").unwrap_or(0) + dropped as i64;
+
+        body.insert("dropped", dropped);
+    }
+}
+
+fn frame(body: bson::Document) -> Response {
+    Response {
+        body: Some(Bson::Document(body)),
+        header: ResHeader {
+            is_error: false,
+            messages: None,
+            status: Status::Message,
+            diagnostic: None,
+        },
+    }
+}
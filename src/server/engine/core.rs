@@ -1,4 +1,4 @@
-use bson::Bson;
+use bson::{Bson, DateTime, Document};
 use dustdata::DustData;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
@@ -7,43 +7,120 @@ use crate::config;
 use crate::query;
 use crate::server;
 use crate::server::wirewave::server::ResHeader;
+use crate::utils;
 
 use config::schema;
 use server::cache;
+use server::route::Router;
 use server::wirewave;
 
 use cache::Cache;
-use query::parser::{ASTNode, Keywords, Verbs};
+use query::parser::{
+    path_to_string, ASTNode, Aggregate, Condition, DropPolicy, Guard, Keywords, ListOptions,
+    PathSegment, Verbs, WatchScope,
+};
 use wirewave::authorization::UserPermission;
 use wirewave::server::{Error, Response, Status};
+use wirewave::session::Session;
 
 use interface::TransactionError;
 
+use super::aggregate::Aggregation;
+use super::catalog::DatabaseOptions;
+use super::changes::ChangeHub;
+use super::channels::ChannelHub;
+use super::document;
 use super::interface;
+use super::transaction::{self, Transaction};
+use super::validation::Schema;
 
-pub struct Core {
+pub struct Core<'a> {
     interface: interface::DustDataInterface,
+    session: &'a mut Session,
+    // taken exclusively by `commit`, shared by every other statement
+    gate: Arc<RwLock<()>>,
 }
 
-impl Core {
+/// What running a statement involves, as reported by `explain`
+struct Plan {
+    // `None` if no database is selected or the statement does not run on one
+    database: Option<String>,
+    permission: Option<UserPermission>,
+    // keys read through the cache
+    cached_keys: Vec<String>,
+    scan: &'static str,
+    // indexed fields a `where` condition is narrowed down with
+    indexes: Vec<String>,
+}
+
+// what an index statement does
+enum IndexAction {
+    Create { unique: bool },
+    Drop,
+    Rebuild,
+}
+
+impl<'a> Core<'a> {
+    /// `new` builds the engine that runs the statements of a request
+    ///
+    /// Arguments:
+    ///
+    /// * `current_database`: The database of the request, `None` for the one of the session.
+    /// * `session`: The session of the connection the request was sent on.
+    /// * `gate`: The lock of the server that serializes commits with the other statements.
+    /// * `changes`: The changes pushed to the watches of the connections.
+    /// * `channels`: The subscriptions of the connections to the channels.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         cache: Arc<RwLock<Cache>>,
-        routers: Arc<RwLock<HashMap<String, DustData>>>,
+        routers: Arc<RwLock<HashMap<String, Router>>>,
         config: Arc<schema::RustbaseConfig>,
         system_db: Arc<RwLock<DustData>>,
-        current_database: String,
-        current_user: Option<String>,
+        current_database: Option<String>,
+        session: &'a mut Session,
+        gate: Arc<RwLock<()>>,
+        changes: Arc<RwLock<ChangeHub>>,
+        channels: Arc<RwLock<ChannelHub>>,
     ) -> Self {
+        // no database is written as an empty name, which no statement can select
+        let current_database = current_database
+            .or_else(|| session.database.clone())
+            .unwrap_or_default();
+
         let interface = interface::DustDataInterface::new(
             cache,
             routers,
             config,
             system_db,
             current_database,
-            current_user,
+            session.username.clone(),
+            session.permission,
+            changes,
+            channels,
         );
 
-        Self { interface }
+        Self {
+            interface,
+            session,
+            gate,
+        }
+    }
+
+    /// `select_database` checks that the statements of the request can run on a database, see
+    /// [`interface::DustDataInterface::select_database`]
+    pub fn select_database(&self, database: &str) -> Result<(), Error> {
+        match self.interface.select_database(database) {
+            Ok(()) => Ok(()),
+            Err(e) => self.dd_error(e).map(|_| ()),
+        }
+    }
+
+    fn current_database(&self) -> Option<String> {
+        if self.interface.current_database.is_empty() {
+            return None;
+        }
+
+        Some(self.interface.current_database.clone())
     }
 
     /// `run_ast` takes an ASTNode and returns a Result<Response, Status>
@@ -56,12 +133,100 @@ impl Core {
     ///
     /// A Result<Response, Status>
     pub fn run_ast(&mut self, ast: ASTNode) -> Result<Response, Error> {
+        if needs_database(&ast) && self.current_database().is_none() {
+            let error = Error {
+                message: "no database selected, send one in the request or run `use <database>`"
+                    .to_string(),
+                diagnostic: None,
+                status: Status::InvalidQuery,
+            };
+
+            return Err(error);
+        }
+
+        match ast {
+            ASTNode::BeginExpression => return self.ast_begin(),
+            ASTNode::CommitExpression => return self.ast_commit(),
+            ASTNode::RollbackExpression => return self.ast_rollback(),
+            _ => {}
+        }
+
+        // statements don't run while a transaction is committed, so they never see half of it
+        let gate = self.gate.clone();
+        let _running = gate.read().unwrap();
+
+        if self.session.transaction.is_some() && !runs_in_transaction(&ast) {
+            if scans(&ast) {
+                return query_error(
+                    "statements that scan a database (list, get where and aggregates) can't be run \
+                    in a transaction, run them before begin or after commit",
+                );
+            }
+
+            if reads(&ast) {
+                return self.ast_read(ast);
+            }
+
+            if needs_database(&ast) {
+                return self.ast_queue(ast);
+            }
+
+            return query_error(
+                "only data statements can be run in a transaction, commit or rollback first",
+            );
+        }
+
+        self.dispatch(ast)
+    }
+
+    // runs a statement, without the transaction handling of `run_ast`
+    fn dispatch(&mut self, ast: ASTNode) -> Result<Response, Error> {
         match ast {
+            ASTNode::ExplainExpression { statement } => self.ast_explain(*statement),
+
+            ASTNode::UseExpression { database } => self.ast_use(*database),
+
+            ASTNode::WhoamiExpression => self.ast_whoami(),
+
+            ASTNode::CreateExpression {
+                verb,
+                ident,
+                options,
+            } => match verb {
+                Verbs::Database => self.ast_database_create(*ident, options.map(|o| *o)),
+
+                Verbs::User => query_error("users are created with `insert user`"),
+            },
+
+            ASTNode::CreateSchemaExpression { database, schema } => {
+                self.ast_schema_create(*database, *schema)
+            }
+
+            ASTNode::DeleteSchemaExpression { database } => self.ast_schema_delete(*database),
+
+            ASTNode::CreateIndexExpression {
+                database,
+                field,
+                unique,
+            } => self.ast_index(IndexAction::Create { unique }, *database, field),
+
+            ASTNode::DropIndexExpression { database, field } => {
+                self.ast_index(IndexAction::Drop, *database, field)
+            }
+
+            ASTNode::RebuildIndexExpression { database, field } => {
+                self.ast_index(IndexAction::Rebuild, *database, field)
+            }
+
+            ASTNode::AssignmentExpression { ident, value } => self.ast_assign(ident, *value),
+
             ASTNode::IntoExpression {
                 keyword,
                 json,
                 ident,
-            } => self.expr_into(keyword, *json, *ident),
+                guard,
+                expire,
+            } => self.expr_into(keyword, *json, *ident, guard, expire),
 
             ASTNode::MonadicExpression {
                 keyword,
@@ -70,10 +235,71 @@ impl Core {
             } => self.monadic_expr(keyword, verb, expr),
 
             ASTNode::SingleExpression { keyword, ident } => self.sgl_expr(keyword, ident),
+
+            ASTNode::MultiExpression { keyword, idents } => self.multi_expr(keyword, idents),
+
+            ASTNode::ListExpression { keyword, options } => self.list_expr(keyword, options),
+
+            ASTNode::MultiIntoExpression { keyword, entries } => {
+                self.multi_into_expr(keyword, entries)
+            }
+
+            ASTNode::WhereExpression { keyword, condition } => self.where_expr(keyword, condition),
+
+            ASTNode::AggregateExpression {
+                function,
+                field,
+                condition,
+                group_by,
+            } => self.ast_aggregate(function, field, condition, group_by),
+
+            ASTNode::PatchExpression {
+                keyword,
+                ident,
+                patch,
+            } => self.patch_expr(keyword, *ident, *patch),
+
+            ASTNode::RenameExpression { ident, to } => {
+                self.ast_transfer(*ident, None, Some(*to), false)
+            }
+
+            ASTNode::CopyExpression {
+                ident,
+                to,
+                database,
+            } => self.ast_transfer(*ident, database.map(|d| *d), Some(*to), true),
+
+            ASTNode::MoveExpression { ident, database } => {
+                self.ast_transfer(*ident, Some(*database), None, false)
+            }
+
+            ASTNode::CounterExpression { keyword, ident, by } => {
+                self.counter_expr(keyword, *ident, by.map(|by| *by))
+            }
+
+            ASTNode::ExpireExpression { ident, seconds } => self.ast_expire(*ident, Some(seconds)),
+
+            ASTNode::PersistExpression { ident } => self.ast_expire(*ident, None),
+
+            ASTNode::TtlExpression { ident } => self.ast_ttl(*ident),
+
+            ASTNode::WatchExpression { scope, after } => self.ast_watch(scope, after),
+
+            ASTNode::UnwatchExpression { watch } => self.ast_unwatch(watch),
+
+            ASTNode::PublishExpression { channel, message } => self.ast_publish(channel, *message),
+
+            ASTNode::SubscribeExpression {
+                pattern,
+                buffer,
+                drop,
+            } => self.ast_subscribe(pattern, buffer, drop.unwrap_or_default()),
+
+            ASTNode::UnsubscribeExpression { subscription } => self.ast_unsubscribe(subscription),
             _ => {
                 let error = Error {
                     message: "Invalid query".to_string(),
-                    query_message: None,
+                    diagnostic: None,
                     status: Status::InvalidQuery,
                 };
 
@@ -82,6 +308,334 @@ impl Core {
         }
     }
 
+    /// It describes how a statement would be run, without running it
+    ///
+    /// Arguments:
+    ///
+    /// * `statement`: The statement to explain.
+    ///
+    /// Returns:
+    ///
+    /// A response whose body has the `ast` of the statement and its canonical `rbql` (passwords
+    /// hidden in both), the `database` it runs on, the `permission` it requires, whether the
+    /// `cache` would serve it (`null` if it does not read through the cache) and its `scan`: `key`
+    /// for direct key accesses, `index` when a `where` condition is narrowed down with the
+    /// `indexes` fields, `full` when every key of the database is read, `none` when no data is
+    /// read.
+    fn ast_explain(&mut self, statement: ASTNode) -> Result<Response, Error> {
+        let plan = self.plan(&statement);
+
+        let cache = if plan.cached_keys.is_empty() {
+            Bson::Null
+        } else {
+            let cached = plan
+                .cached_keys
+                .iter()
+                .all(|key| self.interface.is_cached(key));
+
+            Bson::Boolean(cached)
+        };
+
+        let body = bson::doc! {
+            "ast": bson::to_bson(&statement.redacted()).unwrap(),
+            "rbql": statement.to_string(),
+            "database": plan.database,
+            "permission": bson::to_bson(&plan.permission).unwrap(),
+            "cache": cache,
+            "scan": plan.scan,
+            "indexes": plan.indexes,
+        };
+
+        Ok(Response {
+            body: Some(Bson::Document(body)),
+            header: ResHeader {
+                is_error: false,
+                messages: None,
+                status: Status::Ok,
+                diagnostic: None,
+            },
+        })
+    }
+
+    fn plan(&self, statement: &ASTNode) -> Plan {
+        let mut plan = Plan {
+            database: self.current_database(),
+            permission: None,
+            cached_keys: Vec::new(),
+            scan: "none",
+            indexes: Vec::new(),
+        };
+
+        match statement {
+            ASTNode::IntoExpression { .. }
+            | ASTNode::MultiIntoExpression { .. }
+            | ASTNode::PatchExpression { .. }
+            | ASTNode::CounterExpression { .. } => {
+                plan.permission = Some(UserPermission::Write);
+                plan.scan = "key";
+            }
+
+            ASTNode::ExpireExpression { .. } | ASTNode::PersistExpression { .. } => {
+                plan.permission = Some(UserPermission::Write);
+                plan.scan = "key";
+            }
+
+            ASTNode::TtlExpression { .. } => {
+                plan.permission = Some(UserPermission::Read);
+                plan.scan = "key";
+            }
+
+            // the keys are not read, their next changes are pushed
+            ASTNode::WatchExpression { .. } => plan.permission = Some(UserPermission::Read),
+
+            ASTNode::UnwatchExpression { .. } => plan.database = None,
+
+            // channels belong to no database
+            ASTNode::PublishExpression { .. } => {
+                plan.database = None;
+                plan.permission = Some(UserPermission::Write);
+            }
+
+            ASTNode::SubscribeExpression { .. } => {
+                plan.database = None;
+                plan.permission = Some(UserPermission::Read);
+            }
+
+            ASTNode::UnsubscribeExpression { .. } => plan.database = None,
+
+            // the value is read from one key and written to another
+            ASTNode::RenameExpression { .. }
+            | ASTNode::CopyExpression { .. }
+            | ASTNode::MoveExpression { .. } => {
+                plan.permission = Some(UserPermission::ReadAndWrite);
+                plan.scan = "key";
+            }
+
+            ASTNode::SingleExpression { keyword, ident } => match keyword {
+                Keywords::Get => {
+                    plan.permission = Some(UserPermission::Read);
+                    plan.scan = "key";
+
+                    match ident.as_deref() {
+                        Some(ASTNode::Identifier(ident))
+                        | Some(ASTNode::PathExpression { ident, .. }) => {
+                            plan.cached_keys.push(ident.clone())
+                        }
+                        _ => {}
+                    }
+                }
+                Keywords::List => {
+                    plan.permission = Some(UserPermission::Read);
+                    plan.scan = "full";
+                }
+                _ => {
+                    plan.permission = Some(UserPermission::Write);
+                    plan.scan = "key";
+                }
+            },
+
+            ASTNode::MultiExpression { keyword, idents } => {
+                plan.scan = "key";
+
+                if let Keywords::Get = keyword {
+                    plan.permission = Some(UserPermission::Read);
+
+                    for ident in idents {
+                        if let ASTNode::Identifier(ident) = ident {
+                            plan.cached_keys.push(ident.clone());
+                        }
+                    }
+                } else {
+                    plan.permission = Some(UserPermission::Write);
+                }
+            }
+
+            ASTNode::WhereExpression { condition, .. }
+            | ASTNode::AggregateExpression {
+                condition: Some(condition),
+                ..
+            } => {
+                plan.permission = Some(UserPermission::Read);
+                plan.indexes = self.interface.indexed_fields(condition);
+                plan.scan = if plan.indexes.is_empty() {
+                    "full"
+                } else {
+                    "index"
+                };
+            }
+
+            ASTNode::ListExpression { .. } | ASTNode::AggregateExpression { .. } => {
+                plan.permission = Some(UserPermission::Read);
+                plan.scan = "full";
+            }
+
+            ASTNode::MonadicExpression {
+                keyword: Keywords::List,
+                verb,
+                ..
+            } => match verb {
+                Verbs::User => {
+                    plan.database = Some("_default".to_string());
+                    plan.permission = Some(UserPermission::Admin);
+                    plan.scan = "full";
+                }
+                // every database is listed, none is read
+                Verbs::Database => {
+                    plan.database = None;
+                    plan.permission = Some(UserPermission::Read);
+                }
+            },
+
+            ASTNode::MonadicExpression { verb, expr, .. } => {
+                plan.permission = Some(UserPermission::Admin);
+
+                match verb {
+                    // users live in the system database
+                    Verbs::User => plan.database = Some("_default".to_string()),
+                    Verbs::Database => {
+                        if let Some(ASTNode::Identifier(database)) =
+                            expr.as_ref().and_then(|expr| expr.first())
+                        {
+                            plan.database = Some(database.clone());
+                        }
+                    }
+                }
+            }
+
+            ASTNode::UseExpression { database } => {
+                if let ASTNode::Identifier(database) = database.as_ref() {
+                    plan.database = Some(database.clone());
+                }
+            }
+
+            ASTNode::CreateExpression {
+                ident: database, ..
+            }
+            | ASTNode::CreateSchemaExpression { database, .. }
+            | ASTNode::DeleteSchemaExpression { database }
+            | ASTNode::CreateIndexExpression { database, .. }
+            | ASTNode::DropIndexExpression { database, .. }
+            | ASTNode::RebuildIndexExpression { database, .. } => {
+                plan.permission = Some(UserPermission::Admin);
+
+                if let ASTNode::Identifier(database) = database.as_ref() {
+                    plan.database = Some(database.clone());
+                }
+            }
+
+            ASTNode::AssignmentExpression { .. } | ASTNode::WhoamiExpression => {
+                plan.database = None
+            }
+
+            _ => {}
+        }
+
+        plan
+    }
+
+    /// `ast_use` selects the database the next statements and requests of the session run on
+    ///
+    /// Arguments:
+    ///
+    /// * `database`: The identifier of the database.
+    ///
+    /// Returns:
+    ///
+    /// An empty response.
+    fn ast_use(&mut self, database: ASTNode) -> Result<Response, Error> {
+        let database = match database {
+            ASTNode::Identifier(database) => database,
+            _ => {
+                let error = Error {
+                    message: "Invalid database name".to_string(),
+                    diagnostic: None,
+                    status: Status::InvalidQuery,
+                };
+
+                return Err(error);
+            }
+        };
+
+        self.select_database(&database)?;
+
+        self.interface.current_database = database.clone();
+        self.session.database = Some(database);
+
+        Ok(Response {
+            body: None,
+            header: ResHeader {
+                is_error: false,
+                messages: None,
+                status: Status::Ok,
+                diagnostic: None,
+            },
+        })
+    }
+
+    /// `ast_whoami` describes the session the statement is run in
+    ///
+    /// Returns:
+    ///
+    /// A response whose body has the `username` and `permission` of the user (both `null` when the
+    /// server does not require authentication) and the `database` selected by the session.
+    fn ast_whoami(&mut self) -> Result<Response, Error> {
+        let body = bson::doc! {
+            "username": self.session.username.clone(),
+            "permission": bson::to_bson(&self.session.permission).unwrap(),
+            "database": self.session.database.clone(),
+        };
+
+        Ok(Response {
+            body: Some(Bson::Document(body)),
+            header: ResHeader {
+                is_error: false,
+                messages: None,
+                status: Status::Ok,
+                diagnostic: None,
+            },
+        })
+    }
+
+    /// `ast_assign` sets a variable of the session
+    ///
+    /// Variables are bound to the placeholders of the next requests that are not sent in their
+    /// `params`.
+    ///
+    /// Arguments:
+    ///
+    /// * `ident`: The name of the variable.
+    /// * `value`: The value of the variable.
+    ///
+    /// Returns:
+    ///
+    /// An empty response.
+    fn ast_assign(&mut self, ident: String, value: ASTNode) -> Result<Response, Error> {
+        let value = match value {
+            ASTNode::Bson(value) => value,
+            _ => {
+                let error = Error {
+                    message: format!("{} can only be set to a value", ident),
+                    diagnostic: None,
+                    status: Status::InvalidQuery,
+                };
+
+                return Err(error);
+            }
+        };
+
+        self.session.variables.insert(ident, value);
+
+        Ok(Response {
+            body: None,
+            header: ResHeader {
+                is_error: false,
+                messages: None,
+                status: Status::Ok,
+                diagnostic: None,
+            },
+        })
+    }
+
     /// `expr_into` is a function that takes a keyword, a value, and an expression, and returns a response
     /// or a status
     ///
@@ -90,6 +644,7 @@ impl Core {
     /// * `keyword`: The keyword that was used in the query.
     /// * `value`: The value to be inserted or updated.
     /// * `expr`: The expression to be evaluated.
+    /// * `guard`: The condition of a conditional write.
     ///
     /// Returns:
     ///
@@ -99,16 +654,40 @@ impl Core {
         keyword: Keywords,
         value: ASTNode,
         expr: ASTNode,
+        guard: Option<Guard>,
+        expire: Option<u64>,
     ) -> Result<Response, Error> {
-        match keyword {
-            Keywords::Insert => self.ast_into_insert(value, expr),
+        let expire = expire.map(expires_in);
 
-            Keywords::Update => self.ast_into_update(value, expr),
+        match (keyword, guard) {
+            (Keywords::Insert, None) => self.ast_into_insert(value, expr, expire),
 
-            _ => {
+            (Keywords::Insert, Some(Guard::Absent)) => {
+                self.ast_into_insert_if_absent(value, expr, expire)
+            }
+
+            (Keywords::Update, None) => self.ast_into_update(value, expr, expire),
+
+            (Keywords::Update, Some(Guard::Equals(expected))) => {
+                self.ast_into_compare_and_swap(value, expr, expected, expire)
+            }
+
+            (Keywords::Upsert, None) => self.ast_into_upsert(value, expr, expire),
+
+            (keyword, Some(_)) => {
+                let error = Error {
+                    message: format!("{:?} does not support this condition", keyword),
+                    diagnostic: None,
+                    status: Status::InvalidQuery,
+                };
+
+                Err(error)
+            }
+
+            (keyword, None) => {
                 let error = Error {
                     message: format!("{:?} is unexpected for into expression", keyword),
-                    query_message: None,
+                    diagnostic: None,
                     status: Status::InvalidQuery,
                 };
 
@@ -142,7 +721,7 @@ impl Core {
                 _ => {
                     let error = Error {
                         message: format!("{:?} is unexpected for insert expression", verb),
-                        query_message: None,
+                        diagnostic: None,
                         status: Status::InvalidQuery,
                     };
 
@@ -156,13 +735,23 @@ impl Core {
                 Verbs::User => self.ast_user_delete(expr),
             },
 
+            Keywords::List if expr.is_some() => {
+                query_error(&format!("list {} takes no arguments", verb))
+            }
+
+            Keywords::List => match verb {
+                Verbs::Database => self.ast_database_list(),
+
+                Verbs::User => self.ast_user_list(),
+            },
+
             Keywords::Update => match verb {
                 Verbs::User => self.ast_user_update(expr),
 
                 _ => {
                     let error = Error {
                         message: format!("{:?} is unexpected for update expression", verb),
-                        query_message: None,
+                        diagnostic: None,
                         status: Status::InvalidQuery,
                     };
 
@@ -173,7 +762,7 @@ impl Core {
             _ => {
                 let error = Error {
                     message: format!("{:?} is unexpected for monadic expression", keyword),
-                    query_message: None,
+                    diagnostic: None,
                     status: Status::InvalidQuery,
                 };
 
@@ -207,7 +796,7 @@ impl Core {
             _ => {
                 let error = Error {
                     message: format!("{:?} is unexpected for single expression", keyword),
-                    query_message: None,
+                    diagnostic: None,
                     status: Status::InvalidQuery,
                 };
 
@@ -216,69 +805,1096 @@ impl Core {
         }
     }
 
-    /// It takes a key and a value, and inserts the value into the database
+    /// It takes a keyword and several keys, and runs the keyword over all of them at once
     ///
     /// Arguments:
     ///
-    /// * `value`: The value to insert into the database.
-    /// * `expr`: The expression that is being evaluated.
+    /// * `keyword`: The keyword that was used to start the query.
+    /// * `idents`: The keys to operate on.
     ///
     /// Returns:
     ///
     /// A response object.
-    fn ast_into_insert(&mut self, value: ASTNode, expr: ASTNode) -> Result<Response, Error> {
-        let key = match expr {
-            ASTNode::Identifier(ident) => ident,
-            _ => return query_error("key must be an identifier"),
-        };
+    fn multi_expr(&mut self, keyword: Keywords, idents: Vec<ASTNode>) -> Result<Response, Error> {
+        let mut keys = Vec::with_capacity(idents.len());
 
-        let value = match value {
-            ASTNode::Bson(json) => json,
-            _ => return query_error("value must be a json object"),
-        };
+        for ident in idents {
+            match ident {
+                ASTNode::Identifier(ident) => keys.push(ident),
+                _ => return query_error("key must be an identifier"),
+            }
+        }
 
-        match self.interface.insert_into_dustdata(key, value) {
-            Ok(_) => Ok(Response {
-                body: None,
-                header: ResHeader {
-                    is_error: false,
-                    messages: None,
-                    status: Status::Ok,
-                },
-            }),
+        match keyword {
+            Keywords::Get => self.ast_multi_get(keys),
 
-            Err(e) => self.dd_error(e),
-        }
-    }
+            Keywords::Delete => self.ast_multi_delete(keys),
 
-    /// It takes an ASTNode and an ASTNode, and returns a Result<Response, Error>
-    ///
-    /// Arguments:
-    ///
-    /// * `value`: The value to update the key with.
+            _ => {
+                let error = Error {
+                    message: format!("{:?} is unexpected for multi expression", keyword),
+                    diagnostic: None,
+                    status: Status::InvalidQuery,
+                };
+
+                Err(error)
+            }
+        }
+    }
+
+    /// It takes `list` and the clauses of a scan, and lists the matching keys
+    ///
+    /// Arguments:
+    ///
+    /// * `keyword`: The keyword that was used to start the query.
+    /// * `options`: The prefix, range, limit and cursor of the scan.
+    ///
+    /// Returns:
+    ///
+    /// A response object.
+    fn list_expr(&mut self, keyword: Keywords, options: ListOptions) -> Result<Response, Error> {
+        match keyword {
+            Keywords::List => self.ast_list_scan(options),
+
+            _ => {
+                let error = Error {
+                    message: format!("{:?} is unexpected for list expression", keyword),
+                    diagnostic: None,
+                    status: Status::InvalidQuery,
+                };
+
+                Err(error)
+            }
+        }
+    }
+
+    /// It takes a keyword and several values with their keys, and writes all of them at once
+    ///
+    /// Arguments:
+    ///
+    /// * `keyword`: The keyword that was used to start the query.
+    /// * `entries`: The values and the keys they go into.
+    ///
+    /// Returns:
+    ///
+    /// A response object.
+    fn multi_into_expr(
+        &mut self,
+        keyword: Keywords,
+        entries: Vec<(ASTNode, ASTNode)>,
+    ) -> Result<Response, Error> {
+        let mut values = Vec::with_capacity(entries.len());
+
+        for (value, ident) in entries {
+            let key = match ident {
+                ASTNode::Identifier(ident) => ident,
+                _ => return query_error("key must be an identifier"),
+            };
+
+            let value = match value {
+                ASTNode::Bson(json) => json,
+                _ => return query_error("value must be a json object"),
+            };
+
+            values.push((key, value));
+        }
+
+        match keyword {
+            Keywords::Insert => match self.interface.insert_many_into_dustdata(values) {
+                Ok(_) => Ok(Response {
+                    body: None,
+                    header: ResHeader {
+                        is_error: false,
+                        messages: None,
+                        status: Status::Ok,
+                        diagnostic: None,
+                    },
+                }),
+
+                Err(e) => self.dd_error(e),
+            },
+
+            _ => {
+                let error = Error {
+                    message: format!("{:?} is unexpected for multi into expression", keyword),
+                    diagnostic: None,
+                    status: Status::InvalidQuery,
+                };
+
+                Err(error)
+            }
+        }
+    }
+
+    /// It takes a keyword and a condition, and runs the keyword over every value matching the
+    /// condition
+    ///
+    /// Arguments:
+    ///
+    /// * `keyword`: The keyword that was used to start the query.
+    /// * `condition`: The condition the values must match.
+    ///
+    /// Returns:
+    ///
+    /// A response object.
+    fn where_expr(&mut self, keyword: Keywords, condition: Condition) -> Result<Response, Error> {
+        match keyword {
+            Keywords::Get => self.ast_where_get(condition),
+
+            _ => {
+                let error = Error {
+                    message: format!("{:?} is unexpected for where expression", keyword),
+                    diagnostic: None,
+                    status: Status::InvalidQuery,
+                };
+
+                Err(error)
+            }
+        }
+    }
+
+    /// It takes a keyword, a key and update operators, and patches the value of the key
+    ///
+    /// Arguments:
+    ///
+    /// * `keyword`: The keyword that was used to start the query.
+    /// * `ident`: The key to patch.
+    /// * `patch`: The update operators.
+    ///
+    /// Returns:
+    ///
+    /// A response object.
+    fn patch_expr(
+        &mut self,
+        keyword: Keywords,
+        ident: ASTNode,
+        patch: ASTNode,
+    ) -> Result<Response, Error> {
+        match keyword {
+            Keywords::Update => self.ast_patch_update(ident, patch),
+
+            _ => {
+                let error = Error {
+                    message: format!("{:?} is unexpected for patch expression", keyword),
+                    diagnostic: None,
+                    status: Status::InvalidQuery,
+                };
+
+                Err(error)
+            }
+        }
+    }
+
+    /// It takes `incr` or `decr`, a key and an optional amount, and changes the counter stored in
+    /// the key
+    ///
+    /// Arguments:
+    ///
+    /// * `keyword`: `incr` or `decr`.
+    /// * `ident`: The key of the counter.
+    /// * `by`: The amount to add or subtract, 1 by default.
+    ///
+    /// Returns:
+    ///
+    /// A response whose body is the new value of the counter.
+    fn counter_expr(
+        &mut self,
+        keyword: Keywords,
+        ident: ASTNode,
+        by: Option<ASTNode>,
+    ) -> Result<Response, Error> {
+        let key = match ident {
+            ASTNode::Identifier(ident) => ident,
+            _ => return query_error("key must be an identifier"),
+        };
+
+        let by = match by {
+            Some(ASTNode::Bson(by)) => by,
+            None => Bson::Int64(1),
+            _ => return query_error("amount must be a number"),
+        };
+
+        let by = match (keyword.clone(), by) {
+            (Keywords::Incr, by) => by,
+            (Keywords::Decr, Bson::Int64(by)) => match by.checked_neg() {
+                Some(by) => Bson::Int64(by),
+                None => return query_error("amount is out of range"),
+            },
+            (Keywords::Decr, Bson::Int32(by)) => Bson::Int64(-(by as i64)),
+            (Keywords::Decr, Bson::Double(by)) => Bson::Double(-by),
+            (Keywords::Decr, _) => return query_error("amount must be a number"),
+
+            _ => {
+                let error = Error {
+                    message: format!("{:?} is unexpected for counter expression", keyword),
+                    diagnostic: None,
+                    status: Status::InvalidQuery,
+                };
+
+                return Err(error);
+            }
+        };
+
+        match self.interface.increment_dustdata(key, by) {
+            Ok(value) => Ok(Response {
+                body: Some(value),
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// It takes a key and a value, and inserts the value into the database
+    ///
+    /// Arguments:
+    ///
+    /// * `value`: The value to insert into the database.
+    /// * `expr`: The expression that is being evaluated.
+    /// * `expire`: When the key expires, never if `None`.
+    ///
+    /// Returns:
+    ///
+    /// A response object.
+    fn ast_into_insert(
+        &mut self,
+        value: ASTNode,
+        expr: ASTNode,
+        expire: Option<DateTime>,
+    ) -> Result<Response, Error> {
+        let key = match expr {
+            ASTNode::Identifier(ident) => ident,
+            _ => return query_error("key must be an identifier"),
+        };
+
+        let value = match value {
+            ASTNode::Bson(json) => json,
+            _ => return query_error("value must be a json object"),
+        };
+
+        match self.interface.insert_into_dustdata(key, value, expire) {
+            Ok(_) => Ok(Response {
+                body: None,
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// It takes an ASTNode and an ASTNode, and returns a Result<Response, Error>
+    ///
+    /// Arguments:
+    ///
+    /// * `value`: The value to update the key with.
     /// * `expr`: The expression to evaluate.
+    /// * `expire`: When the key expires, it keeps its current expiry if `None`.
+    ///
+    /// Returns:
+    ///
+    /// A response object.
+    fn ast_into_update(
+        &mut self,
+        value: ASTNode,
+        expr: ASTNode,
+        expire: Option<DateTime>,
+    ) -> Result<Response, Error> {
+        let key = match expr {
+            ASTNode::Identifier(ident) => ident,
+            _ => return query_error("key must be an identifier"),
+        };
+
+        let value = match value {
+            ASTNode::Bson(json) => json,
+            _ => return query_error("value must be a json object"),
+        };
+
+        match self.interface.update_dustdata(key, value, expire) {
+            Ok(_) => Ok(Response {
+                body: None,
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// It inserts a value into the database, unless the key already exists
+    ///
+    /// Arguments:
+    ///
+    /// * `value`: The value to insert into the database.
+    /// * `expr`: The key to insert into.
+    /// * `expire`: When the key expires, never if `None`.
+    ///
+    /// Returns:
+    ///
+    /// A response whose status is `Skipped` if the key already existed.
+    fn ast_into_insert_if_absent(
+        &mut self,
+        value: ASTNode,
+        expr: ASTNode,
+        expire: Option<DateTime>,
+    ) -> Result<Response, Error> {
+        let key = match expr {
+            ASTNode::Identifier(ident) => ident,
+            _ => return query_error("key must be an identifier"),
+        };
+
+        let value = match value {
+            ASTNode::Bson(json) => json,
+            _ => return query_error("value must be a json object"),
+        };
+
+        match self.interface.insert_if_absent_dustdata(key, value, expire) {
+            Ok(inserted) => Ok(Response {
+                body: None,
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: if inserted {
+                        Status::Ok
+                    } else {
+                        Status::Skipped
+                    },
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// It replaces the value of a key, but only if the current value is the expected one
+    ///
+    /// Arguments:
+    ///
+    /// * `value`: The new value.
+    /// * `expr`: The key to update.
+    /// * `expected`: The value the key must currently have.
+    /// * `expire`: When the key expires, it keeps its current expiry if `None`.
+    ///
+    /// Returns:
+    ///
+    /// A response object, or a `Conflict` error if the value is not the expected one.
+    fn ast_into_compare_and_swap(
+        &mut self,
+        value: ASTNode,
+        expr: ASTNode,
+        expected: Bson,
+        expire: Option<DateTime>,
+    ) -> Result<Response, Error> {
+        let key = match expr {
+            ASTNode::Identifier(ident) => ident,
+            _ => return query_error("key must be an identifier"),
+        };
+
+        let value = match value {
+            ASTNode::Bson(json) => json,
+            _ => return query_error("value must be a json object"),
+        };
+
+        match self
+            .interface
+            .compare_and_swap_dustdata(key, expected, value, expire)
+        {
+            Ok(_) => Ok(Response {
+                body: None,
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// It inserts a value into the database, replacing the current value if the key exists
+    ///
+    /// Arguments:
+    ///
+    /// * `value`: The value to write.
+    /// * `expr`: The key to write to.
+    /// * `expire`: When the key expires, it keeps its current expiry if `None`.
+    ///
+    /// Returns:
+    ///
+    /// A response whose status is `Inserted` or `Updated`.
+    fn ast_into_upsert(
+        &mut self,
+        value: ASTNode,
+        expr: ASTNode,
+        expire: Option<DateTime>,
+    ) -> Result<Response, Error> {
+        let key = match expr {
+            ASTNode::Identifier(ident) => ident,
+            _ => return query_error("key must be an identifier"),
+        };
+
+        let value = match value {
+            ASTNode::Bson(json) => json,
+            _ => return query_error("value must be a json object"),
+        };
+
+        match self.interface.upsert_dustdata(key, value, expire) {
+            Ok(inserted) => Ok(Response {
+                body: None,
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: if inserted {
+                        Status::Inserted
+                    } else {
+                        Status::Updated
+                    },
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// It applies update operators to the value of a key, without replacing the whole value
+    ///
+    /// Arguments:
+    ///
+    /// * `ident`: The key to patch.
+    /// * `patch`: A document of update operators such as `$set` or `$inc`.
+    ///
+    /// Returns:
+    ///
+    /// A response whose body is the patched value.
+    fn ast_patch_update(&mut self, ident: ASTNode, patch: ASTNode) -> Result<Response, Error> {
+        let key = match ident {
+            ASTNode::Identifier(ident) => ident,
+            _ => return query_error("key must be an identifier"),
+        };
+
+        let patch = match patch {
+            ASTNode::Bson(Bson::Document(patch)) => patch,
+            _ => return query_error("patch must be a json object"),
+        };
+
+        match self.interface.patch_dustdata(key, patch) {
+            Ok(value) => Ok(Response {
+                body: Some(value),
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// It takes a `Vec<ASTNode>` and returns a `Result<Response, Error>`
+    ///
+    /// Arguments:
+    ///
+    /// * `expr`: Option<Vec<ASTNode>>
+    ///
+    /// Returns:
+    ///
+    /// A response object
+    fn ast_user_insert(&mut self, expr: Option<Vec<ASTNode>>) -> Result<Response, Error> {
+        if expr.is_none() {
+            return query_error("user insert must have an expression");
+        }
+
+        let expr = expr.unwrap();
+
+        let mut username = String::new();
+        let mut permission = String::new();
+        let mut password = String::new();
+
+        // idk if this is the best way to do this
+        for node in expr {
+            match node {
+                // this will find the password and permission
+                ASTNode::AssignmentExpression { ident, value } => {
+                    match ident.as_str() {
+                        "password" => {
+                            password = match *value {
+                                ASTNode::Bson(s) => {
+                                    let s = s.as_str();
+
+                                    // if the password is not a string, return an error
+                                    if let Some(s) = s {
+                                        s.to_string()
+                                    } else {
+                                        return query_error("password must be a string");
+                                    }
+                                }
+
+                                _ => {
+                                    return query_error("password must be a string");
+                                }
+                            }
+                        }
+
+                        "permission" => {
+                            permission = match *value {
+                                ASTNode::Bson(s) => {
+                                    let s = s.as_str();
+
+                                    // if the permission is not a string, return an error
+                                    if let Some(s) = s {
+                                        s.to_string()
+                                    } else {
+                                        return query_error("permission must be a string");
+                                    }
+                                }
+                                _ => {
+                                    return query_error("permission must be a string");
+                                }
+                            }
+                        }
+
+                        _ => {}
+                    }
+                }
+
+                ASTNode::Identifier(ref ident) => username = ident.clone(),
+
+                _ => {}
+            }
+        }
+
+        if username.is_empty() || password.is_empty() || permission.is_empty() {
+            return query_error("username, password, and permission are required");
+        }
+
+        let permission = UserPermission::from_str(permission.as_str());
+
+        if permission.is_err() {
+            return query_error(
+                "permission must be 'read' or 'write', 'read_and_write', or 'admin'",
+            );
+        }
+
+        match self
+            .interface
+            .create_user(username, password, permission.unwrap())
+        {
+            Ok(_) => Ok(Response {
+                body: None,
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// `ast_user_delete` is a function that takes a `Option<Vec<ASTNode>>` and returns a `Result<Response,
+    /// Status>`
+    ///
+    /// Arguments:
+    ///
+    /// * `expr`: The expression that was passed to the command.
+    ///
+    /// Returns:
+    ///
+    /// A `Result` type.
+    fn ast_user_delete(&mut self, expr: Option<Vec<ASTNode>>) -> Result<Response, Error> {
+        let user = if let Some(expr) = expr {
+            match expr[0] {
+                ASTNode::Identifier(ref ident) => ident.clone(),
+                _ => {
+                    return query_error("user delete must have an expression");
+                }
+            }
+        } else {
+            return query_error("user delete must have an expression");
+        };
+
+        match self.interface.delete_user(user) {
+            Ok(_) => Ok(Response {
+                body: None,
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// It takes a vector of ASTNodes, and if the vector is not empty, it will iterate through the
+    /// vector, and if the ASTNode is an AssignmentExpression, it will check if the ident is "password"
+    /// or "permission", and if it is, it will check if the value is a Bson, and if it is, it will check
+    /// if the Bson is a string, and if it is, it will set the password or permission to the string
+    ///
+    /// Arguments:
+    ///
+    /// * `expr`: The ASTNode that represents the expression.
+    ///
+    /// Returns:
+    ///
+    /// A response object.
+    fn ast_user_update(&mut self, expr: Option<Vec<ASTNode>>) -> Result<Response, Error> {
+        if expr.is_none() {
+            return query_error("user update must have an expression");
+        }
+
+        let mut password: Option<String> = None;
+        let mut permission: Option<String> = None;
+        let mut username = String::new();
+
+        for node in expr.unwrap() {
+            match node {
+                // this will find the password and permission
+                ASTNode::AssignmentExpression { ident, value } => {
+                    match ident.as_str() {
+                        "password" => {
+                            password = match *value {
+                                ASTNode::Bson(s) => {
+                                    let s = s.as_str();
+
+                                    // if the password is not a string, return an error
+                                    if let Some(s) = s {
+                                        Some(s.to_string())
+                                    } else {
+                                        return query_error("password must be a string");
+                                    }
+                                }
+                                _ => None,
+                            }
+                        }
+
+                        "permission" => {
+                            permission = match *value {
+                                ASTNode::Bson(s) => {
+                                    let s = s.as_str();
+
+                                    // if the password is not a string, return an error
+                                    if let Some(s) = s {
+                                        Some(s.to_string())
+                                    } else {
+                                        return query_error("permission must be a string");
+                                    }
+                                }
+                                _ => None,
+                            }
+                        }
+
+                        _ => {}
+                    }
+                }
+
+                ASTNode::Identifier(ref ident) => username = ident.clone(),
+
+                _ => {}
+            }
+        }
+
+        let permission = if let Some(permission) = permission {
+            let permission = UserPermission::from_str(permission.as_str());
+            if permission.is_err() {
+                return query_error(
+                    "permission must be 'read' or 'write', 'read_and_write', or 'admin'",
+                );
+            };
+
+            Some(permission.unwrap())
+        } else {
+            None
+        };
+
+        match self.interface.update_user(username, password, permission) {
+            Ok(_) => Ok(Response {
+                body: None,
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// `if the user provided a database name, use it, otherwise use the current database`
+    ///
+    /// Arguments:
+    ///
+    /// * `expr`: The expression that was passed to the function.
+    ///
+    /// Returns:
+    ///
+    /// A response object.
+    fn ast_database_delete(&mut self, expr: Option<Vec<ASTNode>>) -> Result<Response, Error> {
+        let database = if let Some(expr) = expr {
+            match expr[0] {
+                ASTNode::Identifier(ref ident) => ident.clone(),
+                _ => {
+                    unreachable!()
+                }
+            }
+        } else {
+            self.interface.current_database.clone()
+        };
+
+        match self.interface.delete_database(database) {
+            Ok(_) => Ok(Response {
+                body: None,
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// `ast_transfer` runs a `rename`, `copy` or `move` statement
+    ///
+    /// Arguments:
+    ///
+    /// * `ident`: The identifier of the key to transfer.
+    /// * `database`: The identifier of the target database, the current one if `None`.
+    /// * `to`: The identifier of the target key, the same key if `None`.
+    /// * `keep`: Whether the source key is kept.
+    ///
+    /// Returns:
+    ///
+    /// An empty response.
+    fn ast_transfer(
+        &mut self,
+        ident: ASTNode,
+        database: Option<ASTNode>,
+        to: Option<ASTNode>,
+        keep: bool,
+    ) -> Result<Response, Error> {
+        let key = match ident {
+            ASTNode::Identifier(key) => key,
+            _ => return query_error("Invalid key"),
+        };
+
+        let database = match database {
+            Some(ASTNode::Identifier(database)) => database,
+            Some(_) => return query_error("Invalid database name"),
+            None => self.interface.current_database.clone(),
+        };
+
+        let new_key = match to {
+            Some(ASTNode::Identifier(new_key)) => new_key,
+            Some(_) => return query_error("Invalid key"),
+            None => key.clone(),
+        };
+
+        match self
+            .interface
+            .transfer_dustdata(key, database, new_key, keep)
+        {
+            Ok(_) => Ok(Response {
+                body: None,
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// `ast_begin` opens a transaction, the writes that follow are queued until `commit`
+    ///
+    /// Returns:
+    ///
+    /// An empty response.
+    fn ast_begin(&mut self) -> Result<Response, Error> {
+        if self.session.transaction.is_some() {
+            return query_error("a transaction is already open, commit or rollback it first");
+        }
+
+        self.session.transaction = Some(Transaction::default());
+
+        Ok(Response {
+            body: None,
+            header: ResHeader {
+                is_error: false,
+                messages: None,
+                status: Status::Ok,
+                diagnostic: None,
+            },
+        })
+    }
+
+    /// `ast_queue` adds a statement to the open transaction
+    ///
+    /// The keys the statement names are read the first time they are named, so `commit` can
+    /// detect that another connection wrote them in the meantime.
+    ///
+    /// Arguments:
+    ///
+    /// * `ast`: The statement to run on `commit`.
     ///
     /// Returns:
     ///
-    /// A response object.
-    fn ast_into_update(&mut self, value: ASTNode, expr: ASTNode) -> Result<Response, Error> {
-        let key = match expr {
-            ASTNode::Identifier(ident) => ident,
-            _ => return query_error("key must be an identifier"),
+    /// An empty response whose status is `Queued`.
+    fn ast_queue(&mut self, ast: ASTNode) -> Result<Response, Error> {
+        if let Err(e) = self.snapshot_keys(&ast) {
+            return self.dd_error(e);
+        }
+
+        let database = self.interface.current_database.clone();
+        let transaction = self.session.transaction.as_mut().unwrap();
+        transaction.statements.push((database, ast));
+
+        Ok(Response {
+            body: None,
+            header: ResHeader {
+                is_error: false,
+                messages: None,
+                status: Status::Queued,
+                diagnostic: None,
+            },
+        })
+    }
+
+    /// `ast_read` runs a statement that reads the keys it names in the open transaction
+    ///
+    /// The statement runs right away, before the queued statements, and the keys it reads are
+    /// checked by `commit` like the keys of the queued statements.
+    ///
+    /// Arguments:
+    ///
+    /// * `ast`: The statement.
+    ///
+    /// Returns:
+    ///
+    /// The response of the statement.
+    fn ast_read(&mut self, ast: ASTNode) -> Result<Response, Error> {
+        // the keys are read before the statement, a write in between makes the commit conflict
+        if let Err(e) = self.snapshot_keys(&ast) {
+            return self.dd_error(e);
+        }
+
+        self.dispatch(ast)
+    }
+
+    // reads the keys a statement names the first time the open transaction names them
+    fn snapshot_keys(&mut self, ast: &ASTNode) -> Result<(), TransactionError> {
+        let database = self.interface.current_database.clone();
+
+        for (database, key) in transaction::touched_keys(ast, &database) {
+            let named = self
+                .session
+                .transaction
+                .as_ref()
+                .unwrap()
+                .snapshots
+                .contains_key(&(database.clone(), key.clone()));
+
+            if named {
+                continue;
+            }
+
+            let snapshot = self.interface.snapshot_dustdata(&database, &key)?;
+
+            let transaction = self.session.transaction.as_mut().unwrap();
+            transaction.snapshots.insert((database, key), snapshot);
+        }
+
+        Ok(())
+    }
+
+    /// `ast_commit` runs the statements of the open transaction as one
+    ///
+    /// No other statement runs until they are done. Nothing is written if a key they name was
+    /// written by another connection since the transaction first named it, or if one of them fails,
+    /// in which case the keys they name are put back as they were.
+    ///
+    /// Returns:
+    ///
+    /// A response whose body is an array with one response document per statement, a `Conflict`
+    /// error, or the error of the failing statement.
+    fn ast_commit(&mut self) -> Result<Response, Error> {
+        let transaction = match self.session.transaction.take() {
+            Some(transaction) => transaction,
+            None => return query_error("no transaction is open, open one with `begin`"),
         };
 
-        let value = match value {
-            ASTNode::Bson(json) => json,
-            _ => return query_error("value must be a json object"),
+        let gate = self.gate.clone();
+        let _committing = gate.write().unwrap();
+
+        for ((database, key), snapshot) in &transaction.snapshots {
+            let current = match self.interface.snapshot_dustdata(database, key) {
+                Ok(current) => current,
+                Err(e) => return self.dd_error(e),
+            };
+
+            if current != *snapshot {
+                let error = Error {
+                    message: format!(
+                        "key {} of {} was written since the transaction read it, nothing was written",
+                        key, database
+                    ),
+                    diagnostic: None,
+                    status: Status::Conflict,
+                };
+
+                return Err(error);
+            }
+        }
+
+        // the keys are journaled before the first write, so a crash before the writes are
+        // flushed leaves nothing of the transaction once the server starts again
+        if let Err(e) = self.interface.journal_commit(&transaction.snapshots) {
+            return self.dd_error(e);
+        }
+
+        let current_database = self.interface.current_database.clone();
+        let mut results = Vec::with_capacity(transaction.statements.len());
+        let mut failure = None;
+
+        self.interface.hold_changes();
+
+        for (i, (database, ast)) in transaction.statements.into_iter().enumerate() {
+            self.interface.current_database = database;
+
+            match self.dispatch(ast) {
+                Ok(response) => results.push(bson::to_bson(&response).unwrap()),
+                Err(error) => {
+                    failure = Some((i, error));
+                    break;
+                }
+            }
+        }
+
+        self.interface.current_database = current_database;
+        self.interface.release_changes(failure.is_none());
+
+        if let Some((i, error)) = failure {
+            for ((database, key), snapshot) in &transaction.snapshots {
+                let snapshot = snapshot.clone();
+
+                if let Err(e) = self.interface.restore_dustdata(database, key, snapshot) {
+                    return self.dd_error(e);
+                }
+            }
+
+            if let Err(e) = self.interface.settle_commit(&transaction.snapshots) {
+                return self.dd_error(e);
+            }
+
+            let error = Error {
+                message: format!(
+                    "statement {} of the transaction failed, nothing was written: {}",
+                    i + 1,
+                    error.message
+                ),
+                diagnostic: None,
+                status: error.status,
+            };
+
+            return Err(error);
+        }
+
+        if let Err(e) = self.interface.settle_commit(&transaction.snapshots) {
+            return self.dd_error(e);
+        }
+
+        Ok(Response {
+            body: Some(Bson::Array(results)),
+            header: ResHeader {
+                is_error: false,
+                messages: None,
+                status: Status::Ok,
+                diagnostic: None,
+            },
+        })
+    }
+
+    /// `ast_rollback` discards the open transaction and its queued statements
+    ///
+    /// Returns:
+    ///
+    /// An empty response.
+    fn ast_rollback(&mut self) -> Result<Response, Error> {
+        if self.session.transaction.take().is_none() {
+            return query_error("no transaction is open, open one with `begin`");
+        }
+
+        Ok(Response {
+            body: None,
+            header: ResHeader {
+                is_error: false,
+                messages: None,
+                status: Status::Ok,
+                diagnostic: None,
+            },
+        })
+    }
+
+    /// `ast_expire` runs an `expire` or `persist` statement
+    ///
+    /// Arguments:
+    ///
+    /// * `ident`: The identifier of the key.
+    /// * `seconds`: The seconds after which the key expires, `None` to make it never expire.
+    ///
+    /// Returns:
+    ///
+    /// A response whose status is `Skipped` if `persist` found no expiry to remove.
+    fn ast_expire(&mut self, ident: ASTNode, seconds: Option<u64>) -> Result<Response, Error> {
+        let key = match ident {
+            ASTNode::Identifier(key) => key,
+            _ => return query_error("Invalid key"),
         };
 
-        match self.interface.update_dustdata(key, value) {
-            Ok(_) => Ok(Response {
+        let persist = seconds.is_none();
+
+        match self.interface.expire_dustdata(key, seconds.map(expires_in)) {
+            Ok(had_expiry) => Ok(Response {
                 body: None,
                 header: ResHeader {
                     is_error: false,
                     messages: None,
-                    status: Status::Ok,
+                    status: if persist && !had_expiry {
+                        Status::Skipped
+                    } else {
+                        Status::Ok
+                    },
+                    diagnostic: None,
                 },
             }),
 
@@ -286,101 +1902,251 @@ impl Core {
         }
     }
 
-    /// It takes a `Vec<ASTNode>` and returns a `Result<Response, Error>`
+    /// `ast_ttl` runs a `ttl` statement
     ///
     /// Arguments:
     ///
-    /// * `expr`: Option<Vec<ASTNode>>
+    /// * `ident`: The identifier of the key.
     ///
     /// Returns:
     ///
-    /// A response object
-    fn ast_user_insert(&mut self, expr: Option<Vec<ASTNode>>) -> Result<Response, Error> {
-        if expr.is_none() {
-            return query_error("user insert must have an expression");
-        }
+    /// A response whose body is the number of seconds before the key expires, rounded up, or null
+    /// if it never expires.
+    fn ast_ttl(&mut self, ident: ASTNode) -> Result<Response, Error> {
+        let key = match ident {
+            ASTNode::Identifier(key) => key,
+            _ => return query_error("Invalid key"),
+        };
 
-        let expr = expr.unwrap();
+        match self.interface.ttl_dustdata(key) {
+            Ok(at) => {
+                let body = match at {
+                    Some(at) => {
+                        let millis = at.timestamp_millis() - DateTime::now().timestamp_millis();
 
-        let mut username = String::new();
-        let mut permission = String::new();
-        let mut password = String::new();
+                        Bson::Int64((millis.max(0) + 999) / 1000)
+                    }
+                    None => Bson::Null,
+                };
 
-        // idk if this is the best way to do this
-        for node in expr {
-            match node {
-                // this will find the password and permission
-                ASTNode::AssignmentExpression { ident, value } => {
-                    match ident.as_str() {
-                        "password" => {
-                            password = match *value {
-                                ASTNode::Bson(s) => {
-                                    let s = s.as_str();
+                Ok(Response {
+                    body: Some(body),
+                    header: ResHeader {
+                        is_error: false,
+                        messages: None,
+                        status: Status::Ok,
+                        diagnostic: None,
+                    },
+                })
+            }
 
-                                    // if the password is not a string, return an error
-                                    if let Some(s) = s {
-                                        s.to_string()
-                                    } else {
-                                        return query_error("password must be a string");
-                                    }
-                                }
+            Err(e) => self.dd_error(e),
+        }
+    }
 
-                                _ => {
-                                    return query_error("password must be a string");
-                                }
-                            }
-                        }
+    /// `ast_watch` starts pushing the changes of keys to the connection, in `Event` frames whose
+    /// body has the `watch` id, the `seq` number of the change, its `operation`, the `database`,
+    /// the `key` and the new `value`
+    ///
+    /// Arguments:
+    ///
+    /// * `scope`: The keys to push the changes of.
+    /// * `after`: The last change seen before a reconnect, the changes after it are pushed first.
+    ///
+    /// Returns:
+    ///
+    /// A response whose body has the `watch` id and the `seq` number of the last change.
+    fn ast_watch(&mut self, scope: WatchScope, after: Option<u64>) -> Result<Response, Error> {
+        let mailbox = self.session.mailbox.clone();
+
+        match self.interface.watch_changes(scope, after, &mailbox) {
+            Ok((watch, seq)) => {
+                let body = bson::doc! {
+                    "watch": watch as i64,
+                    "seq": seq as i64,
+                };
 
-                        "permission" => {
-                            permission = match *value {
-                                ASTNode::Bson(s) => {
-                                    let s = s.as_str();
+                Ok(Response {
+                    body: Some(Bson::Document(body)),
+                    header: ResHeader {
+                        is_error: false,
+                        messages: None,
+                        status: Status::Ok,
+                        diagnostic: None,
+                    },
+                })
+            }
 
-                                    // if the permission is not a string, return an error
-                                    if let Some(s) = s {
-                                        s.to_string()
-                                    } else {
-                                        return query_error("permission must be a string");
-                                    }
-                                }
-                                _ => {
-                                    return query_error("permission must be a string");
-                                }
-                            }
-                        }
+            Err(e) => self.dd_error(e),
+        }
+    }
 
-                        _ => {}
-                    }
-                }
+    /// `ast_unwatch` stops pushing changes to the connection
+    ///
+    /// Arguments:
+    ///
+    /// * `watch`: The id of the watch to stop, `None` for every watch of the connection.
+    ///
+    /// Returns:
+    ///
+    /// A response whose body is the number of stopped watches.
+    fn ast_unwatch(&mut self, watch: Option<u64>) -> Result<Response, Error> {
+        let mailbox = self.session.mailbox.clone();
 
-                ASTNode::Identifier(ref ident) => username = ident.clone(),
+        match self.interface.unwatch_changes(&mailbox, watch) {
+            Ok(stopped) => Ok(Response {
+                body: Some(Bson::Int64(stopped as i64)),
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
 
-                _ => {}
-            }
+            Err(e) => self.dd_error(e),
         }
+    }
 
-        if username.is_empty() || password.is_empty() || permission.is_empty() {
-            return query_error("username, password, and permission are required");
-        }
+    /// `ast_publish` publishes a message on a channel, pushed to the subscriptions of the
+    /// connections in `Message` frames whose body has the `subscription` id, the `channel`, the
+    /// `message` and the number of messages the subscription `dropped` before this one
+    ///
+    /// Arguments:
+    ///
+    /// * `channel`: The channel.
+    /// * `message`: The message, any value.
+    ///
+    /// Returns:
+    ///
+    /// A response whose body is the number of subscriptions the message was pushed to.
+    fn ast_publish(&mut self, channel: String, message: ASTNode) -> Result<Response, Error> {
+        let message = match message {
+            ASTNode::Bson(message) => message,
+            _ => return query_error("Invalid message"),
+        };
 
-        let permission = UserPermission::from_str(permission.as_str());
+        match self.interface.publish_message(&channel, &message) {
+            Ok(pushed) => Ok(Response {
+                body: Some(Bson::Int64(pushed as i64)),
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
 
-        if permission.is_err() {
-            return query_error(
-                "permission must be 'read' or 'write', 'read_and_write', or 'admin'",
-            );
+            Err(e) => self.dd_error(e),
         }
+    }
+
+    /// `ast_subscribe` starts pushing the messages published on channels to the connection
+    ///
+    /// Arguments:
+    ///
+    /// * `pattern`: The glob the channels match, e.g. `orders.*`.
+    /// * `buffer`: The messages kept while the connection is busy, the default if `None`.
+    /// * `drop`: The messages dropped once the buffer is full.
+    ///
+    /// Returns:
+    ///
+    /// A response whose body has the `subscription` id.
+    fn ast_subscribe(
+        &mut self,
+        pattern: String,
+        buffer: Option<usize>,
+        drop: DropPolicy,
+    ) -> Result<Response, Error> {
+        let mailbox = self.session.mailbox.clone();
 
         match self
             .interface
-            .create_user(username, password, permission.unwrap())
+            .subscribe_channel(pattern, buffer, drop, &mailbox)
         {
+            Ok(subscription) => Ok(Response {
+                body: Some(Bson::Document(bson::doc! {
+                    "subscription": subscription as i64,
+                })),
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// `ast_unsubscribe` stops pushing messages to the connection
+    ///
+    /// Arguments:
+    ///
+    /// * `subscription`: The id of the subscription to stop, `None` for every subscription of
+    ///   the connection.
+    ///
+    /// Returns:
+    ///
+    /// A response whose body is the number of stopped subscriptions.
+    fn ast_unsubscribe(&mut self, subscription: Option<u64>) -> Result<Response, Error> {
+        let mailbox = self.session.mailbox.clone();
+
+        match self.interface.unsubscribe_channel(&mailbox, subscription) {
+            Ok(stopped) => Ok(Response {
+                body: Some(Bson::Int64(stopped as i64)),
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// `ast_database_create` creates a database with the given options
+    ///
+    /// Arguments:
+    ///
+    /// * `ident`: The identifier of the database.
+    /// * `options`: The options document, e.g. `{"read_only": true}`, the defaults if `None`.
+    ///
+    /// Returns:
+    ///
+    /// An empty response.
+    fn ast_database_create(
+        &mut self,
+        ident: ASTNode,
+        options: Option<ASTNode>,
+    ) -> Result<Response, Error> {
+        let database = match ident {
+            ASTNode::Identifier(database) => database,
+            _ => return query_error("Invalid database name"),
+        };
+
+        let options = match options {
+            Some(ASTNode::Bson(Bson::Document(options))) => {
+                match DatabaseOptions::from_document(options) {
+                    Ok(options) => options,
+                    Err(message) => return query_error(&message),
+                }
+            }
+            Some(_) => return query_error("database options must be an object"),
+            None => DatabaseOptions::default(),
+        };
+
+        match self.interface.create_database(database, options) {
             Ok(_) => Ok(Response {
                 body: None,
                 header: ResHeader {
                     is_error: false,
                     messages: None,
                     status: Status::Ok,
+                    diagnostic: None,
                 },
             }),
 
@@ -388,35 +2154,38 @@ impl Core {
         }
     }
 
-    /// `ast_user_delete` is a function that takes a `Option<Vec<ASTNode>>` and returns a `Result<Response,
-    /// Status>`
+    /// `ast_schema_create` validates the values written to a database against a schema
     ///
     /// Arguments:
     ///
-    /// * `expr`: The expression that was passed to the command.
+    /// * `database`: The identifier of the database.
+    /// * `schema`: The schema document, e.g. `{"type": "object", "required": ["name"]}`.
     ///
     /// Returns:
     ///
-    /// A `Result` type.
-    fn ast_user_delete(&mut self, expr: Option<Vec<ASTNode>>) -> Result<Response, Error> {
-        let user = if let Some(expr) = expr {
-            match expr[0] {
-                ASTNode::Identifier(ref ident) => ident.clone(),
-                _ => {
-                    return query_error("user delete must have an expression");
-                }
-            }
-        } else {
-            return query_error("user delete must have an expression");
+    /// An empty response.
+    fn ast_schema_create(&mut self, database: ASTNode, schema: ASTNode) -> Result<Response, Error> {
+        let database = match database {
+            ASTNode::Identifier(database) => database,
+            _ => return query_error("Invalid database name"),
         };
 
-        match self.interface.delete_user(user) {
+        let schema = match schema {
+            ASTNode::Bson(Bson::Document(schema)) => match Schema::from_document(schema) {
+                Ok(schema) => schema,
+                Err(message) => return query_error(&message),
+            },
+            _ => return query_error("schema must be an object"),
+        };
+
+        match self.interface.create_schema(database, schema) {
             Ok(_) => Ok(Response {
                 body: None,
                 header: ResHeader {
                     is_error: false,
                     messages: None,
                     status: Status::Ok,
+                    diagnostic: None,
                 },
             }),
 
@@ -424,94 +2193,29 @@ impl Core {
         }
     }
 
-    /// It takes a vector of ASTNodes, and if the vector is not empty, it will iterate through the
-    /// vector, and if the ASTNode is an AssignmentExpression, it will check if the ident is "password"
-    /// or "permission", and if it is, it will check if the value is a Bson, and if it is, it will check
-    /// if the Bson is a string, and if it is, it will set the password or permission to the string
+    /// `ast_schema_delete` stops validating the values written to a database
     ///
     /// Arguments:
     ///
-    /// * `expr`: The ASTNode that represents the expression.
+    /// * `database`: The identifier of the database.
     ///
     /// Returns:
     ///
-    /// A response object.
-    fn ast_user_update(&mut self, expr: Option<Vec<ASTNode>>) -> Result<Response, Error> {
-        if expr.is_none() {
-            return query_error("user update must have an expression");
-        }
-
-        let mut password: Option<String> = None;
-        let mut permission: Option<String> = None;
-        let mut username = String::new();
-
-        for node in expr.unwrap() {
-            match node {
-                // this will find the password and permission
-                ASTNode::AssignmentExpression { ident, value } => {
-                    match ident.as_str() {
-                        "password" => {
-                            password = match *value {
-                                ASTNode::Bson(s) => {
-                                    let s = s.as_str();
-
-                                    // if the password is not a string, return an error
-                                    if let Some(s) = s {
-                                        Some(s.to_string())
-                                    } else {
-                                        return query_error("password must be a string");
-                                    }
-                                }
-                                _ => None,
-                            }
-                        }
-
-                        "permission" => {
-                            permission = match *value {
-                                ASTNode::Bson(s) => {
-                                    let s = s.as_str();
-
-                                    // if the password is not a string, return an error
-                                    if let Some(s) = s {
-                                        Some(s.to_string())
-                                    } else {
-                                        return query_error("permission must be a string");
-                                    }
-                                }
-                                _ => None,
-                            }
-                        }
-
-                        _ => {}
-                    }
-                }
-
-                ASTNode::Identifier(ref ident) => username = ident.clone(),
-
-                _ => {}
-            }
-        }
-
-        let permission = if let Some(permission) = permission {
-            let permission = UserPermission::from_str(permission.as_str());
-            if permission.is_err() {
-                return query_error(
-                    "permission must be 'read' or 'write', 'read_and_write', or 'admin'",
-                );
-            };
-
-            Some(permission.unwrap())
-        } else {
-            None
+    /// An empty response.
+    fn ast_schema_delete(&mut self, database: ASTNode) -> Result<Response, Error> {
+        let database = match database {
+            ASTNode::Identifier(database) => database,
+            _ => return query_error("Invalid database name"),
         };
 
-        match self.interface.update_user(username, password, permission) {
+        match self.interface.delete_schema(database) {
             Ok(_) => Ok(Response {
                 body: None,
                 header: ResHeader {
                     is_error: false,
                     messages: None,
                     status: Status::Ok,
+                    diagnostic: None,
                 },
             }),
 
@@ -519,34 +2223,42 @@ impl Core {
         }
     }
 
-    /// `if the user provided a database name, use it, otherwise use the current database`
+    /// `ast_index` creates, drops or rebuilds the index of a field of a database
     ///
     /// Arguments:
     ///
-    /// * `expr`: The expression that was passed to the function.
+    /// * `action`: What to do with the index.
+    /// * `database`: The identifier of the database.
+    /// * `field`: The indexed field.
     ///
     /// Returns:
     ///
-    /// A response object.
-    fn ast_database_delete(&mut self, expr: Option<Vec<ASTNode>>) -> Result<Response, Error> {
-        let database = if let Some(expr) = expr {
-            match expr[0] {
-                ASTNode::Identifier(ref ident) => ident.clone(),
-                _ => {
-                    unreachable!()
-                }
-            }
-        } else {
-            self.interface.current_database.clone()
+    /// An empty response.
+    fn ast_index(
+        &mut self,
+        action: IndexAction,
+        database: ASTNode,
+        field: Vec<PathSegment>,
+    ) -> Result<Response, Error> {
+        let database = match database {
+            ASTNode::Identifier(database) => database,
+            _ => return query_error("Invalid database name"),
         };
 
-        match self.interface.delete_database(database) {
+        let result = match action {
+            IndexAction::Create { unique } => self.interface.create_index(database, field, unique),
+            IndexAction::Drop => self.interface.drop_index(database, field),
+            IndexAction::Rebuild => self.interface.rebuild_index(database, field),
+        };
+
+        match result {
             Ok(_) => Ok(Response {
                 body: None,
                 header: ResHeader {
                     is_error: false,
                     messages: None,
                     status: Status::Ok,
+                    diagnostic: None,
                 },
             }),
 
@@ -554,6 +2266,73 @@ impl Core {
         }
     }
 
+    /// `ast_database_list` lists the databases of the server
+    ///
+    /// Returns:
+    ///
+    /// A response whose body is an array of `{name, keys, size, indexes}` documents, `size` being in
+    /// bytes.
+    fn ast_database_list(&mut self) -> Result<Response, Error> {
+        let databases = match self.interface.list_databases() {
+            Ok(databases) => databases,
+            Err(e) => return self.dd_error(e),
+        };
+
+        let databases = databases
+            .into_iter()
+            .map(|database| {
+                Bson::Document(bson::doc! {
+                    "name": database.name,
+                    "keys": database.keys as i64,
+                    "size": database.size as i64,
+                    "indexes": database.indexes,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Ok(Response {
+            body: Some(Bson::Array(databases)),
+            header: ResHeader {
+                is_error: false,
+                messages: None,
+                status: Status::Ok,
+                diagnostic: None,
+            },
+        })
+    }
+
+    /// `ast_user_list` lists the users of the server
+    ///
+    /// Returns:
+    ///
+    /// A response whose body is an array of `{name, permission}` documents.
+    fn ast_user_list(&mut self) -> Result<Response, Error> {
+        let users = match self.interface.list_users() {
+            Ok(users) => users,
+            Err(e) => return self.dd_error(e),
+        };
+
+        let users = users
+            .into_iter()
+            .map(|(name, permission)| {
+                Bson::Document(bson::doc! {
+                    "name": name,
+                    "permission": bson::to_bson(&permission).unwrap(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Ok(Response {
+            body: Some(Bson::Array(users)),
+            header: ResHeader {
+                is_error: false,
+                messages: None,
+                status: Status::Ok,
+                diagnostic: None,
+            },
+        })
+    }
+
     /// It gets a value from the database.
     ///
     /// Arguments:
@@ -568,20 +2347,133 @@ impl Core {
             return query_error("get must have an expression");
         }
 
-        let key = match *ident.unwrap() {
-            ASTNode::Identifier(ident) => ident,
+        let (key, path, projection) = match *ident.unwrap() {
+            ASTNode::Identifier(ident) => (ident, Vec::new(), None),
+            ASTNode::PathExpression {
+                ident,
+                path,
+                projection,
+            } => (ident, path, projection),
             _ => {
                 unreachable!()
             }
         };
 
-        match self.interface.get_from_dustdata(key) {
-            Ok(value) => Ok(Response {
-                body: Some(value),
+        let value = match self.interface.get_from_dustdata(key) {
+            Ok(value) => value,
+            Err(e) => return self.dd_error(e),
+        };
+
+        let value = match document::resolve_path(&value, &path) {
+            Some(value) => value,
+            None => {
+                return Err(Error {
+                    message: format!("path {} not found", path_to_string(&path)),
+                    diagnostic: None,
+                    status: Status::NotFound,
+                })
+            }
+        };
+
+        let value = match projection {
+            Some(projection) => Bson::Document(document::project(value, &projection)),
+            None => value.clone(),
+        };
+
+        Ok(Response {
+            body: Some(value),
+            header: ResHeader {
+                is_error: false,
+                messages: None,
+                status: Status::Ok,
+                diagnostic: None,
+            },
+        })
+    }
+
+    /// It gets several values from the database. Missing keys don't fail the query.
+    ///
+    /// Arguments:
+    ///
+    /// * `keys`: The keys to get.
+    ///
+    /// Returns:
+    ///
+    /// A response whose body is a document of the values found, keyed by their key. Missing keys
+    /// are listed in the messages.
+    fn ast_multi_get(&mut self, keys: Vec<String>) -> Result<Response, Error> {
+        match self.interface.get_many_from_dustdata(keys) {
+            Ok((values, missing)) => Ok(Response {
+                body: Some(Bson::Document(values)),
+                header: ResHeader {
+                    is_error: false,
+                    messages: missing_keys(missing),
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// It gets every value of the current database matching a condition.
+    ///
+    /// Arguments:
+    ///
+    /// * `condition`: The condition the values must match.
+    ///
+    /// Returns:
+    ///
+    /// A response whose body is a document of the matching values, keyed by their key.
+    fn ast_where_get(&mut self, condition: Condition) -> Result<Response, Error> {
+        match self.interface.find_in_dustdata(&condition) {
+            Ok(documents) => Ok(Response {
+                body: Some(Bson::Document(documents)),
+                header: ResHeader {
+                    is_error: false,
+                    messages: None,
+                    status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// It computes an aggregate over the values of the current database.
+    ///
+    /// Arguments:
+    ///
+    /// * `function`: The aggregate to compute.
+    /// * `field`: The field to aggregate, the whole value if `None`.
+    /// * `condition`: The condition the values must match, if any.
+    /// * `group_by`: The field to group the values by, if any.
+    ///
+    /// Returns:
+    ///
+    /// A response whose body is the result of the aggregation.
+    fn ast_aggregate(
+        &mut self,
+        function: Aggregate,
+        field: Option<Vec<PathSegment>>,
+        condition: Option<Condition>,
+        group_by: Option<Vec<PathSegment>>,
+    ) -> Result<Response, Error> {
+        let aggregation = Aggregation::new(function, field, group_by);
+
+        match self
+            .interface
+            .aggregate_dustdata(aggregation, condition.as_ref())
+        {
+            Ok(result) => Ok(Response {
+                body: Some(Bson::Document(result)),
                 header: ResHeader {
                     is_error: false,
                     messages: None,
                     status: Status::Ok,
+                    diagnostic: None,
                 },
             }),
 
@@ -599,11 +2491,13 @@ impl Core {
     ///
     /// A response object.
     fn ast_sgl_delete(&mut self, ident: Option<Box<ASTNode>>) -> Result<Response, Error> {
+        if ident.is_none() {
+            return query_error("delete must have an expression");
+        }
+
         let key = match *ident.unwrap() {
             ASTNode::Identifier(ident) => ident,
-            _ => {
-                unreachable!()
-            }
+            _ => return query_error("delete only accepts a key"),
         };
 
         match self.interface.delete_from_dustdata(key) {
@@ -613,6 +2507,32 @@ impl Core {
                     is_error: false,
                     messages: None,
                     status: Status::Ok,
+                    diagnostic: None,
+                },
+            }),
+
+            Err(e) => self.dd_error(e),
+        }
+    }
+
+    /// It deletes several keys from the database. Missing keys don't fail the query.
+    ///
+    /// Arguments:
+    ///
+    /// * `keys`: The keys to delete.
+    ///
+    /// Returns:
+    ///
+    /// A response object. Missing keys are listed in the messages.
+    fn ast_multi_delete(&mut self, keys: Vec<String>) -> Result<Response, Error> {
+        match self.interface.delete_many_from_dustdata(keys) {
+            Ok(missing) => Ok(Response {
+                body: None,
+                header: ResHeader {
+                    is_error: false,
+                    messages: missing_keys(missing),
+                    status: Status::Ok,
+                    diagnostic: None,
                 },
             }),
 
@@ -620,6 +2540,70 @@ impl Core {
         }
     }
 
+    /// It lists the keys of the database in sorted order, one page at a time
+    ///
+    /// Arguments:
+    ///
+    /// * `options`: The prefix, range, limit and cursor of the scan.
+    ///
+    /// Returns:
+    ///
+    /// A response whose body has the `keys` (or the `values` keyed by their key, with `with
+    /// values`) and a `cursor` to pass to the next `list` to get the next page, `null` on the last
+    /// page.
+    fn ast_list_scan(&mut self, mut options: ListOptions) -> Result<Response, Error> {
+        if let Some(cursor) = options.cursor.take() {
+            if options.after.is_some() {
+                return query_error("list can't have both after and cursor");
+            }
+
+            // the cursor is the last key of the previous page
+            let after = utils::from_hex(&cursor).and_then(|key| String::from_utf8(key).ok());
+
+            match after {
+                Some(after) => options.after = Some(after),
+                None => return query_error("invalid cursor"),
+            }
+        }
+
+        let (entries, more) = match self.interface.scan_dustdata(&options) {
+            Ok(scan) => scan,
+            Err(e) => return self.dd_error(e),
+        };
+
+        let cursor = match entries.last() {
+            Some((key, _)) if more => Bson::String(utils::to_hex(key.as_bytes())),
+            _ => Bson::Null,
+        };
+
+        let mut body = Document::new();
+
+        if options.with_values {
+            let values = entries
+                .into_iter()
+                .map(|(key, value)| (key, value.unwrap_or(Bson::Null)))
+                .collect::<Document>();
+
+            body.insert("values", values);
+        } else {
+            let keys = entries.into_iter().map(|(key, _)| Bson::String(key));
+
+            body.insert("keys", keys.collect::<Vec<_>>());
+        }
+
+        body.insert("cursor", cursor);
+
+        Ok(Response {
+            body: Some(Bson::Document(body)),
+            header: ResHeader {
+                is_error: false,
+                messages: None,
+                status: Status::Ok,
+                diagnostic: None,
+            },
+        })
+    }
+
     /// `fn ast_sgl_list(&self, ident: Option<Box<ASTNode>>) -> Result<Response, Status>`
     ///
     /// The function name is `ast_sgl_list` and it takes two arguments: `&self` and `ident:
@@ -640,6 +2624,7 @@ impl Core {
                     is_error: false,
                     messages: None,
                     status: Status::Ok,
+                    diagnostic: None,
                 },
             }),
 
@@ -656,13 +2641,13 @@ impl Core {
                 Err(Error {
                     message: code.1,
                     status: code.0,
-                    query_message: None,
+                    diagnostic: None,
                 })
             }
             TransactionError::ExternalError(e, message) => Err(Error {
                 message,
                 status: e,
-                query_message: None,
+                diagnostic: None,
             }),
         }
     }
@@ -676,10 +2661,95 @@ fn parse_dd_error_code(code: dustdata::ErrorCode) -> (Status, String) {
     }
 }
 
+fn expires_in(seconds: u64) -> DateTime {
+    let millis = (seconds as i64).saturating_mul(1000);
+
+    DateTime::from_millis(DateTime::now().timestamp_millis().saturating_add(millis))
+}
+
 fn query_error(msg: &str) -> Result<Response, Error> {
     Err(Error {
         message: msg.to_string(),
         status: Status::InvalidQuery,
-        query_message: None,
+        diagnostic: None,
     })
 }
+
+fn missing_keys(keys: Vec<String>) -> Option<Vec<String>> {
+    if keys.is_empty() {
+        return None;
+    }
+
+    Some(
+        keys.into_iter()
+            .map(|key| format!("key {} not found", key))
+            .collect(),
+    )
+}
+
+// statements that touch no data run as soon as they are sent, even in a transaction
+fn runs_in_transaction(ast: &ASTNode) -> bool {
+    matches!(
+        ast,
+        ASTNode::ExplainExpression { .. }
+            | ASTNode::UseExpression { .. }
+            | ASTNode::WhoamiExpression
+            | ASTNode::AssignmentExpression { .. }
+            | ASTNode::WatchExpression { .. }
+            | ASTNode::UnwatchExpression { .. }
+            | ASTNode::SubscribeExpression { .. }
+            | ASTNode::UnsubscribeExpression { .. }
+    )
+}
+
+// statements that only read the keys they name, run right away in a transaction
+fn reads(ast: &ASTNode) -> bool {
+    matches!(
+        ast,
+        ASTNode::SingleExpression {
+            keyword: Keywords::Get,
+            ..
+        } | ASTNode::MultiExpression {
+            keyword: Keywords::Get,
+            ..
+        } | ASTNode::TtlExpression { .. }
+    )
+}
+
+// statements that read every key of a database, a transaction can't tell if they were written
+fn scans(ast: &ASTNode) -> bool {
+    matches!(
+        ast,
+        ASTNode::SingleExpression {
+            keyword: Keywords::List,
+            ..
+        } | ASTNode::ListExpression { .. }
+            | ASTNode::WhereExpression { .. }
+            | ASTNode::AggregateExpression { .. }
+    )
+}
+
+// statements on users and databases name what they run on, and session statements touch no data
+fn needs_database(ast: &ASTNode) -> bool {
+    !matches!(
+        ast,
+        ASTNode::ExplainExpression { .. }
+            | ASTNode::UseExpression { .. }
+            | ASTNode::WhoamiExpression
+            | ASTNode::BeginExpression
+            | ASTNode::CommitExpression
+            | ASTNode::RollbackExpression
+            | ASTNode::CreateExpression { .. }
+            | ASTNode::CreateSchemaExpression { .. }
+            | ASTNode::DeleteSchemaExpression { .. }
+            | ASTNode::CreateIndexExpression { .. }
+            | ASTNode::DropIndexExpression { .. }
+            | ASTNode::RebuildIndexExpression { .. }
+            | ASTNode::AssignmentExpression { .. }
+            | ASTNode::UnwatchExpression { .. }
+            | ASTNode::PublishExpression { .. }
+            | ASTNode::SubscribeExpression { .. }
+            | ASTNode::UnsubscribeExpression { .. }
+            | ASTNode::MonadicExpression { .. }
+    )
+}
@@ -0,0 +1,309 @@
+use bson::{Bson, Document};
+
+use crate::query::parser::{path_to_string, PathSegment};
+use crate::server::wirewave::server::Status;
+
+use super::filter;
+use super::interface::TransactionError;
+
+/// `resolve_path` follows a path of fields and array indexes inside a value
+///
+/// Arguments:
+///
+/// * `value`: The value the path starts from.
+/// * `path`: The fields and array indexes to follow.
+///
+/// Returns:
+///
+/// The value at the end of the path, if every segment exists.
+pub fn resolve_path<'a>(value: &'a Bson, path: &[PathSegment]) -> Option<&'a Bson> {
+    path.iter()
+        .try_fold(value, |value, segment| match (value, segment) {
+            (Bson::Document(doc), PathSegment::Field(field)) => doc.get(field),
+            (Bson::Document(doc), PathSegment::Index(index)) => doc.get(index.to_string()),
+            (Bson::Array(array), PathSegment::Index(index)) => array.get(*index),
+            _ => None,
+        })
+}
+
+/// `project` picks some paths out of a value
+///
+/// Arguments:
+///
+/// * `value`: The value to pick the paths from.
+/// * `projection`: The paths to pick.
+///
+/// Returns:
+///
+/// A document keyed by the dotted paths. Paths missing from the value are left out.
+pub fn project(value: &Bson, projection: &[Vec<PathSegment>]) -> Document {
+    let mut projected = Document::new();
+
+    for path in projection {
+        if let Some(field) = resolve_path(value, path) {
+            projected.insert(path_to_string(path), field.clone());
+        }
+    }
+
+    projected
+}
+
+/// `parse_path` splits a dotted path such as `address.city` or `tags.0` into segments
+pub fn parse_path(path: &str) -> Result<Vec<PathSegment>, TransactionError> {
+    if path.is_empty() || path.split('.').any(|segment| segment.is_empty()) {
+        return Err(TransactionError::ExternalError(
+            Status::InvalidQuery,
+            format!("invalid path {:?}", path),
+        ));
+    }
+
+    Ok(path
+        .split('.')
+        .map(|segment| match segment.parse() {
+            Ok(index) if segment.chars().all(|c| c.is_ascii_digit()) => PathSegment::Index(index),
+            _ => PathSegment::Field(segment.to_string()),
+        })
+        .collect())
+}
+
+/// `apply_patch` applies update operators to a value, in the order they are written
+///
+/// Supported operators:
+///
+/// * `$set`: `{path: value}`, sets fields, creating missing parent documents.
+/// * `$unset`: `[path]` or `{path: any}`, removes fields.
+/// * `$inc`: `{path: number}`, adds to numeric fields, a missing field counts as 0.
+/// * `$push`: `{path: value}`, appends to arrays, a missing field counts as an empty array.
+/// * `$pull`: `{path: value}`, removes every array item equal to the value.
+/// * `$merge`: `document`, applies a JSON merge patch (RFC 7386) to the whole value.
+///
+/// Arguments:
+///
+/// * `value`: The stored value to patch.
+/// * `patch`: The operators to apply.
+pub fn apply_patch(value: &mut Bson, patch: &Document) -> Result<(), TransactionError> {
+    for (operator, operand) in patch {
+        match operator.as_str() {
+            "$merge" => merge_patch(value, operand),
+
+            "$unset" => {
+                let paths: Vec<&str> = match operand {
+                    Bson::Array(paths) => paths
+                        .iter()
+                        .map(|path| path.as_str().ok_or_else(|| bad_operand(operator)))
+                        .collect::<Result<_, _>>()?,
+                    Bson::Document(fields) => fields.keys().map(|path| path.as_str()).collect(),
+                    _ => return Err(bad_operand(operator)),
+                };
+
+                for path in paths {
+                    unset_path(value, &parse_path(path)?);
+                }
+            }
+
+            "$set" | "$inc" | "$push" | "$pull" => {
+                let fields = operand.as_document().ok_or_else(|| bad_operand(operator))?;
+
+                for (path, operand) in fields {
+                    let path = parse_path(path)?;
+
+                    match operator.as_str() {
+                        "$set" => set_path(value, &path, operand.clone())?,
+
+                        "$inc" => {
+                            let current = resolve_path(value, &path)
+                                .cloned()
+                                .unwrap_or(Bson::Int64(0));
+
+                            set_path(value, &path, add(&current, operand)?)?;
+                        }
+
+                        "$push" => match resolve_path_mut(value, &path) {
+                            Some(Bson::Array(array)) => array.push(operand.clone()),
+                            Some(_) => return Err(not_an_array(&path)),
+                            None => set_path(value, &path, Bson::Array(vec![operand.clone()]))?,
+                        },
+
+                        "$pull" => match resolve_path_mut(value, &path) {
+                            Some(Bson::Array(array)) => {
+                                array.retain(|item| !filter::equals(item, operand))
+                            }
+                            Some(_) => return Err(not_an_array(&path)),
+                            None => {}
+                        },
+
+                        _ => unreachable!(),
+                    }
+                }
+            }
+
+            _ => {
+                return Err(TransactionError::ExternalError(
+                    Status::InvalidQuery,
+                    format!("unknown update operator {}", operator),
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// `add` adds two numbers, keeping integers as integers while they fit
+///
+/// Returns:
+///
+/// The sum, or a `TypeMismatch` error if one of the values is not a number.
+pub fn add(a: &Bson, b: &Bson) -> Result<Bson, TransactionError> {
+    let overflow =
+        || TransactionError::ExternalError(Status::TypeMismatch, "integer overflow".to_string());
+
+    match (a, b) {
+        (Bson::Int32(a), Bson::Int32(b)) => Ok(match a.checked_add(*b) {
+            Some(sum) => Bson::Int32(sum),
+            None => Bson::Int64(*a as i64 + *b as i64),
+        }),
+
+        (Bson::Int32(_) | Bson::Int64(_), Bson::Int32(_) | Bson::Int64(_)) => {
+            let a = filter::as_i64(a).unwrap();
+            let b = filter::as_i64(b).unwrap();
+
+            a.checked_add(b).map(Bson::Int64).ok_or_else(overflow)
+        }
+
+        _ => match (filter::as_f64(a), filter::as_f64(b)) {
+            (Some(a), Some(b)) => Ok(Bson::Double(a + b)),
+            _ => Err(TransactionError::ExternalError(
+                Status::TypeMismatch,
+                "value is not a number".to_string(),
+            )),
+        },
+    }
+}
+
+/// RFC 7386: objects are merged recursively, `null` removes a field, anything else replaces
+fn merge_patch(target: &mut Bson, patch: &Bson) {
+    let patch = match patch {
+        Bson::Document(patch) => patch,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+
+    if !matches!(target, Bson::Document(_)) {
+        *target = Bson::Document(Document::new());
+    }
+
+    let target = target.as_document_mut().unwrap();
+
+    for (field, value) in patch {
+        if let Bson::Null = value {
+            target.remove(field);
+            continue;
+        }
+
+        match target.get_mut(field) {
+            Some(existing) => merge_patch(existing, value),
+            None => {
+                let mut merged = Bson::Null;
+                merge_patch(&mut merged, value);
+                target.insert(field, merged);
+            }
+        }
+    }
+}
+
+fn resolve_path_mut<'a>(value: &'a mut Bson, path: &[PathSegment]) -> Option<&'a mut Bson> {
+    path.iter()
+        .try_fold(value, |value, segment| match (value, segment) {
+            (Bson::Document(doc), segment) => doc.get_mut(segment.to_string()),
+            (Bson::Array(array), PathSegment::Index(index)) => array.get_mut(*index),
+            _ => None,
+        })
+}
+
+/// Sets the value at a path, creating missing parent documents. An array index can replace an
+/// item or append right after the last one.
+fn set_path(value: &mut Bson, path: &[PathSegment], new: Bson) -> Result<(), TransactionError> {
+    let (last, parents) = path.split_last().unwrap();
+    let mut parent = value;
+
+    for segment in parents {
+        parent = match (parent, segment) {
+            (Bson::Document(doc), segment) => {
+                let field = segment.to_string();
+
+                if !doc.contains_key(&field) {
+                    doc.insert(field.clone(), Document::new());
+                }
+
+                doc.get_mut(&field).unwrap()
+            }
+
+            (Bson::Array(array), PathSegment::Index(index)) if *index < array.len() => {
+                &mut array[*index]
+            }
+
+            _ => return Err(not_a_container(path)),
+        };
+    }
+
+    match (parent, last) {
+        (Bson::Document(doc), segment) => {
+            doc.insert(segment.to_string(), new);
+        }
+
+        (Bson::Array(array), PathSegment::Index(index)) if *index < array.len() => {
+            array[*index] = new;
+        }
+
+        (Bson::Array(array), PathSegment::Index(index)) if *index == array.len() => {
+            array.push(new);
+        }
+
+        _ => return Err(not_a_container(path)),
+    }
+
+    Ok(())
+}
+
+fn unset_path(value: &mut Bson, path: &[PathSegment]) {
+    let (last, parents) = path.split_last().unwrap();
+
+    match (resolve_path_mut(value, parents), last) {
+        (Some(Bson::Document(doc)), segment) => {
+            doc.remove(segment.to_string());
+        }
+
+        (Some(Bson::Array(array)), PathSegment::Index(index)) if *index < array.len() => {
+            array.remove(*index);
+        }
+
+        _ => {}
+    }
+}
+
+fn bad_operand(operator: &str) -> TransactionError {
+    TransactionError::ExternalError(
+        Status::InvalidQuery,
+        format!("invalid operand for {}", operator),
+    )
+}
+
+fn not_an_array(path: &[PathSegment]) -> TransactionError {
+    TransactionError::ExternalError(
+        Status::TypeMismatch,
+        format!("{} is not an array", path_to_string(path)),
+    )
+}
+
+fn not_a_container(path: &[PathSegment]) -> TransactionError {
+    TransactionError::ExternalError(
+        Status::TypeMismatch,
+        format!(
+            "can't set {}, a parent is not a document",
+            path_to_string(path)
+        ),
+    )
+}
@@ -0,0 +1,233 @@
+use bson::{Bson, DateTime};
+use dustdata::DustData;
+use dustdata::Error as DustDataError;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+use crate::server::cache::Cache;
+use crate::server::route::Router;
+
+use super::changes::{Change, ChangeHub, Operation};
+use super::index;
+
+// the expiry of a key is stored next to it, under a key no statement can name
+const EXPIRE_PREFIX: &str = "_expire.";
+
+/// How often the reaper purges expired keys, in milliseconds
+pub const REAP_INTERVAL: u64 = 1000;
+
+/// How many expired keys the reaper purges at most each time it locks the databases
+pub const REAP_BATCH: usize = 256;
+
+fn meta_key(key: &str) -> String {
+    format!("{}{}", EXPIRE_PREFIX, key)
+}
+
+/// `expires_at` reads when a key expires
+///
+/// Returns:
+///
+/// The expiry of the key, `None` if it never expires.
+pub fn expires_at(dd: &Router, key: &str) -> Result<Option<DateTime>, DustDataError> {
+    match dd.get(&meta_key(key))? {
+        Some(Bson::DateTime(at)) => Ok(Some(at)),
+        _ => Ok(None),
+    }
+}
+
+/// `is_expired` checks if a key has expired but was not purged yet
+pub fn is_expired(dd: &Router, key: &str) -> Result<bool, DustDataError> {
+    match expires_at(dd, key)? {
+        Some(at) => Ok(at <= DateTime::now()),
+        None => Ok(false),
+    }
+}
+
+/// `load_schedule` reads when the expiring keys of a database expire, see [`Router::expiries`]
+pub fn load_schedule(dd: &DustData) -> Result<BTreeSet<(DateTime, String)>, DustDataError> {
+    let mut expiries = BTreeSet::new();
+
+    for key in dd.list_keys()? {
+        if let Some(expiring) = key.strip_prefix(EXPIRE_PREFIX) {
+            if let Some(Bson::DateTime(at)) = dd.get(&key)? {
+                expiries.insert((at, expiring.to_string()));
+            }
+        }
+    }
+
+    Ok(expiries)
+}
+
+/// `set` makes a key expire at the given time, replacing its previous expiry
+pub fn set(dd: &mut Router, key: &str, at: DateTime) -> Result<(), DustDataError> {
+    let meta_key = meta_key(key);
+
+    match dd.get(&meta_key)? {
+        Some(previous) => {
+            if let Bson::DateTime(previous) = previous {
+                dd.expiries.remove(&(previous, key.to_string()));
+            }
+
+            dd.update(&meta_key, Bson::DateTime(at))?;
+        }
+        None => dd.insert(&meta_key, Bson::DateTime(at))?,
+    }
+
+    dd.expiries.insert((at, key.to_string()));
+
+    Ok(())
+}
+
+/// `clear` makes a key never expire
+///
+/// Returns:
+///
+/// Whether the key had an expiry.
+pub fn clear(dd: &mut Router, key: &str) -> Result<bool, DustDataError> {
+    let meta_key = meta_key(key);
+
+    match dd.get(&meta_key)? {
+        Some(at) => {
+            if let Bson::DateTime(at) = at {
+                dd.expiries.remove(&(at, key.to_string()));
+            }
+
+            dd.delete(&meta_key)?;
+
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// `purge` deletes a key and its expiry if it has expired, so writes see it as missing
+///
+/// The indexes of the key and its watches are not told, the caller must reindex the key and emit
+/// an `Operation::Expire` change once the databases can be borrowed again.
+///
+/// Arguments:
+///
+/// * `dd`: The database of the key.
+/// * `cache`: The cache, the key is evicted from it.
+/// * `database`: The name of the database.
+/// * `key`: The key.
+///
+/// Returns:
+///
+/// Whether the key was purged.
+pub fn purge(
+    dd: &mut Router,
+    cache: &mut Cache,
+    database: &str,
+    key: &str,
+) -> Result<bool, DustDataError> {
+    if !is_expired(dd, key)? {
+        return Ok(false);
+    }
+
+    if dd.get(key)?.is_some() {
+        dd.delete(key)?;
+    }
+
+    clear(dd, key)?;
+    cache.remove(&format!("{}:{}", database, key)).ok();
+
+    Ok(true)
+}
+
+/// `live_keys` lists the keys of a database that statements can see
+///
+/// Returns:
+///
+/// The keys, sorted, without metadata and expired keys.
+pub fn live_keys(dd: &Router) -> Result<Vec<String>, DustDataError> {
+    let now = DateTime::now();
+
+    let expired = dd
+        .expiries
+        .iter()
+        .take_while(|(at, _)| *at <= now)
+        .map(|(_, key)| key.as_str())
+        .collect::<HashSet<_>>();
+
+    Ok(dd
+        .range(Bound::Unbounded, Bound::Unbounded)
+        .filter(|key| !expired.contains(key.as_str()))
+        .cloned()
+        .collect())
+}
+
+/// `reap` purges the expired keys of every database and evicts them from the cache
+///
+/// Only the keys due are read, from [`Router::expiries`], and the databases are unlocked after
+/// every `REAP_BATCH` keys so statements don't wait for the whole purge.
+///
+/// Arguments:
+///
+/// * `routers`: The databases.
+/// * `cache`: The cache.
+/// * `changes`: The watches, told the keys expired.
+/// * `gate`: Held for writing by `commit`, no key is purged while a transaction is committed.
+///
+/// Returns:
+///
+/// The number of purged keys.
+pub fn reap(
+    routers: &Arc<RwLock<HashMap<String, Router>>>,
+    cache: &Arc<RwLock<Cache>>,
+    changes: &Arc<RwLock<ChangeHub>>,
+    gate: &Arc<RwLock<()>>,
+) -> Result<usize, DustDataError> {
+    let mut purged = 0;
+
+    loop {
+        let batch = reap_batch(routers, cache, changes, gate)?;
+        purged += batch;
+
+        if batch < REAP_BATCH {
+            return Ok(purged);
+        }
+    }
+}
+
+fn reap_batch(
+    routers: &Arc<RwLock<HashMap<String, Router>>>,
+    cache: &Arc<RwLock<Cache>>,
+    changes: &Arc<RwLock<ChangeHub>>,
+    gate: &Arc<RwLock<()>>,
+) -> Result<usize, DustDataError> {
+    // commits hold the gate for writing: a key purged between the statements of a commit would
+    // change what the next ones see, after its snapshots were checked
+    let _gate = gate.read().unwrap();
+
+    let mut cache = cache.write().unwrap();
+    let mut routers = routers.write().unwrap();
+
+    let now = DateTime::now();
+    let mut expired = Vec::new();
+
+    for (database, dd) in routers.iter_mut() {
+        while expired.len() < REAP_BATCH {
+            match dd.expiries.first() {
+                Some((at, _)) if *at <= now => {}
+                _ => break,
+            }
+
+            let (_, key) = dd.expiries.pop_first().unwrap();
+
+            if purge(dd, &mut cache, database, &key)? {
+                expired.push((database.clone(), key));
+            }
+        }
+    }
+
+    let mut changes = changes.write().unwrap();
+
+    for (database, key) in &expired {
+        index::reindex(&mut routers, database, key, None)?;
+        changes.emit(Change::new(database, key, Operation::Expire, None));
+    }
+
+    Ok(expired.len())
+}
@@ -0,0 +1,117 @@
+use bson::Bson;
+use std::cmp::Ordering;
+
+use crate::query::parser::{Condition, Operator};
+
+use super::document::resolve_path;
+
+/// `matches` checks if a stored value satisfies a `where` condition
+///
+/// Arguments:
+///
+/// * `value`: The stored value, usually a document.
+/// * `condition`: The condition to evaluate.
+///
+/// Returns:
+///
+/// A boolean
+pub fn matches(value: &Bson, condition: &Condition) -> bool {
+    match condition {
+        Condition::And(conditions) => conditions.iter().all(|c| matches(value, c)),
+
+        Condition::Or(conditions) => conditions.iter().any(|c| matches(value, c)),
+
+        Condition::Exists { field } => resolve_path(value, field).is_some(),
+
+        Condition::In { field, values } => match resolve_path(value, field) {
+            Some(actual) => values.iter().any(|expected| equals(actual, expected)),
+            None => false,
+        },
+
+        Condition::Compare {
+            field,
+            operator,
+            value: expected,
+        } => {
+            let actual = resolve_path(value, field);
+
+            match operator {
+                Operator::Eq => match actual {
+                    Some(actual) => equals(actual, expected),
+                    None => false,
+                },
+
+                // a missing field is different from anything
+                Operator::Ne => match actual {
+                    Some(actual) => !equals(actual, expected),
+                    None => true,
+                },
+
+                _ => {
+                    let ordering = match actual.and_then(|actual| compare(actual, expected)) {
+                        Some(ordering) => ordering,
+                        None => return false,
+                    };
+
+                    match operator {
+                        Operator::Lt => ordering == Ordering::Less,
+                        Operator::Le => ordering != Ordering::Greater,
+                        Operator::Gt => ordering == Ordering::Greater,
+                        Operator::Ge => ordering != Ordering::Less,
+                        _ => unreachable!(),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `equals` compares two values, treating numbers of different types as equal if they have the same
+/// value
+pub fn equals(a: &Bson, b: &Bson) -> bool {
+    match compare(a, b) {
+        Some(ordering) => ordering == Ordering::Equal,
+        None => a == b,
+    }
+}
+
+/// `compare` orders two values of the same kind
+///
+/// Numbers are compared by value whatever their BSON type is.
+///
+/// Returns:
+///
+/// The ordering of `a` relative to `b`, or `None` if the values can't be ordered.
+pub fn compare(a: &Bson, b: &Bson) -> Option<Ordering> {
+    match (a, b) {
+        (Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        (Bson::Boolean(a), Bson::Boolean(b)) => Some(a.cmp(b)),
+        (Bson::DateTime(a), Bson::DateTime(b)) => Some(a.cmp(b)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => Some(a.bytes().cmp(&b.bytes())),
+        (Bson::Null, Bson::Null) => Some(Ordering::Equal),
+
+        _ => match (as_i64(a), as_i64(b)) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => as_f64(a)?.partial_cmp(&as_f64(b)?),
+        },
+    }
+}
+
+/// `as_i64` reads an integer value as a `i64`
+pub fn as_i64(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::Int32(i) => Some(*i as i64),
+        Bson::Int64(i) => Some(*i),
+        _ => None,
+    }
+}
+
+/// `as_f64` reads any numeric value as a `f64`
+pub fn as_f64(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(i) => Some(*i as f64),
+        Bson::Int64(i) => Some(*i as f64),
+        Bson::Double(f) => Some(*f),
+        _ => None,
+    }
+}
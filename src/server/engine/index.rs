@@ -0,0 +1,1009 @@
+use bson::Bson;
+use dustdata::Error as DustDataError;
+use std::collections::HashMap;
+use std::ops::Bound;
+
+use crate::query::parser::{path_to_string, Condition, Operator, PathSegment};
+use crate::server::route::Router;
+use crate::utils;
+
+use super::document::resolve_path;
+use super::expiry;
+use super::filter;
+
+// an index is a DustData instance of its own, routed next to its database under a name no
+// statement can select
+const INDEX_PREFIX: &str = "_index.";
+
+// `v:<encoded value>/<key>` entries sort like the values they index, and `k:<key>` entries remember
+// the encoded value of each key, so an entry can be removed without knowing the previous value.
+// Encoded values never contain `/`, which sorts before them, so the entries of a value are next to
+// each other and before the entries of the values it is a prefix of
+const ENTRY_PREFIX: &str = "v:";
+const KEY_PREFIX: &str = "k:";
+
+// set in the indexes created with `create unique index`
+const UNIQUE_KEY: &str = "unique";
+
+/// A key whose value in a uniquely indexed field is already used by another key
+pub struct Duplicate {
+    pub field: String,
+    /// the key that already has the value
+    pub key: String,
+    pub value: Bson,
+}
+
+/// Why a value can't be written in a uniquely indexed field
+pub enum Violation {
+    /// another key already has the value
+    Duplicate(Duplicate),
+    /// the value is not indexed (documents, arrays, decimals...), so it can't be checked
+    Unindexed { field: String, value: Bson },
+}
+
+/// How an index narrows a `where` condition down to the keys that can match it
+enum Probe {
+    /// keys whose field is encoded as one of the values
+    Equals { route: String, encoded: Vec<String> },
+    /// keys whose field has the type of the bound and is encoded at or above (or below) it
+    Range {
+        route: String,
+        bound: String,
+        above: bool,
+    },
+    /// keys found by any of the probes
+    Union(Vec<Probe>),
+}
+
+/// `route` names the DustData instance an index is stored in
+///
+/// Arguments:
+///
+/// * `database`: The name of the indexed database.
+/// * `field`: The indexed field.
+///
+/// Returns:
+///
+/// The route of the index, e.g. `_index.users.address.city`.
+pub fn route(database: &str, field: &[PathSegment]) -> String {
+    format!("{}{}.{}", INDEX_PREFIX, database, path_to_string(field))
+}
+
+/// `routes` lists the indexes of a database
+///
+/// Returns:
+///
+/// The routes of the indexes, sorted.
+pub fn routes(routers: &HashMap<String, Router>, database: &str) -> Vec<String> {
+    let prefix = format!("{}{}.", INDEX_PREFIX, database);
+
+    let mut routes = routers
+        .keys()
+        .filter(|route| route.starts_with(&prefix))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    routes.sort();
+    routes
+}
+
+/// `fields` lists the indexed fields of a database
+pub fn fields(routers: &HashMap<String, Router>, database: &str) -> Vec<String> {
+    routes(routers, database)
+        .into_iter()
+        .map(|route| field_of(&route, database).to_string())
+        .collect()
+}
+
+fn field_of<'a>(route: &'a str, database: &str) -> &'a str {
+    &route[INDEX_PREFIX.len() + database.len() + 1..]
+}
+
+// indexed fields are made of identifiers and array positions, so their route splits back into them
+fn parse_field(field: &str) -> Vec<PathSegment> {
+    field
+        .split('.')
+        .map(|segment| match segment.parse() {
+            Ok(index) => PathSegment::Index(index),
+            Err(_) => PathSegment::Field(segment.to_string()),
+        })
+        .collect()
+}
+
+/// `encode` turns a value into a string that sorts like the values of its type
+///
+/// Returns:
+///
+/// The encoded value, `None` for values that are not indexed (documents, arrays, binaries...).
+fn encode(value: &Bson) -> Option<String> {
+    let encoded = match value {
+        Bson::Null => "0".to_string(),
+
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) => {
+            // -0.0 is equal to 0.0, so it must be encoded the same way
+            let number = filter::as_f64(value)? + 0.0;
+            let bits = number.to_bits();
+
+            // flipping the sign bit, and every bit of negative numbers, makes the bits sort like
+            // the numbers
+            let bits = if bits >> 63 == 1 {
+                !bits
+            } else {
+                bits | 1 << 63
+            };
+
+            format!("1{:016x}", bits)
+        }
+
+        Bson::String(string) => format!("2{}", utils::to_hex(string.as_bytes())),
+        Bson::ObjectId(oid) => format!("3{}", utils::to_hex(&oid.bytes())),
+        Bson::Boolean(boolean) => format!("4{}", *boolean as u8),
+        Bson::DateTime(at) => format!("5{:016x}", (at.timestamp_millis() as u64) ^ 1 << 63),
+
+        _ => return None,
+    };
+
+    Some(encoded)
+}
+
+/// `reindex` updates the indexes of a database after a key was written or deleted
+///
+/// Arguments:
+///
+/// * `routers`: The databases and indexes of the server.
+/// * `database`: The database the key was written to.
+/// * `key`: The key.
+/// * `value`: The new value of the key, `None` if it was deleted.
+pub fn reindex(
+    routers: &mut HashMap<String, Router>,
+    database: &str,
+    key: &str,
+    value: Option<&Bson>,
+) -> Result<(), DustDataError> {
+    for route in routes(routers, database) {
+        let field = parse_field(field_of(&route, database));
+        let encoded = value.and_then(|value| encode(resolve_path(value, &field)?));
+
+        write_entry(routers.get_mut(&route).unwrap(), key, encoded)?;
+    }
+
+    Ok(())
+}
+
+fn write_entry(
+    index: &mut Router,
+    key: &str,
+    encoded: Option<String>,
+) -> Result<(), DustDataError> {
+    let key_entry = format!("{}{}", KEY_PREFIX, key);
+
+    let previous = match index.get(&key_entry)? {
+        Some(Bson::String(previous)) => Some(previous),
+        _ => None,
+    };
+
+    if previous == encoded {
+        return Ok(());
+    }
+
+    if let Some(previous) = previous {
+        index.delete(&format!("{}{}/{}", ENTRY_PREFIX, previous, key))?;
+        index.delete(&key_entry)?;
+    }
+
+    if let Some(encoded) = encoded {
+        index.insert(&format!("{}{}/{}", ENTRY_PREFIX, encoded, key), Bson::Null)?;
+        index.insert(&key_entry, Bson::String(encoded))?;
+    }
+
+    Ok(())
+}
+
+/// `mark_unique` makes an index refuse two keys with the same value, see [`find_violation`]
+pub fn mark_unique(index: &mut Router) -> Result<(), DustDataError> {
+    index.insert(UNIQUE_KEY, Bson::Boolean(true))
+}
+
+fn is_unique(index: &Router) -> Result<bool, DustDataError> {
+    Ok(index.get(UNIQUE_KEY)? == Some(Bson::Boolean(true)))
+}
+
+/// `build` fills an empty index with the live keys of its database
+///
+/// Arguments:
+///
+/// * `routers`: The databases and indexes of the server, the index included.
+/// * `database`: The indexed database.
+/// * `field`: The indexed field.
+///
+/// Returns:
+///
+/// For a unique index, the first key found with a value that is not indexed, or the first two keys
+/// found with the same value, as the second key and a [`Violation::Duplicate`] naming the first.
+/// The index is built anyway.
+pub fn build(
+    routers: &mut HashMap<String, Router>,
+    database: &str,
+    field: &[PathSegment],
+) -> Result<Option<(String, Violation)>, DustDataError> {
+    let unique = is_unique(&routers[&route(database, field)])?;
+
+    let mut entries = Vec::new();
+    let mut violation = None;
+
+    if let Some(dd) = routers.get(database) {
+        for key in expiry::live_keys(dd)? {
+            let value = match dd.get(&key)? {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let value = match resolve_path(&value, field) {
+                Some(value) => value.clone(),
+                None => continue,
+            };
+
+            match encode(&value) {
+                Some(encoded) => entries.push((encoded, key, value)),
+                None if unique && violation.is_none() => {
+                    let field = path_to_string(field);
+
+                    violation = Some((key, Violation::Unindexed { field, value }));
+                }
+                None => {}
+            }
+        }
+    }
+
+    let index = routers.get_mut(&route(database, field)).unwrap();
+
+    if unique && violation.is_none() {
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // keys with the same value are next to each other once sorted
+        violation = entries
+            .windows(2)
+            .find(|pair| pair[0].0 == pair[1].0 && is_duplicate(&pair[0].2, &pair[1].2))
+            .map(|pair| {
+                let key = pair[1].1.clone();
+
+                let duplicate = Duplicate {
+                    field: path_to_string(field),
+                    key: pair[0].1.clone(),
+                    value: pair[0].2.clone(),
+                };
+
+                (key, Violation::Duplicate(duplicate))
+            });
+    }
+
+    for (encoded, key, _) in entries {
+        write_entry(index, &key, Some(encoded))?;
+    }
+
+    Ok(violation)
+}
+
+// null, like a missing field, is not a value keys can conflict on
+fn is_duplicate(a: &Bson, b: &Bson) -> bool {
+    *a != Bson::Null && encode(a).is_some() && filter::equals(a, b)
+}
+
+/// `find_violation` looks for another key that already has the value a key is about to be written
+/// with, in a uniquely indexed field
+///
+/// Values that are not indexed can't be compared with the values of the other keys, so they are
+/// refused in a uniquely indexed field.
+///
+/// Arguments:
+///
+/// * `routers`: The databases and indexes of the server.
+/// * `database`: The database the key is written to.
+/// * `value`: The value about to be written.
+/// * `except`: The keys that can have the same value: the written key, and the source of a
+///   rename or a move, which is deleted by the write.
+///
+/// Returns:
+///
+/// The first key found with the same value, or the value if it is not indexed, `None` if the
+/// value can be written.
+pub fn find_violation(
+    routers: &HashMap<String, Router>,
+    database: &str,
+    value: &Bson,
+    except: &[&str],
+) -> Result<Option<Violation>, DustDataError> {
+    let dd = match routers.get(database) {
+        Some(dd) => dd,
+        None => return Ok(None),
+    };
+
+    for route in routes(routers, database) {
+        let index = &routers[&route];
+
+        if !is_unique(index)? {
+            continue;
+        }
+
+        let field = parse_field(field_of(&route, database));
+
+        let new = match resolve_path(value, &field) {
+            Some(new) if encode(new).is_none() => {
+                return Ok(Some(Violation::Unindexed {
+                    field: field_of(&route, database).to_string(),
+                    value: new.clone(),
+                }))
+            }
+            Some(new) if is_duplicate(new, new) => new,
+            _ => continue,
+        };
+
+        // every key with the value has an entry starting with its encoding
+        let encoded = encode(new).unwrap();
+        let from = format!("{}{}/", ENTRY_PREFIX, encoded);
+
+        let others = entries(index, &from)
+            .take_while(|(value, _)| *value == encoded)
+            .map(|(_, other)| other);
+
+        for other in others {
+            if except.contains(&other) || expiry::is_expired(dd, other)? {
+                continue;
+            }
+
+            // the entry is checked against the stored value, as numbers of different types can
+            // be encoded the same way
+            let current = match dd.get(other)? {
+                Some(current) => current,
+                None => continue,
+            };
+
+            if let Some(current) = resolve_path(&current, &field) {
+                if is_duplicate(current, new) {
+                    return Ok(Some(Violation::Duplicate(Duplicate {
+                        field: field_of(&route, database).to_string(),
+                        key: other.to_string(),
+                        value: current.clone(),
+                    })));
+                }
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// `find_duplicate_in` looks for two keys with the same value in a uniquely indexed field, among
+/// keys about to be written together
+///
+/// Returns:
+///
+/// The second key found with the value, and a [`Duplicate`] naming the first.
+pub fn find_duplicate_in(
+    routers: &HashMap<String, Router>,
+    database: &str,
+    entries: &[(String, Bson)],
+) -> Result<Option<(String, Duplicate)>, DustDataError> {
+    for route in routes(routers, database) {
+        if !is_unique(&routers[&route])? {
+            continue;
+        }
+
+        let field = parse_field(field_of(&route, database));
+
+        for (i, (key, value)) in entries.iter().enumerate() {
+            let new = match resolve_path(value, &field) {
+                Some(new) => new,
+                None => continue,
+            };
+
+            for (other, value) in &entries[..i] {
+                if let Some(current) = resolve_path(value, &field) {
+                    if is_duplicate(current, new) {
+                        let duplicate = Duplicate {
+                            field: field_of(&route, database).to_string(),
+                            key: other.clone(),
+                            value: current.clone(),
+                        };
+
+                        return Ok(Some((key.clone(), duplicate)));
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// `rebuild` empties an index and fills it again with the live keys of its database
+pub fn rebuild(
+    routers: &mut HashMap<String, Router>,
+    database: &str,
+    field: &[PathSegment],
+) -> Result<(), DustDataError> {
+    let index = routers.get_mut(&route(database, field)).unwrap();
+
+    for entry in index.list_keys()? {
+        if entry != UNIQUE_KEY {
+            index.delete(&entry)?;
+        }
+    }
+
+    // the keys of a unique index are checked on every write, there is nothing to report
+    build(routers, database, field).map(|_| ())
+}
+
+fn probe(
+    routers: &HashMap<String, Router>,
+    database: &str,
+    condition: &Condition,
+) -> Option<Probe> {
+    let route_of = |field: &[PathSegment]| {
+        let route = route(database, field);
+
+        routers.contains_key(&route).then_some(route)
+    };
+
+    match condition {
+        // one indexed condition is enough, the others are checked on the values it finds
+        Condition::And(conditions) => conditions
+            .iter()
+            .find_map(|condition| probe(routers, database, condition)),
+
+        Condition::Or(conditions) => conditions
+            .iter()
+            .map(|condition| probe(routers, database, condition))
+            .collect::<Option<Vec<_>>>()
+            .map(Probe::Union),
+
+        Condition::Compare {
+            field,
+            operator,
+            value,
+        } => {
+            let route = route_of(field)?;
+            let encoded = encode(value)?;
+
+            match operator {
+                Operator::Eq => Some(Probe::Equals {
+                    route,
+                    encoded: vec![encoded],
+                }),
+                Operator::Gt | Operator::Ge => Some(Probe::Range {
+                    route,
+                    bound: encoded,
+                    above: true,
+                }),
+                Operator::Lt | Operator::Le => Some(Probe::Range {
+                    route,
+                    bound: encoded,
+                    above: false,
+                }),
+                Operator::Ne => None,
+            }
+        }
+
+        Condition::In { field, values } => Some(Probe::Equals {
+            route: route_of(field)?,
+            encoded: values.iter().map(encode).collect::<Option<_>>()?,
+        }),
+
+        Condition::Exists { .. } => None,
+    }
+}
+
+impl Probe {
+    fn routes(&self, routes: &mut Vec<String>) {
+        match self {
+            Probe::Equals { route, .. } | Probe::Range { route, .. } => {
+                if !routes.contains(route) {
+                    routes.push(route.clone());
+                }
+            }
+            Probe::Union(probes) => probes.iter().for_each(|probe| probe.routes(routes)),
+        }
+    }
+
+    fn run(
+        &self,
+        routers: &HashMap<String, Router>,
+        keys: &mut Vec<String>,
+    ) -> Result<(), DustDataError> {
+        let route = match self {
+            Probe::Equals { route, .. } | Probe::Range { route, .. } => route,
+
+            Probe::Union(probes) => {
+                for probe in probes {
+                    probe.run(routers, keys)?;
+                }
+
+                return Ok(());
+            }
+        };
+
+        let index = match routers.get(route) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+
+        match self {
+            Probe::Equals { encoded, .. } => {
+                for encoded in encoded {
+                    let from = format!("{}{}/", ENTRY_PREFIX, encoded);
+
+                    let found = entries(index, &from)
+                        .take_while(|(value, _)| value == encoded)
+                        .map(|(_, key)| key.to_string());
+
+                    keys.extend(found);
+                }
+            }
+
+            // the values of a type are next to each other, from the bound up or from the lowest
+            // value of the type up to the bound
+            Probe::Range { bound, above, .. } => {
+                let start = if *above { bound } else { &bound[..1] };
+                let from = format!("{}{}", ENTRY_PREFIX, start);
+
+                let found = entries(index, &from)
+                    .take_while(|(value, _)| self.keeps(value))
+                    .map(|(_, key)| key.to_string());
+
+                keys.extend(found);
+            }
+
+            Probe::Union(_) => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    // whether a key whose field is encoded as `value` can match
+    fn keeps(&self, value: &str) -> bool {
+        match self {
+            Probe::Equals { encoded, .. } => encoded.iter().any(|e| e == value),
+
+            // the bound is included, as numbers of different types can be encoded the same way
+            Probe::Range { bound, above, .. } => {
+                value[..1] == bound[..1]
+                    && if *above {
+                        value >= bound.as_str()
+                    } else {
+                        value <= bound.as_str()
+                    }
+            }
+
+            Probe::Union(_) => false,
+        }
+    }
+}
+
+// the `v:` entries of an index from the first one at or after `from`, as (encoded value, key)
+fn entries<'a>(index: &'a Router, from: &str) -> impl Iterator<Item = (&'a str, &'a str)> {
+    index
+        .range(Bound::Included(from), Bound::Unbounded)
+        .map_while(|entry| entry.strip_prefix(ENTRY_PREFIX)?.split_once('/'))
+}
+
+/// `candidates` finds the keys of a database that can match a condition, using its indexes
+///
+/// The keys found must still be checked against the condition: an index only rules keys out.
+///
+/// Returns:
+///
+/// The keys, sorted, or `None` if no index can narrow the condition down.
+pub fn candidates(
+    routers: &HashMap<String, Router>,
+    database: &str,
+    condition: &Condition,
+) -> Result<Option<Vec<String>>, DustDataError> {
+    let probe = match probe(routers, database, condition) {
+        Some(probe) => probe,
+        None => return Ok(None),
+    };
+
+    let mut keys = Vec::new();
+    probe.run(routers, &mut keys)?;
+
+    keys.sort();
+    keys.dedup();
+
+    Ok(Some(keys))
+}
+
+/// `explain` tells which indexes `candidates` would use for a condition
+///
+/// Returns:
+///
+/// The indexed fields, empty if the condition needs a full scan.
+pub fn explain(
+    routers: &HashMap<String, Router>,
+    database: &str,
+    condition: &Condition,
+) -> Vec<String> {
+    let mut routes = Vec::new();
+
+    if let Some(probe) = probe(routers, database, condition) {
+        probe.routes(&mut routes);
+    }
+
+    routes
+        .iter()
+        .map(|route| field_of(route, database).to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::oid::ObjectId;
+    use bson::{doc, Bson, DateTime, Decimal128};
+    use dustdata::{DustDataConfig, LsmConfig, Size};
+    use proptest::prelude::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::query::parser::PathSegment;
+    use crate::server::route::Router;
+
+    use super::{
+        build, encode, find_duplicate_in, find_violation, mark_unique, route, Duplicate, Violation,
+    };
+
+    fn encoded(value: Bson) -> String {
+        encode(&value).unwrap()
+    }
+
+    fn number() -> impl Strategy<Value = f64> {
+        any::<f64>().prop_filter("a number", |number| !number.is_nan())
+    }
+
+    // the databases of a test, in a directory of their own removed once the test is done
+    struct Server {
+        path: PathBuf,
+        routers: HashMap<String, Router>,
+    }
+
+    impl Server {
+        fn open(&mut self, route: &str) -> &mut Router {
+            let config = DustDataConfig {
+                path: self.path.join(route),
+                lsm_config: LsmConfig {
+                    flush_threshold: Size::Bytes(1024 * 1024),
+                },
+            };
+
+            let dd = Router::new(dustdata::initialize(config)).unwrap();
+
+            self.routers.entry(route.to_string()).or_insert(dd)
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            self.routers.clear();
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    // a `users` database holding the given values, with a unique index on `email`
+    fn users(values: &[(&str, Bson)]) -> Server {
+        static SERVERS: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "rustbase-index-{}-{}",
+            std::process::id(),
+            SERVERS.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let mut server = Server {
+            path,
+            routers: HashMap::new(),
+        };
+
+        let dd = server.open("users");
+
+        for (key, value) in values {
+            dd.insert(key, value.clone()).unwrap();
+        }
+
+        let field = [PathSegment::Field("email".to_string())];
+
+        mark_unique(server.open(&route("users", &field))).unwrap();
+        assert!(build(&mut server.routers, "users", &field)
+            .unwrap()
+            .is_none());
+
+        server
+    }
+
+    fn user(email: impl Into<Bson>) -> Bson {
+        Bson::Document(doc! {"email": email.into()})
+    }
+
+    // the key that already has a value about to be written by `key`
+    fn duplicate_of(server: &Server, key: &str, value: Bson) -> Option<String> {
+        match find_violation(&server.routers, "users", &value, &[key]).unwrap() {
+            Some(Violation::Duplicate(Duplicate { key, .. })) => Some(key),
+            Some(Violation::Unindexed { field, value }) => {
+                panic!("{} can't be indexed in {}", value, field)
+            }
+            None => None,
+        }
+    }
+
+    #[test]
+    fn numbers_sort_across_signs() {
+        let numbers = [
+            f64::NEG_INFINITY,
+            f64::MIN,
+            -1e10,
+            -1.5,
+            -1.0,
+            -f64::MIN_POSITIVE,
+            0.0,
+            f64::MIN_POSITIVE,
+            0.5,
+            1.0,
+            1e10,
+            f64::MAX,
+            f64::INFINITY,
+        ];
+
+        let encoded = numbers
+            .iter()
+            .map(|number| encoded(Bson::Double(*number)))
+            .collect::<Vec<_>>();
+
+        assert!(
+            encoded.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            encoded
+        );
+    }
+
+    #[test]
+    fn zeros_are_encoded_the_same() {
+        assert_eq!(encoded(Bson::Double(-0.0)), encoded(Bson::Double(0.0)));
+        assert_eq!(encoded(Bson::Double(-0.0)), encoded(Bson::Int64(0)));
+        assert_eq!(encoded(Bson::Int32(0)), encoded(Bson::Int64(0)));
+    }
+
+    #[test]
+    fn integers_are_encoded_like_doubles() {
+        for number in [i64::MIN, -(1 << 53), -42, -1, 1, 42, 1 << 53, i64::MAX] {
+            assert_eq!(
+                encoded(Bson::Int64(number)),
+                encoded(Bson::Double(number as f64)),
+                "{}",
+                number
+            );
+        }
+
+        assert_eq!(encoded(Bson::Int32(-7)), encoded(Bson::Int64(-7)));
+        assert_eq!(
+            encoded(Bson::Int32(i32::MAX)),
+            encoded(Bson::Int64(i32::MAX as i64))
+        );
+    }
+
+    #[test]
+    fn types_sort_in_order() {
+        // the last value of each type and the first value of the next one
+        let values = [
+            Bson::Null,
+            Bson::Double(f64::NEG_INFINITY),
+            Bson::Double(f64::INFINITY),
+            Bson::String(String::new()),
+            Bson::String("\u{10ffff}".repeat(4)),
+            Bson::ObjectId(ObjectId::from_bytes([0; 12])),
+            Bson::ObjectId(ObjectId::from_bytes([0xff; 12])),
+            Bson::Boolean(false),
+            Bson::Boolean(true),
+            Bson::DateTime(DateTime::from_millis(i64::MIN)),
+            Bson::DateTime(DateTime::from_millis(i64::MAX)),
+        ];
+
+        let encoded = values.into_iter().map(encoded).collect::<Vec<_>>();
+
+        assert!(
+            encoded.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            encoded
+        );
+    }
+
+    #[test]
+    fn dates_sort_across_the_epoch() {
+        let dates = [i64::MIN, -1000, -1, 0, 1, 1000, i64::MAX]
+            .map(|millis| encoded(Bson::DateTime(DateTime::from_millis(millis))));
+
+        assert!(
+            dates.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            dates
+        );
+    }
+
+    #[test]
+    fn documents_arrays_and_decimals_are_not_encoded() {
+        assert_eq!(encode(&Bson::Document(doc! {"a": 1})), None);
+        assert_eq!(encode(&Bson::Array(vec![Bson::Int32(1)])), None);
+        assert_eq!(
+            encode(&Bson::Decimal128(Decimal128::from_bytes([0; 16]))),
+            None
+        );
+    }
+
+    #[test]
+    fn writes_duplicating_another_key_are_refused() {
+        let server = users(&[("ada", user("ada@example.com"))]);
+
+        // an insert of a new key, then an update and a patch of an existing one, are all checked
+        // against the whole value the key ends up with
+        assert_eq!(
+            duplicate_of(&server, "grace", user("ada@example.com")),
+            Some("ada".to_string())
+        );
+        assert_eq!(
+            duplicate_of(
+                &server,
+                "grace",
+                Bson::Document(doc! {"email": "ada@example.com", "name": "Grace"})
+            ),
+            Some("ada".to_string())
+        );
+        assert_eq!(
+            duplicate_of(&server, "grace", user("grace@example.com")),
+            None
+        );
+    }
+
+    #[test]
+    fn a_key_keeps_its_own_value() {
+        let server = users(&[("ada", user("ada@example.com"))]);
+
+        assert_eq!(
+            duplicate_of(
+                &server,
+                "ada",
+                Bson::Document(doc! {"email": "ada@example.com", "name": "Ada"})
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn renamed_keys_dont_conflict_with_themselves() {
+        let server = users(&[("ada", user("ada@example.com"))]);
+
+        let value = user("ada@example.com");
+        let violation = find_violation(&server.routers, "users", &value, &["lovelace", "ada"]);
+
+        assert!(violation.unwrap().is_none());
+    }
+
+    #[test]
+    fn missing_and_null_values_never_conflict() {
+        let server = users(&[("ada", Bson::Document(doc! {"name": "Ada"}))]);
+
+        assert_eq!(
+            duplicate_of(&server, "grace", Bson::Document(doc! {"name": "Grace"})),
+            None
+        );
+        assert_eq!(duplicate_of(&server, "grace", user(Bson::Null)), None);
+    }
+
+    #[test]
+    fn numbers_encoded_the_same_are_duplicates() {
+        let server = users(&[("ada", user(Bson::Int32(7)))]);
+
+        for number in [Bson::Int32(7), Bson::Int64(7), Bson::Double(7.0)] {
+            assert_eq!(
+                duplicate_of(&server, "grace", user(number.clone())),
+                Some("ada".to_string()),
+                "{}",
+                number
+            );
+        }
+
+        assert_eq!(
+            duplicate_of(&server, "grace", user(Bson::Double(7.5))),
+            None
+        );
+    }
+
+    #[test]
+    fn values_that_cant_be_indexed_are_refused() {
+        let server = users(&[]);
+
+        let values = [
+            Bson::Document(doc! {"address": "ada@example.com"}),
+            Bson::Array(vec![Bson::String("ada@example.com".to_string())]),
+            Bson::Decimal128(Decimal128::from_bytes([0; 16])),
+        ];
+
+        for value in values {
+            let violation = find_violation(&server.routers, "users", &user(value.clone()), &[]);
+
+            match violation.unwrap() {
+                Some(Violation::Unindexed {
+                    field,
+                    value: found,
+                }) => {
+                    assert_eq!(field, "email");
+                    assert_eq!(found, value);
+                }
+                _ => panic!("{} was not refused", value),
+            }
+        }
+    }
+
+    #[test]
+    fn duplicates_within_a_write_are_found() {
+        let server = users(&[]);
+
+        let entries = [
+            ("ada".to_string(), user("ada@example.com")),
+            ("grace".to_string(), user("grace@example.com")),
+            ("lovelace".to_string(), user("ada@example.com")),
+        ];
+
+        let (key, duplicate) = find_duplicate_in(&server.routers, "users", &entries)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(key, "lovelace");
+        assert_eq!(duplicate.key, "ada");
+        assert_eq!(duplicate.field, "email");
+
+        let entries = [
+            ("ada".to_string(), user(Bson::Int64(1))),
+            ("grace".to_string(), user(Bson::Double(1.0))),
+        ];
+
+        let found = find_duplicate_in(&server.routers, "users", &entries).unwrap();
+
+        assert_eq!(found.map(|(key, _)| key), Some("grace".to_string()));
+
+        let entries = [
+            ("ada".to_string(), user(Bson::Null)),
+            ("grace".to_string(), user(Bson::Null)),
+            (
+                "hopper".to_string(),
+                Bson::Document(doc! {"name": "Hopper"}),
+            ),
+        ];
+
+        assert!(find_duplicate_in(&server.routers, "users", &entries)
+            .unwrap()
+            .is_none());
+    }
+
+    proptest! {
+        #[test]
+        fn doubles_sort_like_their_encoding(a in number(), b in number()) {
+            prop_assert_eq!(
+                encoded(Bson::Double(a)).cmp(&encoded(Bson::Double(b))),
+                a.partial_cmp(&b).unwrap()
+            );
+        }
+
+        #[test]
+        fn integers_never_sort_against_their_encoding(a in any::<i64>(), b in any::<i64>()) {
+            let (a, b) = (a.min(b), a.max(b));
+
+            prop_assert!(encoded(Bson::Int64(a)) <= encoded(Bson::Int64(b)));
+        }
+
+        #[test]
+        fn strings_sort_like_their_encoding(a in any::<String>(), b in any::<String>()) {
+            prop_assert_eq!(
+                encoded(Bson::String(a.clone())).cmp(&encoded(Bson::String(b.clone()))),
+                a.cmp(&b)
+            );
+        }
+    }
+}
@@ -1,12 +1,15 @@
-use bson::Bson;
+use bson::{Bson, DateTime, Document};
 use dustdata::DustData;
 use dustdata::Error as DustDataError;
 use rand::Rng;
 use rustbase_scram::hash_password;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::ops::Bound;
 use std::sync::{Arc, RwLock};
 
 use crate::config;
+use crate::query;
 use crate::server;
 
 use config::schema;
@@ -15,31 +18,69 @@ use server::route;
 use server::wirewave;
 
 use cache::Cache;
+use query::parser::{path_to_string, Condition, DropPolicy, ListOptions, PathSegment, WatchScope};
+use route::Router;
 use wirewave::authorization::UserPermission;
+use wirewave::mailbox::Mailbox;
 use wirewave::server::Status;
 
+use super::aggregate::Aggregation;
+use super::catalog::{self, CachePolicy, DatabaseOptions};
+use super::changes::{Change, ChangeHub, Operation};
+use super::channels::ChannelHub;
+use super::document;
+use super::expiry;
+use super::filter;
+use super::index::{self, Violation};
+use super::journal;
+use super::transaction::{self, Snapshot};
+use super::validation::Schema;
+
 pub enum TransactionError {
     InternalError(DustDataError),
     ExternalError(Status, String),
 }
 
+/// The keys of a `list` scan, with their values if requested, and whether more keys are left
+pub type ScanPage = (Vec<(String, Option<Bson>)>, bool);
+
+/// A database as listed by `list database`
+pub struct DatabaseInfo {
+    pub name: String,
+    pub keys: usize,
+    /// size of its files on disk, in bytes
+    pub size: u64,
+    /// its indexed fields
+    pub indexes: Vec<String>,
+}
+
 pub struct DustDataInterface {
     cache: Arc<RwLock<Cache>>,
-    routers: Arc<RwLock<HashMap<String, DustData>>>,
+    routers: Arc<RwLock<HashMap<String, Router>>>,
     config: Arc<schema::RustbaseConfig>,
     pub current_database: String,
     system_db: Arc<RwLock<DustData>>,
     current_user: Option<String>,
+    // the permission of `current_user`, as resolved by its session
+    current_permission: Option<UserPermission>,
+    changes: Arc<RwLock<ChangeHub>>,
+    channels: Arc<RwLock<ChannelHub>>,
+    // the changes of a transaction being committed, emitted once it succeeds
+    held_changes: RefCell<Option<Vec<Change>>>,
 }
 
 impl DustDataInterface {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         cache: Arc<RwLock<Cache>>,
-        routers: Arc<RwLock<HashMap<String, DustData>>>,
+        routers: Arc<RwLock<HashMap<String, Router>>>,
         config: Arc<schema::RustbaseConfig>,
         system_db: Arc<RwLock<DustData>>,
         current_database: String,
         current_user: Option<String>,
+        current_permission: Option<UserPermission>,
+        changes: Arc<RwLock<ChangeHub>>,
+        channels: Arc<RwLock<ChannelHub>>,
     ) -> Self {
         Self {
             cache,
@@ -48,23 +89,1653 @@ impl DustDataInterface {
             current_database,
             system_db,
             current_user,
+            current_permission,
+            changes,
+            channels,
+            held_changes: RefCell::new(None),
+        }
+    }
+
+    pub fn insert_into_dustdata(
+        &mut self,
+        key: String,
+        value: Bson,
+        expire: Option<DateTime>,
+    ) -> Result<(), TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Write)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        self.check_writable(&self.current_database, true)?;
+
+        let mut cache = self.cache.write().unwrap();
+        let mut routers = self.routers.write().unwrap();
+
+        self.validate(&self.current_database, &key, &value)?;
+
+        self.create_if_missing(&mut routers, &self.current_database)?;
+        self.purge_expired(&mut routers, &mut cache, &self.current_database, &key)?;
+        self.check_unique(&routers, &self.current_database, &value, &[&key])?;
+
+        let dd = routers.get_mut(&self.current_database).unwrap();
+
+        dd.insert(&key, value.clone())
+            .map_err(TransactionError::InternalError)?;
+
+        if let Some(at) = expire {
+            expiry::set(dd, &key, at).map_err(TransactionError::InternalError)?;
+        }
+
+        self.written(
+            &mut routers,
+            &self.current_database,
+            &key,
+            Operation::Insert,
+            Some(&value),
+        )?;
+
+        Ok(())
+    }
+
+    /// `insert_many_into_dustdata` inserts several keys at once
+    ///
+    /// Nothing is written if one of the keys already exists or is given twice.
+    pub fn insert_many_into_dustdata(
+        &mut self,
+        entries: Vec<(String, Bson)>,
+    ) -> Result<(), TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Write)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        self.check_writable(&self.current_database, true)?;
+
+        let mut cache = self.cache.write().unwrap();
+        let mut routers = self.routers.write().unwrap();
+
+        for (key, value) in &entries {
+            self.validate(&self.current_database, key, value)?;
+        }
+
+        self.create_if_missing(&mut routers, &self.current_database)?;
+
+        for (key, value) in &entries {
+            self.purge_expired(&mut routers, &mut cache, &self.current_database, key)?;
+            self.check_unique(&routers, &self.current_database, value, &[key])?;
+        }
+
+        if let Some((key, duplicate)) =
+            index::find_duplicate_in(&routers, &self.current_database, &entries)
+                .map_err(TransactionError::InternalError)?
+        {
+            let violation = Violation::Duplicate(duplicate);
+
+            return Err(self.unique_violation(&self.current_database, &key, violation));
+        }
+
+        let dd = routers.get_mut(&self.current_database).unwrap();
+
+        for (i, (key, _)) in entries.iter().enumerate() {
+            let repeated = entries[..i].iter().any(|(previous, _)| previous == key);
+
+            if repeated
+                || dd
+                    .get(key)
+                    .map_err(TransactionError::InternalError)?
+                    .is_some()
+            {
+                return Err(TransactionError::ExternalError(
+                    Status::AlreadyExists,
+                    format!("key {} already exists", key),
+                ));
+            }
+        }
+
+        for (key, value) in &entries {
+            dd.insert(key, value.clone())
+                .map_err(TransactionError::InternalError)?;
+        }
+
+        for (key, value) in &entries {
+            self.written(
+                &mut routers,
+                &self.current_database,
+                key,
+                Operation::Insert,
+                Some(value),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn update_dustdata(
+        &mut self,
+        key: String,
+        value: Bson,
+        expire: Option<DateTime>,
+    ) -> Result<(), TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Write)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        self.check_writable(&self.current_database, true)?;
+
+        let mut cache = self.cache.write().unwrap();
+        let cache_key = format!("{}:{}", self.current_database, key);
+        cache.remove(&cache_key).ok();
+
+        let mut routers = self.routers.write().unwrap();
+
+        self.validate(&self.current_database, &key, &value)?;
+
+        self.purge_expired(&mut routers, &mut cache, &self.current_database, &key)?;
+        self.check_unique(&routers, &self.current_database, &value, &[&key])?;
+
+        let dd = routers.get_mut(&self.current_database);
+
+        if let Some(dd) = dd {
+            dd.update(&key, value.clone())
+                .map_err(TransactionError::InternalError)?;
+
+            if let Some(at) = expire {
+                expiry::set(dd, &key, at).map_err(TransactionError::InternalError)?;
+            }
+
+            self.written(
+                &mut routers,
+                &self.current_database,
+                &key,
+                Operation::Update,
+                Some(&value),
+            )?;
+
+            Ok(())
+        } else {
+            Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            ))
+        }
+    }
+
+    /// `upsert_dustdata` inserts a key, or replaces its value if it already exists
+    ///
+    /// Returns:
+    ///
+    /// `true` if the key was inserted, `false` if it was updated.
+    pub fn upsert_dustdata(
+        &mut self,
+        key: String,
+        value: Bson,
+        expire: Option<DateTime>,
+    ) -> Result<bool, TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Write)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        self.check_writable(&self.current_database, true)?;
+
+        let mut cache = self.cache.write().unwrap();
+        let cache_key = format!("{}:{}", self.current_database, key);
+        cache.remove(&cache_key).ok();
+
+        let mut routers = self.routers.write().unwrap();
+
+        self.validate(&self.current_database, &key, &value)?;
+
+        self.create_if_missing(&mut routers, &self.current_database)?;
+        self.purge_expired(&mut routers, &mut cache, &self.current_database, &key)?;
+        self.check_unique(&routers, &self.current_database, &value, &[&key])?;
+
+        let dd = routers.get_mut(&self.current_database).unwrap();
+
+        let inserted = if dd
+            .get(&key)
+            .map_err(TransactionError::InternalError)?
+            .is_some()
+        {
+            dd.update(&key, value.clone())
+                .map_err(TransactionError::InternalError)?;
+
+            false
+        } else {
+            dd.insert(&key, value.clone())
+                .map_err(TransactionError::InternalError)?;
+
+            true
+        };
+
+        if let Some(at) = expire {
+            expiry::set(dd, &key, at).map_err(TransactionError::InternalError)?;
+        }
+
+        let operation = if inserted {
+            Operation::Insert
+        } else {
+            Operation::Update
+        };
+
+        self.written(
+            &mut routers,
+            &self.current_database,
+            &key,
+            operation,
+            Some(&value),
+        )?;
+
+        Ok(inserted)
+    }
+
+    /// `insert_if_absent_dustdata` inserts a key only if it does not exist yet
+    ///
+    /// Returns:
+    ///
+    /// `true` if the key was inserted, `false` if it already existed and nothing was written.
+    pub fn insert_if_absent_dustdata(
+        &mut self,
+        key: String,
+        value: Bson,
+        expire: Option<DateTime>,
+    ) -> Result<bool, TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Write)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        self.check_writable(&self.current_database, true)?;
+
+        let mut cache = self.cache.write().unwrap();
+        let mut routers = self.routers.write().unwrap();
+
+        self.validate(&self.current_database, &key, &value)?;
+
+        self.create_if_missing(&mut routers, &self.current_database)?;
+        self.purge_expired(&mut routers, &mut cache, &self.current_database, &key)?;
+        self.check_unique(&routers, &self.current_database, &value, &[&key])?;
+
+        let dd = routers.get_mut(&self.current_database).unwrap();
+
+        if dd
+            .get(&key)
+            .map_err(TransactionError::InternalError)?
+            .is_some()
+        {
+            return Ok(false);
+        }
+
+        dd.insert(&key, value.clone())
+            .map_err(TransactionError::InternalError)?;
+
+        if let Some(at) = expire {
+            expiry::set(dd, &key, at).map_err(TransactionError::InternalError)?;
+        }
+
+        self.written(
+            &mut routers,
+            &self.current_database,
+            &key,
+            Operation::Insert,
+            Some(&value),
+        )?;
+
+        Ok(true)
+    }
+
+    /// `compare_and_swap_dustdata` replaces the value of a key only if it is equal to the expected one
+    ///
+    /// Values are compared like in `where` conditions, so `1` is equal to `1.0`.
+    pub fn compare_and_swap_dustdata(
+        &mut self,
+        key: String,
+        expected: Bson,
+        value: Bson,
+        expire: Option<DateTime>,
+    ) -> Result<(), TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Write)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        self.check_writable(&self.current_database, true)?;
+
+        let mut cache = self.cache.write().unwrap();
+        let cache_key = format!("{}:{}", self.current_database, key);
+        cache.remove(&cache_key).ok();
+
+        // the value is compared and written under the same lock
+        let mut routers = self.routers.write().unwrap();
+
+        self.validate(&self.current_database, &key, &value)?;
+
+        self.purge_expired(&mut routers, &mut cache, &self.current_database, &key)?;
+        self.check_unique(&routers, &self.current_database, &value, &[&key])?;
+
+        let dd = routers.get_mut(&self.current_database);
+
+        if let Some(dd) = dd {
+            let current = match dd.get(&key).map_err(TransactionError::InternalError)? {
+                Some(current) => current,
+                None => {
+                    return Err(TransactionError::ExternalError(
+                        Status::NotFound,
+                        "key not found".to_string(),
+                    ))
+                }
+            };
+
+            if !filter::equals(&current, &expected) {
+                return Err(TransactionError::ExternalError(
+                    Status::Conflict,
+                    "value is not the expected one".to_string(),
+                ));
+            }
+
+            dd.update(&key, value.clone())
+                .map_err(TransactionError::InternalError)?;
+
+            if let Some(at) = expire {
+                expiry::set(dd, &key, at).map_err(TransactionError::InternalError)?;
+            }
+
+            self.written(
+                &mut routers,
+                &self.current_database,
+                &key,
+                Operation::Update,
+                Some(&value),
+            )?;
+
+            Ok(())
+        } else {
+            Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            ))
+        }
+    }
+
+    pub fn patch_dustdata(
+        &mut self,
+        key: String,
+        patch: Document,
+    ) -> Result<Bson, TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Write)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        self.check_writable(&self.current_database, true)?;
+
+        let mut cache = self.cache.write().unwrap();
+        let cache_key = format!("{}:{}", self.current_database, key);
+        cache.remove(&cache_key).ok();
+
+        // the value is read, patched and written back under the same lock
+        let mut routers = self.routers.write().unwrap();
+
+        self.purge_expired(&mut routers, &mut cache, &self.current_database, &key)?;
+
+        let dd = routers.get_mut(&self.current_database);
+
+        if let Some(dd) = dd {
+            let value = dd.get(&key).map_err(TransactionError::InternalError)?;
+
+            let mut value = match value {
+                Some(value) => value,
+                None => {
+                    return Err(TransactionError::ExternalError(
+                        Status::NotFound,
+                        "key not found".to_string(),
+                    ))
+                }
+            };
+
+            document::apply_patch(&mut value, &patch)?;
+
+            self.validate(&self.current_database, &key, &value)?;
+            self.check_unique(&routers, &self.current_database, &value, &[&key])?;
+
+            let dd = routers.get_mut(&self.current_database).unwrap();
+
+            dd.update(&key, value.clone())
+                .map_err(TransactionError::InternalError)?;
+
+            self.written(
+                &mut routers,
+                &self.current_database,
+                &key,
+                Operation::Update,
+                Some(&value),
+            )?;
+
+            Ok(value)
+        } else {
+            Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            ))
+        }
+    }
+
+    pub fn increment_dustdata(&mut self, key: String, by: Bson) -> Result<Bson, TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Write)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        self.check_writable(&self.current_database, true)?;
+
+        let mut cache = self.cache.write().unwrap();
+        let cache_key = format!("{}:{}", self.current_database, key);
+        cache.remove(&cache_key).ok();
+
+        let mut routers = self.routers.write().unwrap();
+
+        self.create_if_missing(&mut routers, &self.current_database)?;
+        self.purge_expired(&mut routers, &mut cache, &self.current_database, &key)?;
+
+        let dd = routers.get_mut(&self.current_database).unwrap();
+
+        // a missing counter starts at 0
+        match dd.get(&key).map_err(TransactionError::InternalError)? {
+            Some(current) => {
+                let value = document::add(&current, &by)?;
+
+                self.validate(&self.current_database, &key, &value)?;
+
+                dd.update(&key, value.clone())
+                    .map_err(TransactionError::InternalError)?;
+
+                self.written(
+                    &mut routers,
+                    &self.current_database,
+                    &key,
+                    Operation::Update,
+                    Some(&value),
+                )?;
+
+                Ok(value)
+            }
+
+            None => {
+                let value = document::add(&Bson::Int64(0), &by)?;
+
+                self.validate(&self.current_database, &key, &value)?;
+
+                dd.insert(&key, value.clone())
+                    .map_err(TransactionError::InternalError)?;
+
+                self.written(
+                    &mut routers,
+                    &self.current_database,
+                    &key,
+                    Operation::Insert,
+                    Some(&value),
+                )?;
+
+                Ok(value)
+            }
+        }
+    }
+
+    pub fn delete_from_dustdata(&mut self, key: String) -> Result<(), TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Write)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        self.check_writable(&self.current_database, false)?;
+
+        let mut cache = self.cache.write().unwrap();
+        let cache_key = format!("{}:{}", self.current_database, key);
+        cache.remove(&cache_key).ok();
+
+        let mut routers = self.routers.write().unwrap();
+
+        self.purge_expired(&mut routers, &mut cache, &self.current_database, &key)?;
+
+        let dd = routers.get_mut(&self.current_database);
+
+        if let Some(dd) = dd {
+            dd.delete(&key).map_err(TransactionError::InternalError)?;
+            expiry::clear(dd, &key).map_err(TransactionError::InternalError)?;
+
+            self.written(
+                &mut routers,
+                &self.current_database,
+                &key,
+                Operation::Delete,
+                None,
+            )?;
+
+            Ok(())
+        } else {
+            Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            ))
+        }
+    }
+
+    /// `delete_many_from_dustdata` deletes several keys at once
+    ///
+    /// Returns:
+    ///
+    /// The keys that did not exist.
+    pub fn delete_many_from_dustdata(
+        &mut self,
+        keys: Vec<String>,
+    ) -> Result<Vec<String>, TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Write)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        self.check_writable(&self.current_database, false)?;
+
+        let mut cache = self.cache.write().unwrap();
+
+        for key in &keys {
+            let cache_key = format!("{}:{}", self.current_database, key);
+            cache.remove(&cache_key).ok();
+        }
+
+        let mut routers = self.routers.write().unwrap();
+
+        for key in &keys {
+            self.purge_expired(&mut routers, &mut cache, &self.current_database, key)?;
+        }
+
+        let dd = routers.get_mut(&self.current_database);
+
+        if let Some(dd) = dd {
+            let mut missing = Vec::new();
+            let mut deleted = Vec::new();
+
+            for key in keys {
+                if dd
+                    .get(&key)
+                    .map_err(TransactionError::InternalError)?
+                    .is_none()
+                {
+                    missing.push(key);
+                    continue;
+                }
+
+                dd.delete(&key).map_err(TransactionError::InternalError)?;
+                expiry::clear(dd, &key).map_err(TransactionError::InternalError)?;
+
+                deleted.push(key);
+            }
+
+            for key in deleted {
+                self.written(
+                    &mut routers,
+                    &self.current_database,
+                    &key,
+                    Operation::Delete,
+                    None,
+                )?;
+            }
+
+            Ok(missing)
+        } else {
+            Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            ))
+        }
+    }
+
+    /// `transfer_dustdata` writes the value of a key of the current database under another key,
+    /// possibly in another database, as one operation
+    ///
+    /// `rename`, `copy` and `move` are transfers: the target key must not exist, and the source key
+    /// is deleted unless it is kept.
+    ///
+    /// Arguments:
+    ///
+    /// * `key`: The key to transfer.
+    /// * `database`: The database to write it to, created if needed.
+    /// * `new_key`: The key to write it under.
+    /// * `keep`: Whether the source key is kept (a copy) or deleted.
+    pub fn transfer_dustdata(
+        &mut self,
+        key: String,
+        database: String,
+        new_key: String,
+        keep: bool,
+    ) -> Result<(), TransactionError> {
+        check_database_name(&self.current_database)?;
+        check_database_name(&database)?;
+
+        if database == self.current_database && new_key == key {
+            return Err(TransactionError::ExternalError(
+                Status::InvalidQuery,
+                format!("key {} can't be transferred to itself", key),
+            ));
+        }
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Read)?
+                || !self.user_has_perm(current_user.clone(), UserPermission::Write)?
+            {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        if !keep {
+            self.check_writable(&self.current_database, false)?;
+        }
+
+        self.check_writable(&database, database != self.current_database)?;
+
+        let mut cache = self.cache.write().unwrap();
+        let mut routers = self.routers.write().unwrap();
+
+        self.purge_expired(&mut routers, &mut cache, &self.current_database, &key)?;
+
+        let (value, expire) = match routers.get_mut(&self.current_database) {
+            Some(dd) => {
+                let value = dd.get(&key).map_err(TransactionError::InternalError)?;
+                let expire =
+                    expiry::expires_at(dd, &key).map_err(TransactionError::InternalError)?;
+
+                (value, expire)
+            }
+            None => {
+                return Err(TransactionError::ExternalError(
+                    Status::NotFound,
+                    "database not found".to_string(),
+                ))
+            }
+        };
+
+        let value = match value {
+            Some(value) => value,
+            None => {
+                return Err(TransactionError::ExternalError(
+                    Status::NotFound,
+                    format!("key {} not found", key),
+                ))
+            }
+        };
+
+        self.validate(&database, &new_key, &value)?;
+
+        self.create_if_missing(&mut routers, &database)?;
+        self.purge_expired(&mut routers, &mut cache, &database, &new_key)?;
+
+        // a renamed or moved key leaves its value behind
+        let except = if keep || database != self.current_database {
+            vec![new_key.as_str()]
+        } else {
+            vec![new_key.as_str(), key.as_str()]
+        };
+
+        self.check_unique(&routers, &database, &value, &except)?;
+
+        let target = routers.get_mut(&database).unwrap();
+
+        if target
+            .get(&new_key)
+            .map_err(TransactionError::InternalError)?
+            .is_some()
+        {
+            return Err(TransactionError::ExternalError(
+                Status::AlreadyExists,
+                format!("key {} already exists in {}", new_key, database),
+            ));
+        }
+
+        target
+            .insert(&new_key, value.clone())
+            .map_err(TransactionError::InternalError)?;
+
+        // the key keeps its expiry wherever it goes
+        if let Some(at) = expire {
+            expiry::set(target, &new_key, at).map_err(TransactionError::InternalError)?;
+        }
+
+        self.written(
+            &mut routers,
+            &database,
+            &new_key,
+            Operation::Insert,
+            Some(&value),
+        )?;
+
+        cache.remove(&format!("{}:{}", database, new_key)).ok();
+
+        if !keep {
+            let dd = routers.get_mut(&self.current_database).unwrap();
+
+            dd.delete(&key).map_err(TransactionError::InternalError)?;
+            expiry::clear(dd, &key).map_err(TransactionError::InternalError)?;
+
+            self.written(
+                &mut routers,
+                &self.current_database,
+                &key,
+                Operation::Delete,
+                None,
+            )?;
+
+            cache
+                .remove(&format!("{}:{}", self.current_database, key))
+                .ok();
+        }
+
+        Ok(())
+    }
+
+    /// `expire_dustdata` sets when a key expires, or makes it never expire
+    ///
+    /// Arguments:
+    ///
+    /// * `key`: The key.
+    /// * `at`: When the key expires, `None` to make it never expire.
+    ///
+    /// Returns:
+    ///
+    /// Whether the key had an expiry before.
+    pub fn expire_dustdata(
+        &mut self,
+        key: String,
+        at: Option<DateTime>,
+    ) -> Result<bool, TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Write)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        self.check_writable(&self.current_database, false)?;
+
+        let mut cache = self.cache.write().unwrap();
+        let mut routers = self.routers.write().unwrap();
+
+        self.purge_expired(&mut routers, &mut cache, &self.current_database, &key)?;
+
+        let dd = routers.get_mut(&self.current_database);
+
+        if let Some(dd) = dd {
+            if dd
+                .get(&key)
+                .map_err(TransactionError::InternalError)?
+                .is_none()
+            {
+                return Err(TransactionError::ExternalError(
+                    Status::NotFound,
+                    "key not found".to_string(),
+                ));
+            }
+
+            let had_expiry = expiry::clear(dd, &key).map_err(TransactionError::InternalError)?;
+
+            if let Some(at) = at {
+                expiry::set(dd, &key, at).map_err(TransactionError::InternalError)?;
+            }
+
+            Ok(had_expiry)
+        } else {
+            Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            ))
+        }
+    }
+
+    /// `ttl_dustdata` reads when a key expires
+    ///
+    /// Returns:
+    ///
+    /// When the key expires, `None` if it never expires.
+    pub fn ttl_dustdata(&mut self, key: String) -> Result<Option<DateTime>, TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Read)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        let routers = self.routers.read().unwrap();
+        let dd = routers.get(&self.current_database);
+
+        if let Some(dd) = dd {
+            let missing = expiry::is_expired(dd, &key).map_err(TransactionError::InternalError)?
+                || dd
+                    .get(&key)
+                    .map_err(TransactionError::InternalError)?
+                    .is_none();
+
+            if missing {
+                return Err(TransactionError::ExternalError(
+                    Status::NotFound,
+                    "key not found".to_string(),
+                ));
+            }
+
+            expiry::expires_at(dd, &key).map_err(TransactionError::InternalError)
+        } else {
+            Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            ))
+        }
+    }
+
+    /// `snapshot_dustdata` reads a key as a transaction sees it, without checking permissions
+    ///
+    /// Arguments:
+    ///
+    /// * `database`: The database of the key.
+    /// * `key`: The key.
+    ///
+    /// Returns:
+    ///
+    /// The value and expiry of the key, both `None` if it is missing or expired.
+    pub fn snapshot_dustdata(
+        &self,
+        database: &str,
+        key: &str,
+    ) -> Result<Snapshot, TransactionError> {
+        let routers = self.routers.read().unwrap();
+
+        let dd = match routers.get(database) {
+            Some(dd) => dd,
+            None => return Ok((None, None)),
+        };
+
+        if expiry::is_expired(dd, key).map_err(TransactionError::InternalError)? {
+            return Ok((None, None));
+        }
+
+        let value = dd.get(key).map_err(TransactionError::InternalError)?;
+        let expire = expiry::expires_at(dd, key).map_err(TransactionError::InternalError)?;
+
+        Ok((value, expire))
+    }
+
+    /// `restore_dustdata` puts a key back as it was in a snapshot, undoing a failed transaction
+    ///
+    /// Arguments:
+    ///
+    /// * `database`: The database of the key.
+    /// * `key`: The key.
+    /// * `snapshot`: The value and expiry the key had.
+    pub fn restore_dustdata(
+        &mut self,
+        database: &str,
+        key: &str,
+        snapshot: Snapshot,
+    ) -> Result<(), TransactionError> {
+        let mut cache = self.cache.write().unwrap();
+        let mut routers = self.routers.write().unwrap();
+
+        cache.remove(&format!("{}:{}", database, key)).ok();
+
+        // not a change, the watches never saw the writes of the failed transaction
+        transaction::restore(&mut routers, database, key, snapshot)
+            .map_err(TransactionError::InternalError)
+    }
+
+    /// `journal_commit` records the keys of a transaction before it is committed, so a commit
+    /// interrupted by a crash is undone when the server starts again, see [`journal::recover`]
+    pub fn journal_commit(
+        &self,
+        snapshots: &HashMap<(String, String), Snapshot>,
+    ) -> Result<(), TransactionError> {
+        let mut system_db = self.system_db.write().unwrap();
+
+        journal::write(&mut system_db, snapshots).map_err(TransactionError::InternalError)
+    }
+
+    /// `settle_commit` flushes the databases written by a committed, or undone, transaction and
+    /// forgets its journaled keys
+    pub fn settle_commit(
+        &self,
+        snapshots: &HashMap<(String, String), Snapshot>,
+    ) -> Result<(), TransactionError> {
+        let mut routers = self.routers.write().unwrap();
+        let mut system_db = self.system_db.write().unwrap();
+
+        let databases = snapshots.keys().map(|(database, _)| database.as_str());
+
+        journal::settle(&mut routers, &mut system_db, databases)
+            .map_err(TransactionError::InternalError)
+    }
+
+    /// `hold_changes` stops emitting the changes of the next writes until `release_changes`, so
+    /// the watches never see the writes of a transaction that fails
+    pub fn hold_changes(&mut self) {
+        *self.held_changes.borrow_mut() = Some(Vec::new());
+    }
+
+    /// `release_changes` emits the changes held since `hold_changes`
+    ///
+    /// Arguments:
+    ///
+    /// * `emit`: Whether to emit them, `false` to drop them when the writes were undone.
+    pub fn release_changes(&mut self, emit: bool) {
+        let held = self.held_changes.borrow_mut().take();
+
+        if let (Some(held), true) = (held, emit) {
+            let mut changes = self.changes.write().unwrap();
+
+            for change in held {
+                changes.emit(change);
+            }
+        }
+    }
+
+    /// `watch_changes` starts pushing the changes of keys of the current database to a connection
+    ///
+    /// Arguments:
+    ///
+    /// * `scope`: The keys to push the changes of.
+    /// * `after`: The last change the connection has seen, to resume a watch after a reconnect.
+    /// * `mailbox`: The frames pushed to the connection.
+    ///
+    /// Returns:
+    ///
+    /// The id of the watch and the sequence number of the last change.
+    pub fn watch_changes(
+        &mut self,
+        scope: WatchScope,
+        after: Option<u64>,
+        mailbox: &Arc<Mailbox>,
+    ) -> Result<(u64, u64), TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Read)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        self.changes
+            .write()
+            .unwrap()
+            .watch(self.current_database.clone(), scope, after, mailbox)
+    }
+
+    /// `unwatch_changes` stops watches of a connection
+    ///
+    /// Arguments:
+    ///
+    /// * `mailbox`: The frames pushed to the connection.
+    /// * `watch`: The id of the watch to stop, `None` for every watch of the connection.
+    ///
+    /// Returns:
+    ///
+    /// The number of stopped watches.
+    pub fn unwatch_changes(
+        &mut self,
+        mailbox: &Arc<Mailbox>,
+        watch: Option<u64>,
+    ) -> Result<usize, TransactionError> {
+        self.changes.write().unwrap().unwatch(mailbox, watch)
+    }
+
+    /// `publish_message` pushes a message to the subscriptions of a channel
+    ///
+    /// Returns:
+    ///
+    /// The number of subscriptions the message was pushed to.
+    pub fn publish_message(
+        &mut self,
+        channel: &str,
+        message: &Bson,
+    ) -> Result<usize, TransactionError> {
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Write)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        Ok(self.channels.write().unwrap().publish(channel, message))
+    }
+
+    /// `subscribe_channel` starts pushing the messages published on some channels to a connection
+    ///
+    /// Arguments:
+    ///
+    /// * `pattern`: The glob the channels match.
+    /// * `buffer`: The messages kept while the connection is busy, the default if `None`.
+    /// * `drop`: The messages dropped once the buffer is full.
+    /// * `mailbox`: The frames pushed to the connection.
+    ///
+    /// Returns:
+    ///
+    /// The id of the subscription.
+    pub fn subscribe_channel(
+        &mut self,
+        pattern: String,
+        buffer: Option<usize>,
+        drop: DropPolicy,
+        mailbox: &Arc<Mailbox>,
+    ) -> Result<u64, TransactionError> {
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Read)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        self.channels
+            .write()
+            .unwrap()
+            .subscribe(pattern, buffer, drop, mailbox)
+    }
+
+    /// `unsubscribe_channel` stops subscriptions of a connection
+    ///
+    /// Arguments:
+    ///
+    /// * `mailbox`: The frames pushed to the connection.
+    /// * `subscription`: The id of the subscription to stop, `None` for every subscription of the
+    ///   connection.
+    ///
+    /// Returns:
+    ///
+    /// The number of stopped subscriptions.
+    pub fn unsubscribe_channel(
+        &mut self,
+        mailbox: &Arc<Mailbox>,
+        subscription: Option<u64>,
+    ) -> Result<usize, TransactionError> {
+        self.channels
+            .write()
+            .unwrap()
+            .unsubscribe(mailbox, subscription)
+    }
+
+    pub fn get_from_dustdata(&mut self, key: String) -> Result<Bson, TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Read)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        let cacheable = self.options(&self.current_database)?.cache == CachePolicy::ReadThrough;
+
+        let mut cache = self.cache.write().unwrap();
+
+        let cache_key = format!("{}:{}", self.current_database, key);
+
+        let routers = self.routers.read().unwrap();
+        let dd = routers.get(&self.current_database);
+
+        if let Some(dd) = dd {
+            // expired keys stay until they are purged, but can't be read
+            let value = if expiry::is_expired(dd, &key).map_err(TransactionError::InternalError)? {
+                None
+            } else if let Some(bson) = cache.get(&cache_key) {
+                return Ok(bson.clone());
+            } else {
+                dd.get(&key).map_err(TransactionError::InternalError)?
+            };
+
+            if let Some(bson) = value {
+                if cacheable {
+                    cache.insert(cache_key, bson.clone()).unwrap();
+                }
+
+                Ok(bson)
+            } else {
+                Err(TransactionError::ExternalError(
+                    Status::NotFound,
+                    "key not found".to_string(),
+                ))
+            }
+        } else {
+            Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            ))
+        }
+    }
+
+    /// `get_many_from_dustdata` gets several keys at once
+    ///
+    /// Returns:
+    ///
+    /// The values found, keyed by their key, and the keys that were not found.
+    pub fn get_many_from_dustdata(
+        &mut self,
+        keys: Vec<String>,
+    ) -> Result<(Document, Vec<String>), TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Read)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        let cacheable = self.options(&self.current_database)?.cache == CachePolicy::ReadThrough;
+
+        let mut cache = self.cache.write().unwrap();
+
+        let routers = self.routers.read().unwrap();
+        let dd = routers.get(&self.current_database);
+
+        if let Some(dd) = dd {
+            let mut values = Document::new();
+            let mut missing = Vec::new();
+
+            for key in keys {
+                let cache_key = format!("{}:{}", self.current_database, key);
+
+                if expiry::is_expired(dd, &key).map_err(TransactionError::InternalError)? {
+                    missing.push(key);
+                    continue;
+                }
+
+                if let Some(bson) = cache.get(&cache_key) {
+                    values.insert(key, bson.clone());
+                    continue;
+                }
+
+                match dd.get(&key).map_err(TransactionError::InternalError)? {
+                    Some(bson) => {
+                        if cacheable {
+                            cache.insert(cache_key, bson.clone()).unwrap();
+                        }
+
+                        values.insert(key, bson);
+                    }
+                    None => missing.push(key),
+                }
+            }
+
+            Ok((values, missing))
+        } else {
+            Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            ))
+        }
+    }
+
+    /// `is_cached` checks if the value of a key of the current database would be served by the cache
+    pub fn is_cached(&self, key: &str) -> bool {
+        let cache = self.cache.read().unwrap();
+        let cache_key = format!("{}:{}", self.current_database, key);
+
+        cache.get(&cache_key).is_some()
+    }
+
+    pub fn find_in_dustdata(
+        &mut self,
+        condition: &Condition,
+    ) -> Result<Document, TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Read)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        let routers = self.routers.read().unwrap();
+        let dd = routers.get(&self.current_database);
+
+        if let Some(dd) = dd {
+            let keys = self.matching_keys(&routers, dd, Some(condition))?;
+
+            let mut documents = Document::new();
+
+            for key in keys {
+                let value = dd.get(&key).map_err(TransactionError::InternalError)?;
+
+                if let Some(value) = value {
+                    if filter::matches(&value, condition) {
+                        documents.insert(key, value);
+                    }
+                }
+            }
+
+            Ok(documents)
+        } else {
+            Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            ))
+        }
+    }
+
+    pub fn aggregate_dustdata(
+        &mut self,
+        mut aggregation: Aggregation,
+        condition: Option<&Condition>,
+    ) -> Result<Document, TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Read)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        let routers = self.routers.read().unwrap();
+        let dd = routers.get(&self.current_database);
+
+        if let Some(dd) = dd {
+            let keys = self.matching_keys(&routers, dd, condition)?;
+
+            for key in keys {
+                let value = dd.get(&key).map_err(TransactionError::InternalError)?;
+
+                let value = match value {
+                    Some(value) => value,
+                    None => continue,
+                };
+
+                let matches = match condition {
+                    Some(condition) => filter::matches(&value, condition),
+                    None => true,
+                };
+
+                if matches {
+                    aggregation.add(&value)?;
+                }
+            }
+
+            Ok(aggregation.finish())
+        } else {
+            Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            ))
+        }
+    }
+
+    pub fn list_from_dustdata(&mut self) -> Result<Vec<String>, TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Read)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        let routers = self.routers.read().unwrap();
+
+        if let Some(dd) = routers.get(&self.current_database) {
+            expiry::live_keys(dd).map_err(TransactionError::InternalError)
+        } else {
+            Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            ))
+        }
+    }
+
+    /// `scan_dustdata` lists the keys of the current database in sorted order, within the bounds
+    /// of a `list` scan
+    ///
+    /// Returns:
+    ///
+    /// The keys (with their values if requested) and whether more keys are left after the limit.
+    pub fn scan_dustdata(&mut self, options: &ListOptions) -> Result<ScanPage, TransactionError> {
+        check_database_name(&self.current_database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Read)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        let routers = self.routers.read().unwrap();
+        let dd = routers.get(&self.current_database);
+
+        if let Some(dd) = dd {
+            // the scan starts at the highest of its lower bounds, and stops past the prefix
+            let lower = [&options.prefix, &options.from, &options.after]
+                .into_iter()
+                .flatten()
+                .max()
+                .map_or(Bound::Unbounded, |key| Bound::Included(key.as_str()));
+            let upper = options
+                .to
+                .as_deref()
+                .map_or(Bound::Unbounded, Bound::Excluded);
+
+            let prefix = options.prefix.as_deref().unwrap_or("");
+            let keys = dd
+                .range(lower, upper)
+                .take_while(|key| key.starts_with(prefix))
+                .filter(|key| in_scan(key, options));
+
+            let limit = options.limit.unwrap_or(usize::MAX);
+            let mut entries = Vec::new();
+            let mut more = false;
+
+            for key in keys {
+                if expiry::is_expired(dd, key).map_err(TransactionError::InternalError)? {
+                    continue;
+                }
+
+                if entries.len() == limit {
+                    more = true;
+                    break;
+                }
+
+                let value = if options.with_values {
+                    dd.get(key).map_err(TransactionError::InternalError)?
+                } else {
+                    None
+                };
+
+                entries.push((key.clone(), value));
+            }
+
+            Ok((entries, more))
+        } else {
+            Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            ))
+        }
+    }
+
+    pub fn delete_database(&mut self, database: String) -> Result<(), TransactionError> {
+        check_database_name(&database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission.denied".to_string(),
+                ));
+            }
+        }
+
+        let mut routers = self.routers.write().unwrap();
+
+        if let Some(mut dd) = routers.remove(&database) {
+            dd.drop_files();
+            drop(dd);
+
+            let indexes = index::routes(&routers, &database);
+
+            for route in &indexes {
+                if let Some(mut dd) = routers.remove(route) {
+                    dd.drop_files();
+                }
+            }
+
+            let mut system_db = self.system_db.write().unwrap();
+
+            catalog::remove(&mut system_db, &database).map_err(TransactionError::InternalError)?;
+            catalog::remove_schema(&mut system_db, &database)
+                .map_err(TransactionError::InternalError)?;
+
+            drop(system_db);
+
+            let database = database.clone();
+
+            // using thread to delete database because it's a blocking operation
+            let c_db = database.clone();
+            let c_path = self.config.storage.path.clone();
+            std::thread::spawn(move || {
+                route::remove_dustdata(&c_path, c_db);
+
+                for route in indexes {
+                    route::remove_dustdata(&c_path, route);
+                }
+            });
+
+            println!("[Engine] database {} deleted", database);
+
+            Ok(())
+        } else {
+            Err(TransactionError::ExternalError(
+                Status::NotFound,
+                "database not found".to_string(),
+            ))
+        }
+    }
+
+    /// `create_database` creates a database and records its options in the catalog
+    ///
+    /// Arguments:
+    ///
+    /// * `database`: The name of the database.
+    /// * `options`: The options of the database.
+    pub fn create_database(
+        &mut self,
+        database: String,
+        options: DatabaseOptions,
+    ) -> Result<(), TransactionError> {
+        check_database_name(&database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        let mut routers = self.routers.write().unwrap();
+
+        if routers.contains_key(&database) {
+            return Err(TransactionError::ExternalError(
+                Status::AlreadyExists,
+                format!("database {} already exists", database),
+            ));
+        }
+
+        let dd = route::create_dustdata_with(&self.config, &database, &options)
+            .map_err(TransactionError::InternalError)?;
+
+        catalog::insert(&mut self.system_db.write().unwrap(), &database, &options)
+            .map_err(TransactionError::InternalError)?;
+
+        routers.insert(database.clone(), dd);
+        println!("[Engine] created database {}", database);
+
+        Ok(())
+    }
+
+    /// `create_schema` makes the values written to a database be validated against a schema
+    ///
+    /// Values already in the database are not validated.
+    ///
+    /// Arguments:
+    ///
+    /// * `database`: The name of the database, created if needed.
+    /// * `schema`: The schema.
+    pub fn create_schema(
+        &mut self,
+        database: String,
+        schema: Schema,
+    ) -> Result<(), TransactionError> {
+        check_database_name(&database)?;
+
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        let mut routers = self.routers.write().unwrap();
+
+        self.create_if_missing(&mut routers, &database)?;
+
+        let mut system_db = self.system_db.write().unwrap();
+
+        if catalog::get_schema(&system_db, &database)
+            .map_err(TransactionError::InternalError)?
+            .is_some()
+        {
+            return Err(TransactionError::ExternalError(
+                Status::AlreadyExists,
+                format!("database {} already has a schema", database),
+            ));
+        }
+
+        catalog::insert_schema(&mut system_db, &database, &schema)
+            .map_err(TransactionError::InternalError)
+    }
+
+    /// `delete_schema` stops validating the values written to a database
+    pub fn delete_schema(&mut self, database: String) -> Result<(), TransactionError> {
+        if let Some(current_user) = &self.current_user {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
+                return Err(TransactionError::ExternalError(
+                    Status::NotAuthorized,
+                    "permission denied".to_string(),
+                ));
+            }
+        }
+
+        let removed = catalog::remove_schema(&mut self.system_db.write().unwrap(), &database)
+            .map_err(TransactionError::InternalError)?;
+
+        if !removed {
+            return Err(TransactionError::ExternalError(
+                Status::NotFound,
+                format!("database {} has no schema", database),
+            ));
         }
+
+        Ok(())
     }
 
-    pub fn insert_into_dustdata(
+    /// `create_index` indexes a field of the values of a database, so `where` conditions on it
+    /// don't scan the whole database
+    ///
+    /// The values already in the database are indexed right away. A unique index is not created
+    /// if two of them are the same.
+    ///
+    /// Arguments:
+    ///
+    /// * `database`: The name of the database, created if needed.
+    /// * `field`: The field to index.
+    /// * `unique`: Whether two keys can't have the same value in the field.
+    pub fn create_index(
         &mut self,
-        key: String,
-        value: Bson,
+        database: String,
+        field: Vec<PathSegment>,
+        unique: bool,
     ) -> Result<(), TransactionError> {
-        if self.current_database == "_default" {
-            return Err(TransactionError::ExternalError(
-                Status::Reserved,
-                "database reserved".to_string(),
-            ));
-        }
+        check_database_name(&database)?;
 
         if let Some(current_user) = &self.current_user {
-            if !self.user_has_perm(current_user.clone(), UserPermission::Write)? {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
                 return Err(TransactionError::ExternalError(
                     Status::NotAuthorized,
                     "permission denied".to_string(),
@@ -72,31 +1743,79 @@ impl DustDataInterface {
             }
         }
 
-        let mut routers = self.routers.write().unwrap();
-
-        if !routers.contains_key(&self.current_database) {
-            let dd = route::create_dustdata(&self.config, Some(&self.current_database));
+        // the field is part of the name of the directory the index is stored in
+        let valid = field.iter().all(|segment| match segment {
+            PathSegment::Field(name) => name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            PathSegment::Index(_) => true,
+        });
 
-            routers.insert(self.current_database.clone(), dd);
-            println!("[Engine] created database {}", self.current_database);
+        if !valid {
+            return Err(TransactionError::ExternalError(
+                Status::InvalidQuery,
+                format!(
+                    "can't index {}, only fields made of letters, digits and _ can be indexed",
+                    path_to_string(&field)
+                ),
+            ));
         }
 
-        let dd = routers.get_mut(&self.current_database).unwrap();
+        let mut routers = self.routers.write().unwrap();
 
-        dd.insert(&key, value)
-            .map_err(TransactionError::InternalError)
-    }
+        self.create_if_missing(&mut routers, &database)?;
+
+        let route = index::route(&database, &field);
 
-    pub fn update_dustdata(&mut self, key: String, value: Bson) -> Result<(), TransactionError> {
-        if self.current_database == "_default" {
+        if routers.contains_key(&route) {
             return Err(TransactionError::ExternalError(
-                Status::Reserved,
-                "database reserved".to_string(),
+                Status::AlreadyExists,
+                format!(
+                    "{} of {} is already indexed",
+                    path_to_string(&field),
+                    database
+                ),
             ));
         }
 
+        let mut dd = route::create_dustdata(&self.config, Some(&route))
+            .map_err(TransactionError::InternalError)?;
+
+        if unique {
+            index::mark_unique(&mut dd).map_err(TransactionError::InternalError)?;
+        }
+
+        routers.insert(route.clone(), dd);
+
+        let violation = index::build(&mut routers, &database, &field)
+            .map_err(TransactionError::InternalError)?;
+
+        if let Some((key, violation)) = violation {
+            let mut dd = routers.remove(&route).unwrap();
+
+            dd.drop_files();
+            drop(dd);
+
+            route::remove_dustdata(&self.config.storage.path, route);
+
+            return Err(self.unique_violation(&database, &key, violation));
+        }
+
+        println!(
+            "[Engine] indexed {} of {}",
+            path_to_string(&field),
+            database
+        );
+
+        Ok(())
+    }
+
+    /// `drop_index` removes the index of a field of a database
+    pub fn drop_index(
+        &mut self,
+        database: String,
+        field: Vec<PathSegment>,
+    ) -> Result<(), TransactionError> {
         if let Some(current_user) = &self.current_user {
-            if !self.user_has_perm(current_user.clone(), UserPermission::Write)? {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
                 return Err(TransactionError::ExternalError(
                     Status::NotAuthorized,
                     "permission denied".to_string(),
@@ -104,34 +1823,37 @@ impl DustDataInterface {
             }
         }
 
-        let mut cache = self.cache.write().unwrap();
-        let cache_key = format!("{}:{}", self.current_database, key);
-        cache.remove(&cache_key).ok();
-
         let mut routers = self.routers.write().unwrap();
-        let dd = routers.get_mut(&self.current_database);
+        let route = index::route(&database, &field);
 
-        if let Some(dd) = dd {
-            dd.update(&key, value)
-                .map_err(TransactionError::InternalError)
-        } else {
-            Err(TransactionError::ExternalError(
-                Status::NotFound,
-                "database not found".to_string(),
-            ))
-        }
-    }
+        let mut dd = match routers.remove(&route) {
+            Some(dd) => dd,
+            None => {
+                return Err(TransactionError::ExternalError(
+                    Status::NotFound,
+                    format!("{} of {} is not indexed", path_to_string(&field), database),
+                ))
+            }
+        };
 
-    pub fn delete_from_dustdata(&mut self, key: String) -> Result<(), TransactionError> {
-        if self.current_database == "_default" {
-            return Err(TransactionError::ExternalError(
-                Status::Reserved,
-                "database reserved".to_string(),
-            ));
-        }
+        dd.drop_files();
+        drop(dd);
+
+        // removed before the lock is released, so an index created again right away can't lose
+        // its files
+        route::remove_dustdata(&self.config.storage.path, route);
+
+        Ok(())
+    }
 
+    /// `rebuild_index` indexes again, from scratch, the values of a database
+    pub fn rebuild_index(
+        &mut self,
+        database: String,
+        field: Vec<PathSegment>,
+    ) -> Result<(), TransactionError> {
         if let Some(current_user) = &self.current_user {
-            if !self.user_has_perm(current_user.clone(), UserPermission::Write)? {
+            if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
                 return Err(TransactionError::ExternalError(
                     Status::NotAuthorized,
                     "permission denied".to_string(),
@@ -139,31 +1861,37 @@ impl DustDataInterface {
             }
         }
 
-        let mut cache = self.cache.write().unwrap();
-        let cache_key = format!("{}:{}", self.current_database, key);
-        cache.remove(&cache_key).ok();
-
         let mut routers = self.routers.write().unwrap();
-        let dd = routers.get_mut(&self.current_database);
 
-        if let Some(dd) = dd {
-            dd.delete(&key).map_err(TransactionError::InternalError)
-        } else {
-            Err(TransactionError::ExternalError(
+        if !routers.contains_key(&index::route(&database, &field)) {
+            return Err(TransactionError::ExternalError(
                 Status::NotFound,
-                "database not found".to_string(),
-            ))
+                format!("{} of {} is not indexed", path_to_string(&field), database),
+            ));
         }
+
+        index::rebuild(&mut routers, &database, &field).map_err(TransactionError::InternalError)
     }
 
-    pub fn get_from_dustdata(&mut self, key: String) -> Result<Bson, TransactionError> {
-        if self.current_database == "_default" {
-            return Err(TransactionError::ExternalError(
-                Status::Reserved,
-                "database reserved".to_string(),
-            ));
-        }
+    /// `indexed_fields` tells which indexes of the current database a `where` condition would use
+    ///
+    /// Returns:
+    ///
+    /// The indexed fields, empty if the condition needs a full scan.
+    pub fn indexed_fields(&self, condition: &Condition) -> Vec<String> {
+        let routers = self.routers.read().unwrap();
+
+        index::explain(&routers, &self.current_database, condition)
+    }
 
+    /// `select_database` checks that the current user can run statements on a database, selected
+    /// with `use` or sent with a request
+    ///
+    /// Arguments:
+    ///
+    /// * `database`: The name of the database. It must exist, unless the server creates databases
+    ///   on their first write.
+    pub fn select_database(&self, database: &str) -> Result<(), TransactionError> {
         if let Some(current_user) = &self.current_user {
             if !self.user_has_perm(current_user.clone(), UserPermission::Read)? {
                 return Err(TransactionError::ExternalError(
@@ -173,46 +1901,29 @@ impl DustDataInterface {
             }
         }
 
-        let mut cache = self.cache.write().unwrap();
-
-        let cache_key = format!("{}:{}", self.current_database, key);
-
-        if let Some(bson) = cache.get(&cache_key) {
-            return Ok(bson.clone());
-        }
-
-        let routers = self.routers.read().unwrap();
-        let dd = routers.get(&self.current_database);
-
-        if let Some(dd) = dd {
-            let value = dd.get(&key).map_err(TransactionError::InternalError)?;
-
-            if let Some(bson) = value {
-                cache.insert(cache_key, bson.clone()).unwrap();
+        check_database_name(database)?;
 
-                Ok(bson)
-            } else {
-                Err(TransactionError::ExternalError(
-                    Status::NotFound,
-                    "key not found".to_string(),
-                ))
-            }
-        } else {
-            Err(TransactionError::ExternalError(
-                Status::NotFound,
-                "database not found".to_string(),
-            ))
-        }
-    }
+        let exists = self.routers.read().unwrap().contains_key(database);
 
-    pub fn list_from_dustdata(&mut self) -> Result<Vec<String>, TransactionError> {
-        if self.current_database == "_default" {
+        if !exists && !self.config.storage.implicit_create.unwrap_or(true) {
             return Err(TransactionError::ExternalError(
-                Status::Reserved,
-                "database reserved".to_string(),
+                Status::NotFound,
+                format!(
+                    "database {} not found, create it with `create database {}`",
+                    database, database
+                ),
             ));
         }
 
+        Ok(())
+    }
+
+    /// `list_databases` lists the databases of the server, the system database excluded
+    ///
+    /// Returns:
+    ///
+    /// The databases sorted by name, with their number of keys and size on disk.
+    pub fn list_databases(&self) -> Result<Vec<DatabaseInfo>, TransactionError> {
         if let Some(current_user) = &self.current_user {
             if !self.user_has_perm(current_user.clone(), UserPermission::Read)? {
                 return Err(TransactionError::ExternalError(
@@ -223,52 +1934,70 @@ impl DustDataInterface {
         }
 
         let routers = self.routers.read().unwrap();
-        let dd = routers.get(&self.current_database).unwrap();
 
-        dd.list_keys().map_err(TransactionError::InternalError)
-    }
+        let mut databases = Vec::new();
 
-    pub fn delete_database(&mut self, database: String) -> Result<(), TransactionError> {
-        if self.current_database == "_default" {
-            return Err(TransactionError::ExternalError(
-                Status::Reserved,
-                "database reserved".to_string(),
-            ));
+        for (name, dd) in routers.iter() {
+            if name.starts_with('_') {
+                continue;
+            }
+
+            let keys = expiry::live_keys(dd).map_err(TransactionError::InternalError)?;
+
+            databases.push(DatabaseInfo {
+                name: name.clone(),
+                keys: keys.len(),
+                size: route::dustdata_size(&self.config.storage.path, name),
+                indexes: index::fields(&routers, name),
+            });
         }
 
+        databases.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(databases)
+    }
+
+    /// `list_users` lists the users of the server, without their passwords
+    ///
+    /// Returns:
+    ///
+    /// The names of the users sorted by name, with their permission.
+    pub fn list_users(&self) -> Result<Vec<(String, UserPermission)>, TransactionError> {
         if let Some(current_user) = &self.current_user {
             if !self.user_has_perm(current_user.clone(), UserPermission::Admin)? {
                 return Err(TransactionError::ExternalError(
                     Status::NotAuthorized,
-                    "permission.denied".to_string(),
+                    "permission denied".to_string(),
                 ));
             }
         }
 
-        let mut routers = self.routers.write().unwrap();
+        let dd = self.system_db.read().unwrap();
 
-        if let Some(mut dd) = routers.remove(&database) {
-            dd.lsm.drop();
-            drop(dd);
+        let mut usernames = dd.list_keys().map_err(TransactionError::InternalError)?;
+        usernames.sort();
 
-            let database = database.clone();
+        let mut users = Vec::new();
 
-            // using thread to delete database because it's a blocking operation
-            let c_db = database.clone();
-            let c_path = self.config.storage.path.clone();
-            std::thread::spawn(move || {
-                route::remove_dustdata(&c_path, c_db);
-            });
+        for username in usernames {
+            if catalog::is_reserved(&username) {
+                continue;
+            }
 
-            println!("[Engine] database {} deleted", database);
+            let user = dd.get(&username).map_err(TransactionError::InternalError)?;
 
-            Ok(())
-        } else {
-            Err(TransactionError::ExternalError(
-                Status::NotFound,
-                "database not found".to_string(),
-            ))
+            let permission = user
+                .as_ref()
+                .and_then(|user| user.as_document())
+                .and_then(|user| user.get_i32("permission").ok())
+                .and_then(|permission| UserPermission::from_i32(permission).ok());
+
+            if let Some(permission) = permission {
+                users.push((username, permission));
+            }
         }
+
+        Ok(users)
     }
 
     pub fn create_user(
@@ -390,11 +2119,231 @@ impl DustDataInterface {
             .map_err(TransactionError::InternalError)
     }
 
+    // databases written to before being created are created with the default options, unless the
+    // server requires `create database`
+    fn create_if_missing(
+        &self,
+        routers: &mut HashMap<String, Router>,
+        database: &str,
+    ) -> Result<(), TransactionError> {
+        check_database_name(database)?;
+
+        if routers.contains_key(database) {
+            return Ok(());
+        }
+
+        if !self.config.storage.implicit_create.unwrap_or(true) {
+            return Err(TransactionError::ExternalError(
+                Status::NotFound,
+                format!(
+                    "database {} not found, create it with `create database {}`",
+                    database, database
+                ),
+            ));
+        }
+
+        let dd = route::create_dustdata(&self.config, Some(database))
+            .map_err(TransactionError::InternalError)?;
+
+        routers.insert(database.to_string(), dd);
+        println!("[Engine] created database {}", database);
+
+        Ok(())
+    }
+
+    // keeps the indexes of a written key up to date and pushes the change to its watches,
+    // called with the routers still locked so changes are numbered in the order of the writes
+    fn written(
+        &self,
+        routers: &mut HashMap<String, Router>,
+        database: &str,
+        key: &str,
+        operation: Operation,
+        value: Option<&Bson>,
+    ) -> Result<(), TransactionError> {
+        index::reindex(routers, database, key, value).map_err(TransactionError::InternalError)?;
+
+        let change = Change::new(database, key, operation, value.cloned());
+
+        match self.held_changes.borrow_mut().as_mut() {
+            Some(held) => held.push(change),
+            None => self.changes.write().unwrap().emit(change),
+        }
+
+        Ok(())
+    }
+
+    // purges a key that has expired but was not reaped yet, so the write sees it as missing,
+    // and tells its indexes and watches as the reaper would
+    fn purge_expired(
+        &self,
+        routers: &mut HashMap<String, Router>,
+        cache: &mut Cache,
+        database: &str,
+        key: &str,
+    ) -> Result<(), TransactionError> {
+        let purged = match routers.get_mut(database) {
+            Some(dd) => {
+                expiry::purge(dd, cache, database, key).map_err(TransactionError::InternalError)?
+            }
+            None => false,
+        };
+
+        if purged {
+            self.written(routers, database, key, Operation::Expire, None)?;
+        }
+
+        Ok(())
+    }
+
+    // the options of a database, the defaults if it was created implicitly
+    fn options(&self, database: &str) -> Result<DatabaseOptions, TransactionError> {
+        let dd = self.system_db.read().unwrap();
+
+        let options = catalog::get(&dd, database).map_err(TransactionError::InternalError)?;
+
+        Ok(options.unwrap_or_default())
+    }
+
+    // the live keys of a database that can match a condition, sorted, found with an index when
+    // one can narrow the condition down, so they must still be checked against the condition
+    fn matching_keys(
+        &self,
+        routers: &HashMap<String, Router>,
+        dd: &Router,
+        condition: Option<&Condition>,
+    ) -> Result<Vec<String>, TransactionError> {
+        let candidates = match condition {
+            Some(condition) => index::candidates(routers, &self.current_database, condition)
+                .map_err(TransactionError::InternalError)?,
+            None => None,
+        };
+
+        let candidates = match candidates {
+            Some(candidates) => candidates,
+            None => {
+                let mut keys = expiry::live_keys(dd).map_err(TransactionError::InternalError)?;
+                keys.sort();
+
+                return Ok(keys);
+            }
+        };
+
+        let mut keys = Vec::new();
+
+        for key in candidates {
+            if !expiry::is_expired(dd, &key).map_err(TransactionError::InternalError)? {
+                keys.push(key);
+            }
+        }
+
+        Ok(keys)
+    }
+
+    // values written to a database with a schema must match it, checked with the routers locked
+    // like `create_schema` does, so a write can't miss a schema created after it was validated
+    fn validate(&self, database: &str, key: &str, value: &Bson) -> Result<(), TransactionError> {
+        let dd = self.system_db.read().unwrap();
+
+        let schema = match catalog::get_schema(&dd, database) {
+            Ok(Some(schema)) => schema,
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(TransactionError::InternalError(e)),
+        };
+
+        let violations = schema.validate(key, value);
+
+        if violations.is_empty() {
+            return Ok(());
+        }
+
+        Err(TransactionError::ExternalError(
+            Status::SchemaViolation,
+            format!(
+                "value does not match the schema of {}: {}",
+                database,
+                violations.join("; ")
+            ),
+        ))
+    }
+
+    // values written to a uniquely indexed field can't be the value of another key
+    fn check_unique(
+        &self,
+        routers: &HashMap<String, Router>,
+        database: &str,
+        value: &Bson,
+        except: &[&str],
+    ) -> Result<(), TransactionError> {
+        let violation = index::find_violation(routers, database, value, except)
+            .map_err(TransactionError::InternalError)?;
+
+        match violation {
+            Some(violation) => Err(self.unique_violation(database, except[0], violation)),
+            None => Ok(()),
+        }
+    }
+
+    fn unique_violation(
+        &self,
+        database: &str,
+        key: &str,
+        violation: Violation,
+    ) -> TransactionError {
+        let message = match violation {
+            Violation::Duplicate(duplicate) => format!(
+                "{} of {} must be unique in {}, key {} already has {}",
+                duplicate.field, key, database, duplicate.key, duplicate.value
+            ),
+            Violation::Unindexed { field, value } => format!(
+                "{} of {} has a unique index in {} and can't be {}, only null, numbers, strings, \
+                object ids, booleans and dates are indexed",
+                field, key, database, value
+            ),
+        };
+
+        TransactionError::ExternalError(Status::ConstraintViolation, message)
+    }
+
+    // read-only databases refuse every write, full databases refuse the writes that can grow them
+    fn check_writable(&self, database: &str, grows: bool) -> Result<(), TransactionError> {
+        let options = self.options(database)?;
+
+        if options.read_only {
+            return Err(TransactionError::ExternalError(
+                Status::ReadOnly,
+                format!("database {} is read-only", database),
+            ));
+        }
+
+        if let Some(max_size) = options.max_size {
+            let size = route::dustdata_size(&self.config.storage.path, database);
+
+            if grows && size >= max_size {
+                return Err(TransactionError::ExternalError(
+                    Status::QuotaExceeded,
+                    format!(
+                        "database {} is full ({} of {} bytes)",
+                        database, size, max_size
+                    ),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn user_has_perm(
         &self,
         username: String,
         perm: UserPermission,
     ) -> Result<bool, TransactionError> {
+        if let Some(permission) = &self.current_permission {
+            if self.current_user.as_ref() == Some(&username) {
+                return Ok(permission.cmp(&perm));
+            }
+        }
+
         let dd = self.system_db.read().unwrap();
 
         let user = dd.get(&username).map_err(TransactionError::InternalError)?;
@@ -415,3 +2364,52 @@ impl DustDataInterface {
         Ok(user_permission.cmp(&perm))
     }
 }
+
+// the system database and the indexes are routes too, named `_default` and
+// `_index.<database>.<field>`, so databases can't start with `_` nor hold a dot, or the indexes of
+// `a.b` would be taken for indexes of `a`
+fn check_database_name(database: &str) -> Result<(), TransactionError> {
+    if database.starts_with('_') {
+        return Err(TransactionError::ExternalError(
+            Status::Reserved,
+            "database reserved".to_string(),
+        ));
+    }
+
+    if database.contains('.') {
+        return Err(TransactionError::ExternalError(
+            Status::InvalidQuery,
+            format!("invalid database name {}, it can't contain a dot", database),
+        ));
+    }
+
+    Ok(())
+}
+
+fn in_scan(key: &str, options: &ListOptions) -> bool {
+    if let Some(prefix) = &options.prefix {
+        if !key.starts_with(prefix.as_str()) {
+            return false;
+        }
+    }
+
+    if let Some(from) = &options.from {
+        if key < from.as_str() {
+            return false;
+        }
+    }
+
+    if let Some(to) = &options.to {
+        if key >= to.as_str() {
+            return false;
+        }
+    }
+
+    if let Some(after) = &options.after {
+        if key <= after.as_str() {
+            return false;
+        }
+    }
+
+    true
+}
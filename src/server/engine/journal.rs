@@ -0,0 +1,260 @@
+use bson::{Bson, Document};
+use dustdata::DustData;
+use dustdata::Error as DustDataError;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::server::route::Router;
+
+use super::index;
+use super::transaction::{self, Snapshot};
+
+// the snapshots of the keys of the transaction being committed, kept in the system database under
+// a key no user can be named until the writes of the commit are flushed
+const JOURNAL_KEY: &str = "_journal";
+
+/// `write` records the keys a commit is about to write as they are before it, and flushes them
+/// to disk before the first write
+///
+/// Arguments:
+///
+/// * `system_db`: The system database.
+/// * `snapshots`: The keys of the transaction, keyed by (database, key).
+pub fn write(
+    system_db: &mut DustData,
+    snapshots: &HashMap<(String, String), Snapshot>,
+) -> Result<(), DustDataError> {
+    let entries = snapshots
+        .iter()
+        .map(|((database, key), snapshot)| Bson::Document(write_entry(database, key, snapshot)))
+        .collect::<Vec<_>>();
+
+    if system_db.get(JOURNAL_KEY)?.is_some() {
+        system_db.update(JOURNAL_KEY, Bson::Array(entries))?;
+    } else {
+        system_db.insert(JOURNAL_KEY, Bson::Array(entries))?;
+    }
+
+    system_db.flush()
+}
+
+/// `settle` flushes the databases written by a commit, then forgets its keys
+///
+/// Arguments:
+///
+/// * `routers`: The databases and indexes of the server.
+/// * `system_db`: The system database.
+/// * `databases`: The databases the commit wrote to.
+pub fn settle<'a>(
+    routers: &mut HashMap<String, Router>,
+    system_db: &mut DustData,
+    databases: impl IntoIterator<Item = &'a str>,
+) -> Result<(), DustDataError> {
+    for database in databases.into_iter().collect::<BTreeSet<_>>() {
+        let mut routes = index::routes(routers, database);
+        routes.push(database.to_string());
+
+        for route in routes {
+            if let Some(dd) = routers.get_mut(&route) {
+                dd.flush()?;
+            }
+        }
+    }
+
+    if system_db.get(JOURNAL_KEY)?.is_some() {
+        system_db.delete(JOURNAL_KEY)?;
+        system_db.flush()?;
+    }
+
+    Ok(())
+}
+
+/// `recover` undoes a commit interrupted before its writes were flushed, when the server starts
+///
+/// Returns:
+///
+/// The number of keys put back as they were before the commit, 0 if no commit was interrupted.
+pub fn recover(
+    routers: &mut HashMap<String, Router>,
+    system_db: &mut DustData,
+) -> Result<usize, DustDataError> {
+    let entries = match system_db.get(JOURNAL_KEY)? {
+        Some(Bson::Array(entries)) => entries,
+        _ => return Ok(0),
+    };
+
+    let entries = entries
+        .iter()
+        .filter_map(Bson::as_document)
+        .filter_map(read_entry)
+        .collect::<Vec<_>>();
+
+    for (database, key, snapshot) in &entries {
+        transaction::restore(routers, database, key, snapshot.clone())?;
+    }
+
+    let databases = entries.iter().map(|(database, _, _)| database.as_str());
+
+    settle(routers, system_db, databases)?;
+
+    Ok(entries.len())
+}
+
+fn write_entry(database: &str, key: &str, snapshot: &Snapshot) -> Document {
+    let (value, expire) = snapshot;
+    let mut entry = bson::doc! { "database": database, "key": key };
+
+    // a missing value is a key the transaction may create, and deletes when undone
+    if let Some(value) = value {
+        entry.insert("value", value.clone());
+    }
+
+    if let Some(expire) = expire {
+        entry.insert("expire", *expire);
+    }
+
+    entry
+}
+
+fn read_entry(entry: &Document) -> Option<(String, String, Snapshot)> {
+    let database = entry.get_str("database").ok()?.to_string();
+    let key = entry.get_str("key").ok()?.to_string();
+
+    let value = entry.get("value").cloned();
+    let expire = entry.get_datetime("expire").ok().copied();
+
+    Some((database, key, (value, expire)))
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{doc, Bson, DateTime};
+    use dustdata::{DustData, DustDataConfig, LsmConfig, Size};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::server::engine::expiry;
+    use crate::server::route::Router;
+
+    use super::{read_entry, recover, write, write_entry};
+
+    // a system database and a `shop` database, in a directory removed once the test is done
+    struct Server {
+        path: PathBuf,
+        system_db: DustData,
+        routers: HashMap<String, Router>,
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            self.routers.clear();
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn open(path: PathBuf) -> DustData {
+        dustdata::initialize(DustDataConfig {
+            path,
+            lsm_config: LsmConfig {
+                flush_threshold: Size::Bytes(1024 * 1024),
+            },
+        })
+    }
+
+    fn server(name: &str) -> Server {
+        let path =
+            std::env::temp_dir().join(format!("rustbase-journal-{}-{}", std::process::id(), name));
+
+        let shop = Router::new(open(path.join("shop"))).unwrap();
+
+        Server {
+            system_db: open(path.join("_default")),
+            routers: HashMap::from([("shop".to_string(), shop)]),
+            path,
+        }
+    }
+
+    fn round_trip(value: Option<Bson>, expire: Option<DateTime>) {
+        let entry = write_entry("shop", "cart_1", &(value.clone(), expire));
+
+        assert_eq!(
+            read_entry(&entry),
+            Some(("shop".to_string(), "cart_1".to_string(), (value, expire)))
+        );
+    }
+
+    #[test]
+    fn entries_read_back_as_written() {
+        let at = DateTime::from_millis(1_700_000_000_000);
+
+        round_trip(Some(Bson::Document(doc! {"items": [1, 2]})), Some(at));
+        round_trip(Some(Bson::Int64(4)), None);
+        round_trip(None, Some(at));
+        round_trip(None, None);
+    }
+
+    #[test]
+    fn null_values_are_not_missing_values() {
+        round_trip(Some(Bson::Null), None);
+    }
+
+    #[test]
+    fn malformed_entries_are_skipped() {
+        assert_eq!(read_entry(&doc! {"key": "cart_1"}), None);
+        assert_eq!(read_entry(&doc! {"database": "shop"}), None);
+        assert_eq!(read_entry(&doc! {"database": 1, "key": "cart_1"}), None);
+    }
+
+    #[test]
+    fn interrupted_commits_are_undone() {
+        let mut server = server("interrupted");
+        let at = DateTime::from_millis(4_000_000_000_000);
+
+        let shop = server.routers.get_mut("shop").unwrap();
+        shop.insert("cart_1", Bson::Int64(1)).unwrap();
+        expiry::set(shop, "cart_1", at).unwrap();
+
+        let snapshots = HashMap::from([
+            (
+                ("shop".to_string(), "cart_1".to_string()),
+                (Some(Bson::Int64(1)), Some(at)),
+            ),
+            (("shop".to_string(), "cart_2".to_string()), (None, None)),
+        ]);
+
+        write(&mut server.system_db, &snapshots).unwrap();
+
+        // the commit wrote its keys, but stopped before flushing them
+        let shop = server.routers.get_mut("shop").unwrap();
+        shop.update("cart_1", Bson::Int64(2)).unwrap();
+        expiry::clear(shop, "cart_1").unwrap();
+        shop.insert("cart_2", Bson::Int64(5)).unwrap();
+
+        let recovered = recover(&mut server.routers, &mut server.system_db).unwrap();
+        assert_eq!(recovered, 2);
+
+        let shop = &server.routers["shop"];
+        assert_eq!(shop.get("cart_1").unwrap(), Some(Bson::Int64(1)));
+        assert_eq!(expiry::expires_at(shop, "cart_1").unwrap(), Some(at));
+        assert_eq!(shop.get("cart_2").unwrap(), None);
+
+        // the journal is forgotten once the keys are back
+        let recovered = recover(&mut server.routers, &mut server.system_db).unwrap();
+        assert_eq!(recovered, 0);
+    }
+
+    #[test]
+    fn nothing_is_undone_without_a_journal() {
+        let mut server = server("empty");
+
+        let shop = server.routers.get_mut("shop").unwrap();
+        shop.insert("cart_1", Bson::Int64(1)).unwrap();
+
+        let recovered = recover(&mut server.routers, &mut server.system_db).unwrap();
+        assert_eq!(recovered, 0);
+        assert_eq!(
+            server.routers["shop"].get("cart_1").unwrap(),
+            Some(Bson::Int64(1))
+        );
+    }
+}
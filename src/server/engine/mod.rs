@@ -1,2 +1,13 @@
+mod aggregate;
+pub mod catalog;
+pub mod changes;
+pub mod channels;
 pub mod core;
+mod document;
+pub mod expiry;
+mod filter;
+mod index;
 mod interface;
+pub mod journal;
+pub mod transaction;
+pub mod validation;
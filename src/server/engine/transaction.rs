@@ -0,0 +1,211 @@
+use bson::{Bson, DateTime};
+use dustdata::Error as DustDataError;
+use std::collections::HashMap;
+
+use crate::query::parser::ASTNode;
+use crate::server::route::Router;
+
+use super::expiry;
+use super::index;
+
+/// The value and expiry of a key, both `None` if the key is missing
+pub type Snapshot = (Option<Bson>, Option<DateTime>);
+
+/// The statements of a transaction opened with `begin`, run together on `commit`
+#[derive(Default)]
+pub struct Transaction {
+    /// the statements to run, with the database each one was written on
+    pub statements: Vec<(String, ASTNode)>,
+    /// the keys named by the statements, as they were when first named, keyed by (database, key)
+    pub snapshots: HashMap<(String, String), Snapshot>,
+}
+
+/// `touched_keys` lists the keys a statement names, which it reads or writes
+///
+/// Arguments:
+///
+/// * `ast`: The statement.
+/// * `database`: The database the statement runs on.
+///
+/// Returns:
+///
+/// The (database, key) pairs. Statements that scan a whole database, such as `list`, name none,
+/// so they can't be run in a transaction.
+pub fn touched_keys(ast: &ASTNode, database: &str) -> Vec<(String, String)> {
+    let in_database = |node: &ASTNode| key_of(node).map(|key| (database.to_string(), key));
+
+    let keys = match ast {
+        ASTNode::IntoExpression { ident, .. }
+        | ASTNode::PatchExpression { ident, .. }
+        | ASTNode::CounterExpression { ident, .. }
+        | ASTNode::ExpireExpression { ident, .. }
+        | ASTNode::PersistExpression { ident }
+        | ASTNode::TtlExpression { ident } => vec![in_database(ident)],
+
+        ASTNode::SingleExpression {
+            ident: Some(ident), ..
+        } => vec![in_database(ident)],
+
+        ASTNode::MultiExpression { idents, .. } => idents.iter().map(in_database).collect(),
+
+        ASTNode::MultiIntoExpression { entries, .. } => {
+            entries.iter().map(|(_, key)| in_database(key)).collect()
+        }
+
+        ASTNode::RenameExpression { ident, to } => vec![in_database(ident), in_database(to)],
+
+        ASTNode::CopyExpression {
+            ident,
+            to,
+            database: target,
+        } => {
+            let target = match target.as_deref() {
+                Some(target) => key_of(target),
+                None => Some(database.to_string()),
+            };
+
+            vec![in_database(ident), target.zip(key_of(to))]
+        }
+
+        ASTNode::MoveExpression {
+            ident,
+            database: target,
+        } => vec![in_database(ident), key_of(target).zip(key_of(ident))],
+
+        _ => Vec::new(),
+    };
+
+    keys.into_iter().flatten().collect()
+}
+
+/// `restore` puts a key back as it was in a snapshot, with its expiry and index entries
+///
+/// Arguments:
+///
+/// * `routers`: The databases and indexes of the server.
+/// * `database`: The database of the key, nothing is restored if it no longer exists.
+/// * `key`: The key.
+/// * `snapshot`: The value and expiry the key had.
+pub fn restore(
+    routers: &mut HashMap<String, Router>,
+    database: &str,
+    key: &str,
+    snapshot: Snapshot,
+) -> Result<(), DustDataError> {
+    let dd = match routers.get_mut(database) {
+        Some(dd) => dd,
+        None => return Ok(()),
+    };
+
+    let (value, expire) = snapshot;
+    let exists = dd.get(key)?.is_some();
+
+    match &value {
+        Some(value) if exists => dd.update(key, value.clone())?,
+        Some(value) => dd.insert(key, value.clone())?,
+        None if exists => dd.delete(key)?,
+        None => {}
+    }
+
+    expiry::clear(dd, key)?;
+
+    if let Some(at) = expire {
+        expiry::set(dd, key, at)?;
+    }
+
+    index::reindex(routers, database, key, value.as_ref())
+}
+
+fn key_of(node: &ASTNode) -> Option<String> {
+    match node {
+        ASTNode::Identifier(key) | ASTNode::PathExpression { ident: key, .. } => Some(key.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Document;
+
+    use crate::query::parser::parse;
+
+    use super::touched_keys;
+
+    fn touched(rbql: &str) -> Vec<(String, String)> {
+        let mut statements = parse(rbql, &Document::new()).unwrap();
+
+        touched_keys(&statements.pop().unwrap().ast, "shop")
+    }
+
+    fn keys(keys: &[(&str, &str)]) -> Vec<(String, String)> {
+        keys.iter()
+            .map(|(database, key)| (database.to_string(), key.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn writes_name_their_key() {
+        assert_eq!(touched("insert 1 into a"), keys(&[("shop", "a")]));
+        assert_eq!(
+            touched("update a with {\"$inc\": {\"n\": 1}}"),
+            keys(&[("shop", "a")])
+        );
+        assert_eq!(touched("incr a by 2"), keys(&[("shop", "a")]));
+        assert_eq!(touched("delete a"), keys(&[("shop", "a")]));
+        assert_eq!(touched("expire a 60"), keys(&[("shop", "a")]));
+        assert_eq!(touched("persist a"), keys(&[("shop", "a")]));
+    }
+
+    #[test]
+    fn reads_name_their_key() {
+        assert_eq!(touched("get a"), keys(&[("shop", "a")]));
+        assert_eq!(touched("get a.b.0"), keys(&[("shop", "a")]));
+        assert_eq!(touched("ttl a"), keys(&[("shop", "a")]));
+    }
+
+    #[test]
+    fn statements_on_several_keys_name_each_key() {
+        assert_eq!(touched("get a, b"), keys(&[("shop", "a"), ("shop", "b")]));
+        assert_eq!(
+            touched("delete a, b"),
+            keys(&[("shop", "a"), ("shop", "b")])
+        );
+        assert_eq!(
+            touched("insert 1 into a, 2 into b"),
+            keys(&[("shop", "a"), ("shop", "b")])
+        );
+    }
+
+    #[test]
+    fn copies_and_moves_name_their_target() {
+        assert_eq!(
+            touched("rename a to b"),
+            keys(&[("shop", "a"), ("shop", "b")])
+        );
+        assert_eq!(
+            touched("copy a to b"),
+            keys(&[("shop", "a"), ("shop", "b")])
+        );
+        assert_eq!(
+            touched("copy a to b in archive"),
+            keys(&[("shop", "a"), ("archive", "b")])
+        );
+        assert_eq!(
+            touched("move a to archive"),
+            keys(&[("shop", "a"), ("archive", "a")])
+        );
+    }
+
+    #[test]
+    fn scans_name_no_key() {
+        for rbql in [
+            "list",
+            "list prefix \"user\" limit 10",
+            "get where age >= 18",
+            "count",
+            "sum total where status = \"paid\"",
+        ] {
+            assert_eq!(touched(rbql), Vec::new(), "{}", rbql);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use bson::{Bson, Document};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::utils;
+
+/// A JSON-Schema-like description of the values a database accepts, set by `create schema`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Schema {
+    /// the types the value can have, any if `None`
+    #[serde(rename = "type")]
+    pub types: Option<Types>,
+    /// the values the value can be one of
+    #[serde(rename = "enum")]
+    pub one_of: Option<Vec<Bson>>,
+    /// bounds of numbers
+    pub minimum: Option<f64>,
+    pub maximum: Option<f64>,
+    /// bounds of the length of strings, in characters
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+    /// glob pattern strings must match, see [`utils::glob_match`]
+    pub pattern: Option<String>,
+    /// fields documents must have
+    pub required: Option<Vec<String>>,
+    /// schemas of the fields of documents
+    pub properties: Option<HashMap<String, Schema>>,
+    /// whether documents can have fields missing from `properties`
+    pub additional_properties: Option<bool>,
+    /// schema of the elements of arrays
+    pub items: Option<Box<Schema>>,
+    /// bounds of the length of arrays
+    pub min_items: Option<usize>,
+    pub max_items: Option<usize>,
+}
+
+/// `"type": "string"` or `"type": ["string", "null"]`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged, try_from = "Bson")]
+pub enum Types {
+    One(Type),
+    Many(Vec<Type>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Type {
+    Object,
+    Array,
+    String,
+    /// any number, decimals included
+    Number,
+    /// 32 or 64-bit integers
+    Integer,
+    Boolean,
+    Null,
+    Date,
+    ObjectId,
+    Binary,
+}
+
+impl Schema {
+    /// `from_document` reads the schema written in a `create schema` statement
+    ///
+    /// Returns:
+    ///
+    /// The schema, or a message saying what is invalid in it.
+    pub fn from_document(schema: Document) -> Result<Self, String> {
+        bson::from_document(schema).map_err(|e| format!("invalid schema: {}", e))
+    }
+
+    /// `validate` checks a value against the schema
+    ///
+    /// Arguments:
+    ///
+    /// * `path`: The path of the value, its key for a whole value.
+    /// * `value`: The value to check.
+    ///
+    /// Returns:
+    ///
+    /// The violations, each prefixed with the path of the offending field, e.g.
+    /// `user_1.age: must be at least 0`. Empty if the value is valid.
+    pub fn validate(&self, path: &str, value: &Bson) -> Vec<String> {
+        let mut violations = Vec::new();
+
+        self.check(path, value, &mut violations);
+
+        violations
+    }
+
+    fn check(&self, path: &str, value: &Bson, violations: &mut Vec<String>) {
+        if let Some(types) = &self.types {
+            let types = match types {
+                Types::One(kind) => std::slice::from_ref(kind),
+                Types::Many(kinds) => kinds.as_slice(),
+            };
+
+            if !types.iter().any(|kind| kind.matches(value)) {
+                let expected = types
+                    .iter()
+                    .map(|kind| kind.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" or ");
+
+                violations.push(format!("{}: must be of type {}", path, expected));
+
+                // the other constraints are meaningless on a value of the wrong type
+                return;
+            }
+        }
+
+        if let Some(one_of) = &self.one_of {
+            if !one_of.contains(value) {
+                let allowed = one_of
+                    .iter()
+                    .map(|value| value.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+
+                violations.push(format!("{}: must be one of {}", path, allowed));
+            }
+        }
+
+        match value {
+            Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) => {
+                let number = match value {
+                    Bson::Int32(n) => *n as f64,
+                    Bson::Int64(n) => *n as f64,
+                    Bson::Double(n) => *n,
+                    _ => unreachable!(),
+                };
+
+                if let Some(minimum) = self.minimum {
+                    if number < minimum {
+                        violations.push(format!("{}: must be at least {}", path, minimum));
+                    }
+                }
+
+                if let Some(maximum) = self.maximum {
+                    if number > maximum {
+                        violations.push(format!("{}: must be at most {}", path, maximum));
+                    }
+                }
+            }
+
+            Bson::String(string) => {
+                let length = string.chars().count();
+
+                if let Some(min_length) = self.min_length {
+                    if length < min_length {
+                        violations.push(format!(
+                            "{}: must be at least {} characters long",
+                            path, min_length
+                        ));
+                    }
+                }
+
+                if let Some(max_length) = self.max_length {
+                    if length > max_length {
+                        violations.push(format!(
+                            "{}: must be at most {} characters long",
+                            path, max_length
+                        ));
+                    }
+                }
+
+                if let Some(pattern) = &self.pattern {
+                    if !utils::glob_match(pattern, string) {
+                        violations.push(format!("{}: must match \"{}\"", path, pattern));
+                    }
+                }
+            }
+
+            Bson::Document(document) => {
+                for field in self.required.iter().flatten() {
+                    if !document.contains_key(field) {
+                        violations.push(format!("{}.{}: is required", path, field));
+                    }
+                }
+
+                for (field, value) in document {
+                    let path = format!("{}.{}", path, field);
+
+                    match self.properties.as_ref().and_then(|p| p.get(field)) {
+                        Some(schema) => schema.check(&path, value, violations),
+                        None if self.additional_properties == Some(false) => {
+                            violations.push(format!("{}: is not allowed", path))
+                        }
+                        None => {}
+                    }
+                }
+            }
+
+            Bson::Array(array) => {
+                if let Some(min_items) = self.min_items {
+                    if array.len() < min_items {
+                        violations
+                            .push(format!("{}: must have at least {} items", path, min_items));
+                    }
+                }
+
+                if let Some(max_items) = self.max_items {
+                    if array.len() > max_items {
+                        violations.push(format!("{}: must have at most {} items", path, max_items));
+                    }
+                }
+
+                if let Some(items) = &self.items {
+                    for (i, item) in array.iter().enumerate() {
+                        items.check(&format!("{}.{}", path, i), item, violations);
+                    }
+                }
+            }
+
+            _ => {}
+        }
+    }
+}
+
+// parsed by hand so an unknown type is named in the error
+impl TryFrom<Bson> for Types {
+    type Error = bson::de::Error;
+
+    fn try_from(value: Bson) -> Result<Self, Self::Error> {
+        match value {
+            Bson::Array(types) => Ok(Types::Many(
+                types
+                    .into_iter()
+                    .map(bson::from_bson)
+                    .collect::<Result<_, _>>()?,
+            )),
+            value => Ok(Types::One(bson::from_bson(value)?)),
+        }
+    }
+}
+
+impl Type {
+    fn matches(&self, value: &Bson) -> bool {
+        matches!(
+            (self, value),
+            (Type::Object, Bson::Document(_))
+                | (Type::Array, Bson::Array(_))
+                | (Type::String, Bson::String(_))
+                | (
+                    Type::Number,
+                    Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_)
+                )
+                | (Type::Integer, Bson::Int32(_) | Bson::Int64(_))
+                | (Type::Boolean, Bson::Boolean(_))
+                | (Type::Null, Bson::Null)
+                | (Type::Date, Bson::DateTime(_))
+                | (Type::ObjectId, Bson::ObjectId(_))
+                | (Type::Binary, Bson::Binary(_))
+        )
+    }
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Type::Object => "object",
+            Type::Array => "array",
+            Type::String => "string",
+            Type::Number => "number",
+            Type::Integer => "integer",
+            Type::Boolean => "boolean",
+            Type::Null => "null",
+            Type::Date => "date",
+            Type::ObjectId => "objectId",
+            Type::Binary => "binary",
+        };
+
+        write!(f, "{}", name)
+    }
+}
@@ -1,4 +1,5 @@
 use async_trait::async_trait;
+use bson::{Bson, Document};
 use colored::Colorize;
 use dustdata::{DustData, DustDataConfig, LsmConfig, Size};
 use rayon::{ThreadPool, ThreadPoolBuilder};
@@ -15,63 +16,133 @@ use crate::server;
 
 use cache::Cache;
 use config::schema;
+use engine::catalog;
+use engine::changes::ChangeHub;
+use engine::channels::ChannelHub;
 use engine::core::Core;
-use server::route;
-use wirewave::server::{Error, Request, Response, Server, Status, Wirewave, WirewaveServer};
+use query::parser::Statement;
+use query::QueryDiagnostic;
+use server::route::{self, Router};
+use wirewave::server::{
+    Error, Request, ResHeader, Response, Server, Status, Wirewave, WirewaveServer,
+};
+use wirewave::session::Session;
 
 pub struct Database {
     pool: ThreadPool,
-    routers: Arc<RwLock<HashMap<String, DustData>>>,
+    routers: Arc<RwLock<HashMap<String, Router>>>,
     config: Arc<schema::RustbaseConfig>,
     cache: Arc<RwLock<Cache>>,
     system_db: Arc<RwLock<dustdata::DustData>>,
+    // serializes commits with the other statements, see `Core::new`
+    gate: Arc<RwLock<()>>,
+    changes: Arc<RwLock<ChangeHub>>,
+    channels: Arc<RwLock<ChannelHub>>,
 }
 
 #[async_trait]
 impl Wirewave for Database {
-    async fn request(&self, request: Request, username: Option<String>) -> Result<Response, Error> {
+    async fn request(&self, request: Request, session: &mut Session) -> Result<Response, Error> {
         let body = request.body;
 
         if body.is_empty() {
             let error = Error {
                 message: "Empty body".to_string(),
-                query_message: None,
+                diagnostic: None,
                 status: Status::BadBody,
             };
 
             return Err(error);
         }
 
-        if !body.contains_key("query") || !body.contains_key("database") {
-            let error = Error {
-                message: "Missing query or database".to_string(),
-                query_message: None,
-                status: Status::BadBody,
-            };
-
-            return Err(error);
+        let query = match body.get_str("query") {
+            Ok(query) => query,
+            Err(_) => {
+                let error = Error {
+                    message: "Missing query".to_string(),
+                    diagnostic: None,
+                    status: Status::BadBody,
+                };
+
+                return Err(error);
+            }
+        };
+
+        // without a database, the request runs on the one selected by the session
+        let database = match body.get("database") {
+            Some(Bson::String(database)) => Some(database.to_string()),
+            None => None,
+            _ => {
+                let error = Error {
+                    message: "database must be a string".to_string(),
+                    diagnostic: None,
+                    status: Status::BadBody,
+                };
+
+                return Err(error);
+            }
+        };
+
+        let on_error = match body.get_str("on_error") {
+            Ok("stop") | Err(bson::document::ValueAccessError::NotPresent) => OnError::Stop,
+            Ok("continue") => OnError::Continue,
+            _ => {
+                let error = Error {
+                    message: "on_error must be 'stop' or 'continue'".to_string(),
+                    diagnostic: None,
+                    status: Status::BadBody,
+                };
+
+                return Err(error);
+            }
+        };
+
+        // positional parameters can be sent as an array, `$1` being the first item
+        let mut params = match body.get("params") {
+            Some(Bson::Document(params)) => params.clone(),
+            Some(Bson::Array(params)) => params
+                .iter()
+                .enumerate()
+                .map(|(i, param)| ((i + 1).to_string(), param.clone()))
+                .collect(),
+            None => Document::new(),
+            _ => {
+                let error = Error {
+                    message: "params must be a document or an array".to_string(),
+                    diagnostic: None,
+                    status: Status::BadBody,
+                };
+
+                return Err(error);
+            }
+        };
+
+        // the variables of the session are bound to the placeholders missing from the params
+        for (name, value) in &session.variables {
+            if !params.contains_key(name) {
+                params.insert(name, value.clone());
+            }
         }
 
-        let database = body.get_str("database").unwrap();
-        let query = body.get_str("query").unwrap();
-
         self.pool
-            .install(move || match query::parser::parse(query) {
-                Err(e) => match e.0 {
+            .install(move || match query::parser::parse(query, &params) {
+                Err(e) => match e.error_type {
                     query::QueryErrorType::SyntaxError => {
                         let error = Error {
-                            message: e.1,
-                            query_message: None,
+                            message: e.message,
+                            diagnostic: e.diagnostic,
                             status: Status::SyntaxError,
                         };
 
                         Err(error)
                     }
 
-                    query::QueryErrorType::UnexpectedToken => {
+                    query::QueryErrorType::UnexpectedToken
+                    | query::QueryErrorType::InvalidLiteral
+                    | query::QueryErrorType::InvalidParameter => {
                         let error = Error {
-                            message: e.1,
-                            query_message: None,
+                            message: e.message,
+                            diagnostic: e.diagnostic,
                             status: Status::InvalidQuery,
                         };
 
@@ -79,40 +150,178 @@ impl Wirewave for Database {
                     }
                 },
 
-                Ok(query) => {
+                Ok(statements) => {
+                    if statements.is_empty() {
+                        let error = Error {
+                            message: "Empty query".to_string(),
+                            diagnostic: None,
+                            status: Status::InvalidQuery,
+                        };
+
+                        return Err(error);
+                    }
+
+                    // the user may have been updated or deleted since the last request
+                    session.refresh_permission(&self.system_db);
+
                     let mut core = Core::new(
                         self.cache.clone(),
                         self.routers.clone(),
                         self.config.clone(),
                         self.system_db.clone(),
-                        database.to_string(),
-                        username,
+                        database.clone(),
+                        session,
+                        self.gate.clone(),
+                        self.changes.clone(),
+                        self.channels.clone(),
                     );
 
-                    core.run_ast(query[0].clone())
+                    // the database sent with the request follows the rules of `use`
+                    if let Some(database) = &database {
+                        core.select_database(database)?;
+                    }
+
+                    Ok(run_statements(&mut core, query, statements, on_error))
                 }
             })
     }
 }
 
+/// What to do with the remaining statements of a program when one of them fails
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    Stop,
+    Continue,
+}
+
+/// `run_statement` runs a statement, pointing its errors at where it was written in the query
+///
+/// Arguments:
+///
+/// * `core`: The engine the statement is run on.
+/// * `query`: The query the statement comes from.
+/// * `statement`: The parsed statement.
+///
+/// Returns:
+///
+/// The response of the statement.
+fn run_statement(core: &mut Core, query: &str, statement: Statement) -> Result<Response, Error> {
+    let locate = || Some(QueryDiagnostic::new(query, statement.start, statement.end));
+
+    match core.run_ast(statement.ast) {
+        Ok(mut response) => {
+            if response.header.is_error && response.header.diagnostic.is_none() {
+                response.header.diagnostic = locate();
+            }
+
+            Ok(response)
+        }
+        Err(mut error) => {
+            if error.diagnostic.is_none() {
+                error.diagnostic = locate();
+            }
+
+            Err(error)
+        }
+    }
+}
+
+/// `run_statements` runs every statement of a program in order
+///
+/// Arguments:
+///
+/// * `core`: The engine the statements are run on.
+/// * `query`: The query the statements come from.
+/// * `statements`: The parsed statements of the program.
+/// * `on_error`: Whether to stop on the first failing statement or keep going.
+///
+/// Returns:
+///
+/// A response whose body is an array with one response document per executed statement, even
+/// when the program has a single statement. The header carries the status of the first failing
+/// statement, if any.
+fn run_statements(
+    core: &mut Core,
+    query: &str,
+    statements: Vec<Statement>,
+    on_error: OnError,
+) -> Response {
+    let mut results = Vec::with_capacity(statements.len());
+    let mut failed: Option<Status> = None;
+
+    for statement in statements {
+        let response = match run_statement(core, query, statement) {
+            Ok(response) => response,
+            Err(error) => Response::from(error),
+        };
+
+        let is_error = response.header.is_error;
+
+        if is_error && failed.is_none() {
+            failed = Some(response.header.status.clone());
+        }
+
+        results.push(bson::to_bson(&response).unwrap());
+
+        if is_error && on_error == OnError::Stop {
+            break;
+        }
+    }
+
+    Response {
+        body: Some(Bson::Array(results)),
+        header: ResHeader {
+            is_error: failed.is_some(),
+            messages: None,
+            status: failed.unwrap_or(Status::Ok),
+            diagnostic: None,
+        },
+    }
+}
+
 pub fn current_users(system_db: Arc<RwLock<DustData>>) -> usize {
     let dd = system_db.read().unwrap();
 
-    dd.list_keys().unwrap().len()
+    dd.list_keys()
+        .unwrap()
+        .iter()
+        .filter(|key| !catalog::is_reserved(key))
+        .count()
 }
 
 pub async fn initalize_server(config: schema::RustbaseConfig) {
     let config = Arc::new(config);
     let addr = format!("{}:{}", config.net.host, config.net.port);
 
-    let routers = route::initialize_dustdata(&config);
-    let cache = Arc::new(RwLock::new(Cache::new(config.cache_size)));
-
     let system_db = Arc::new(RwLock::new(DustData::new(default_dustdata_config(
         &config,
         Some("_default"),
     ))));
 
+    let routers = match route::initialize_dustdata(&config, &system_db.read().unwrap()) {
+        Ok(routers) => routers,
+        Err(e) => {
+            println!("[Route] failed to open the databases: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    match engine::journal::recover(
+        &mut routers.write().unwrap(),
+        &mut system_db.write().unwrap(),
+    ) {
+        Ok(0) => {}
+        Ok(restored) => println!(
+            "[Engine] undid a commit interrupted by a crash, {} keys restored",
+            restored
+        ),
+        Err(e) => println!("[Engine] failed to undo an interrupted commit: {:?}", e),
+    }
+
+    let cache = Arc::new(RwLock::new(Cache::new(config.cache_size)));
+    let changes = Arc::new(RwLock::new(ChangeHub::default()));
+    let gate = Arc::new(RwLock::new(()));
+
     let c_routers = routers.clone();
     let c_system_db = system_db.clone();
     ctrlc::set_handler(move || {
@@ -131,6 +340,26 @@ pub async fn initalize_server(config: schema::RustbaseConfig) {
     })
     .expect("Error setting Ctrl-C handler");
 
+    let r_routers = routers.clone();
+    let r_cache = cache.clone();
+    let r_changes = changes.clone();
+    let r_gate = gate.clone();
+    // the reaper blocks on the locks and on disk, so it runs on a thread of its own rather than
+    // on the runtime serving the connections
+    std::thread::spawn(move || {
+        let interval = std::time::Duration::from_millis(engine::expiry::REAP_INTERVAL);
+
+        loop {
+            std::thread::sleep(interval);
+
+            match engine::expiry::reap(&r_routers, &r_cache, &r_changes, &r_gate) {
+                Ok(0) => {}
+                Ok(purged) => println!("[Engine] purged {} expired keys", purged),
+                Err(e) => println!("[Engine] failed to purge expired keys: {:?}", e),
+            }
+        }
+    });
+
     let pool = ThreadPoolBuilder::new()
         .num_threads(config.threads)
         .build()
@@ -142,6 +371,9 @@ pub async fn initalize_server(config: schema::RustbaseConfig) {
         cache,
         config: Arc::clone(&config),
         system_db: Arc::clone(&system_db),
+        gate,
+        changes,
+        channels: Arc::new(RwLock::new(ChannelHub::default())),
     };
     let svc = WirewaveServer::new(database);
 
@@ -1,16 +1,111 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fs;
+use std::ops::Bound;
 use std::path;
 use std::path::Path;
 use std::sync::Arc;
 use std::sync::RwLock;
 
 use crate::config::schema;
+use bson::{Bson, DateTime};
 use colored::Colorize;
-use dustdata::DustData;
+use dustdata::Error as DustDataError;
+use dustdata::{DustData, Size};
 
+use super::engine::catalog::{self, DatabaseOptions};
+use super::engine::expiry;
 use super::main::default_dustdata_config;
 
+/// A database, or an index, with its keys kept sorted in memory so they can be read in order
+/// from any key without listing the whole database
+///
+/// The keys are all loaded when the database is opened and stay in memory while it is open: a
+/// database costs about the size of its keys plus some 50 bytes per key (the nodes of the set),
+/// on top of what DustData keeps itself. The same goes for the keys set to expire.
+pub struct Router {
+    dustdata: DustData,
+    // the keys holding metadata start with `_` and are not kept, statements can't name them
+    keys: BTreeSet<String>,
+    /// the expiring keys by the time they expire, kept up to date by [`expiry`]
+    pub expiries: BTreeSet<(DateTime, String)>,
+}
+
+impl Router {
+    /// `new` loads the keys of a database and when they expire
+    pub fn new(dustdata: DustData) -> Result<Self, DustDataError> {
+        let keys = dustdata
+            .list_keys()?
+            .into_iter()
+            .filter(|key| !catalog::is_reserved(key))
+            .collect();
+
+        let expiries = expiry::load_schedule(&dustdata)?;
+
+        Ok(Self {
+            dustdata,
+            keys,
+            expiries,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Bson>, DustDataError> {
+        self.dustdata.get(key)
+    }
+
+    pub fn insert(&mut self, key: &str, value: Bson) -> Result<(), DustDataError> {
+        self.dustdata.insert(key, value)?;
+
+        if !catalog::is_reserved(key) {
+            self.keys.insert(key.to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn update(&mut self, key: &str, value: Bson) -> Result<(), DustDataError> {
+        self.dustdata.update(key, value)
+    }
+
+    pub fn delete(&mut self, key: &str) -> Result<(), DustDataError> {
+        self.dustdata.delete(key)?;
+        self.keys.remove(key);
+
+        Ok(())
+    }
+
+    /// `list_keys` lists every key, metadata included, in no particular order
+    pub fn list_keys(&self) -> Result<Vec<String>, DustDataError> {
+        self.dustdata.list_keys()
+    }
+
+    /// `range` iterates over the keys within bounds, sorted and without metadata
+    pub fn range(&self, from: Bound<&str>, to: Bound<&str>) -> impl Iterator<Item = &String> + '_ {
+        // `BTreeSet::range` panics on bounds that cross, they just leave no key here
+        let crossed = match (from, to) {
+            (Bound::Included(from) | Bound::Excluded(from), Bound::Excluded(to)) => from >= to,
+            (Bound::Included(from), Bound::Included(to)) => from > to,
+            (Bound::Excluded(from), Bound::Included(to)) => from >= to,
+            _ => false,
+        };
+
+        let keys = match crossed {
+            true => None,
+            false => Some(self.keys.range::<str, _>((from, to))),
+        };
+
+        keys.into_iter().flatten()
+    }
+
+    pub fn flush(&mut self) -> Result<(), DustDataError> {
+        self.dustdata.flush()
+    }
+
+    /// `drop_files` deletes what the database has on disk, once it is removed from the routers
+    pub fn drop_files(&mut self) {
+        self.dustdata.lsm.drop();
+    }
+}
+
 pub fn get_existing_routes(data_path: &Path) -> Vec<String> {
     let mut routes = Vec::new();
 
@@ -34,16 +129,22 @@ pub fn get_existing_routes(data_path: &Path) -> Vec<String> {
     routes
 }
 
+/// `initialize_dustdata` opens every database found on disk
+///
+/// Returns:
+///
+/// The databases by name, or the error of the first one that could not be opened.
 pub fn initialize_dustdata(
     config: &schema::RustbaseConfig,
-) -> Arc<RwLock<HashMap<String, DustData>>> {
+    system_db: &DustData,
+) -> Result<Arc<RwLock<HashMap<String, Router>>>, DustDataError> {
     let mut routers = HashMap::new();
 
     let path = path::Path::new(&config.storage.path);
     let routes = get_existing_routes(path);
 
     let dd = dustdata::initialize(default_dustdata_config(config, Some("_default")));
-    routers.insert("_default".to_string(), dd);
+    routers.insert("_default".to_string(), Router::new(dd)?);
 
     if !routes.is_empty() {
         for route in routes {
@@ -51,13 +152,44 @@ pub fn initialize_dustdata(
                 continue;
             }
 
-            let dd = dustdata::initialize(default_dustdata_config(config, Some(&route)));
+            let options = catalog::get(system_db, &route)?.unwrap_or_default();
+            let dd = create_dustdata_with(config, &route, &options)?;
 
             routers.insert(route, dd);
         }
     }
 
-    Arc::new(RwLock::new(routers))
+    Ok(Arc::new(RwLock::new(routers)))
+}
+
+/// `dustdata_size` sums the size of the files of a database on disk
+///
+/// Arguments:
+///
+/// * `data_path`: The path the databases are stored in.
+/// * `route`: The name of the database.
+///
+/// Returns:
+///
+/// The size in bytes, 0 if the database has nothing on disk yet.
+pub fn dustdata_size(data_path: &Path, route: &str) -> u64 {
+    fn dir_size(path: &Path) -> u64 {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(_) => return 0,
+        };
+
+        entries
+            .flatten()
+            .map(|entry| match entry.metadata() {
+                Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+                Ok(metadata) => metadata.len(),
+                Err(_) => 0,
+            })
+            .sum()
+    }
+
+    dir_size(&data_path.join(route))
 }
 
 pub fn remove_dustdata(data_path: &Path, route: String) {
@@ -68,6 +200,26 @@ pub fn remove_dustdata(data_path: &Path, route: String) {
     }
 }
 
-pub fn create_dustdata(config: &schema::RustbaseConfig, database: Option<&str>) -> DustData {
-    dustdata::initialize(default_dustdata_config(config, database))
+pub fn create_dustdata(
+    config: &schema::RustbaseConfig,
+    database: Option<&str>,
+) -> Result<Router, DustDataError> {
+    Router::new(dustdata::initialize(default_dustdata_config(
+        config, database,
+    )))
+}
+
+/// `create_dustdata_with` opens a database with the options it was created with
+pub fn create_dustdata_with(
+    config: &schema::RustbaseConfig,
+    database: &str,
+    options: &DatabaseOptions,
+) -> Result<Router, DustDataError> {
+    let mut dustdata_config = default_dustdata_config(config, Some(database));
+
+    if let Some(flush_threshold) = options.flush_threshold {
+        dustdata_config.lsm_config.flush_threshold = Size::Bytes(flush_threshold);
+    }
+
+    Router::new(dustdata::initialize(dustdata_config))
 }
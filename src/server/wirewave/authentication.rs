@@ -4,6 +4,7 @@ use std::sync::{Arc, RwLock};
 
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
+use super::super::engine::catalog;
 use super::server;
 
 use server::read_socket;
@@ -16,6 +17,11 @@ pub struct DefaultAuthenticationProvider {
 
 impl AuthenticationProvider for DefaultAuthenticationProvider {
     fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+        // the catalog shares the system database with the users, but can't be logged in as
+        if catalog::is_reserved(username) {
+            return None;
+        }
+
         let dustdata = self.dustdata.read().unwrap();
 
         let user = dustdata.get(username).unwrap();
@@ -52,6 +58,7 @@ fn process_authentication_request(buffer: &[u8]) -> Result<AuthRequest, Response
                     is_error: true,
                     status: Status::BadBody,
                     messages: Some(vec![e.to_string()]),
+                    diagnostic: None,
                 },
             };
 
@@ -0,0 +1,156 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+use super::server::Response;
+
+/// How many pushed frames a connection can have waiting before they are refused
+pub const MAILBOX_SIZE: usize = 4096;
+
+/// The frames the server pushes to a connection on its own, such as the changes of a `watch`,
+/// written to the socket between responses
+#[derive(Default)]
+pub struct Mailbox {
+    frames: Mutex<Frames>,
+    notify: Notify,
+    closed: AtomicBool,
+}
+
+#[derive(Default)]
+struct Frames {
+    // each frame with the subscription it was pushed for, if any
+    queue: VecDeque<(Option<u64>, Response)>,
+    // the number of waiting frames of each subscription
+    pending: HashMap<u64, usize>,
+}
+
+impl Mailbox {
+    /// `push` queues a frame for the connection
+    ///
+    /// Returns:
+    ///
+    /// Whether the frame was queued, `false` if `MAILBOX_SIZE` frames are already waiting or the
+    /// connection is closed.
+    pub fn push(&self, frame: Response) -> bool {
+        self.push_for(None, frame)
+    }
+
+    /// `push_owned` queues a frame pushed for a subscription, see `pending`
+    ///
+    /// Returns:
+    ///
+    /// Whether the frame was queued, like `push`.
+    pub fn push_owned(&self, owner: u64, frame: Response) -> bool {
+        self.push_for(Some(owner), frame)
+    }
+
+    fn push_for(&self, owner: Option<u64>, frame: Response) -> bool {
+        if self.is_closed() {
+            return false;
+        }
+
+        let mut frames = self.frames.lock().unwrap();
+
+        if frames.queue.len() >= MAILBOX_SIZE {
+            return false;
+        }
+
+        if let Some(owner) = owner {
+            *frames.pending.entry(owner).or_default() += 1;
+        }
+
+        frames.queue.push_back((owner, frame));
+        self.notify.notify_one();
+
+        true
+    }
+
+    /// `push_anyway` queues a frame even if the mailbox is full, for the notices telling a
+    /// connection that frames were refused
+    pub fn push_anyway(&self, frame: Response) {
+        self.frames.lock().unwrap().queue.push_back((None, frame));
+        self.notify.notify_one();
+    }
+
+    /// `pending` counts the frames of a subscription that are still waiting
+    pub fn pending(&self, owner: u64) -> usize {
+        let frames = self.frames.lock().unwrap();
+
+        frames.pending.get(&owner).copied().unwrap_or(0)
+    }
+
+    /// `drop_oldest` drops the oldest waiting frame of a subscription, if it has one
+    ///
+    /// Arguments:
+    ///
+    /// * `owner`: The subscription.
+    /// * `carry`: Called with the dropped frame and the next waiting frame of the subscription, if
+    ///   any, to carry what the dropped frame said over to the frame written in its place.
+    pub fn drop_oldest<F>(&self, owner: u64, carry: F)
+    where
+        F: FnOnce(Response, Option<&mut Response>),
+    {
+        let mut frames = self.frames.lock().unwrap();
+
+        let i = match frames.queue.iter().position(|(o, _)| *o == Some(owner)) {
+            Some(i) => i,
+            None => return,
+        };
+
+        let (_, dropped) = frames.queue.remove(i).unwrap();
+
+        frames.forget(owner);
+
+        let next = frames
+            .queue
+            .iter_mut()
+            .skip(i)
+            .find(|(o, _)| *o == Some(owner))
+            .map(|(_, frame)| frame);
+
+        carry(dropped, next);
+    }
+
+    /// `wait` waits until frames are queued
+    pub async fn wait(&self) {
+        self.notify.notified().await
+    }
+
+    /// `take` takes the queued frames, in the order they were pushed
+    pub fn take(&self) -> Vec<Response> {
+        let mut frames = self.frames.lock().unwrap();
+        let queue = std::mem::take(&mut frames.queue);
+
+        frames.pending.clear();
+
+        queue.into_iter().map(|(_, frame)| frame).collect()
+    }
+
+    /// `close` refuses the next frames, once the connection is closed
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+
+        let mut frames = self.frames.lock().unwrap();
+
+        frames.queue.clear();
+        frames.pending.clear();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+impl Frames {
+    fn forget(&mut self, owner: u64) {
+        if let Some(pending) = self.pending.get_mut(&owner) {
+            *pending -= 1;
+
+            if *pending == 0 {
+                self.pending.remove(&owner);
+            }
+        }
+    }
+}
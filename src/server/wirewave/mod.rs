@@ -1,3 +1,5 @@
 mod authentication;
 pub mod authorization;
+pub mod mailbox;
 pub mod server;
+pub mod session;
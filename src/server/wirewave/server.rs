@@ -2,7 +2,6 @@ use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 
 use std::fs::File;
-use std::future::Future;
 use std::io::{self, BufReader};
 use std::sync::{Arc, RwLock};
 
@@ -17,11 +16,14 @@ use rustbase_scram::{AuthenticationStatus, ScramServer};
 
 use super::super::main::current_users;
 use super::authentication;
+use super::session::Session;
 use crate::config;
+use crate::query;
 
 use authentication::authentication_challenge;
 
 use config::schema::Tls;
+use query::QueryDiagnostic;
 
 fn load_certs(path: &String) -> io::Result<Vec<Certificate>> {
     certs(&mut BufReader::new(File::open(path)?))
@@ -39,7 +41,7 @@ const BUFFER_SIZE: usize = 8 * 1024;
 
 #[async_trait]
 pub trait Wirewave: Send + Sync + 'static {
-    async fn request(&self, request: Request, username: Option<String>) -> Result<Response, Error>;
+    async fn request(&self, request: Request, session: &mut Session) -> Result<Response, Error>;
 }
 
 pub struct WirewaveServer<T: Wirewave> {
@@ -89,7 +91,7 @@ impl<T: Wirewave> Server<T> {
             tokio::spawn(async move {
                 println!("[Wirewave] incoming connection: {}", addr);
 
-                let users = current_users(system_db);
+                let users = current_users(system_db.clone());
 
                 let require_authentication = users > 0;
 
@@ -108,12 +110,9 @@ impl<T: Wirewave> Server<T> {
                     None
                 };
 
-                handle_connection(stream, move |request| {
-                    let svc = svc.clone();
-                    let username = username.clone();
-                    async move { svc.inner.0.request(request, username).await }
-                })
-                .await;
+                let session = Session::new(username, &system_db);
+
+                handle_connection(stream, svc, session).await;
             });
         }
     }
@@ -149,7 +148,7 @@ impl<T: Wirewave> Server<T> {
 
                 println!("[Wirewave] incoming connection: {}", addr);
 
-                let users = current_users(system_db);
+                let users = current_users(system_db.clone());
 
                 let require_authentication = users > 0;
 
@@ -168,12 +167,9 @@ impl<T: Wirewave> Server<T> {
                     None
                 };
 
-                handle_connection(stream, move |request| {
-                    let svc = svc.clone();
-                    let username = username.clone();
-                    async move { svc.inner.0.request(request, username).await }
-                })
-                .await;
+                let session = Session::new(username, &system_db);
+
+                handle_connection(stream, svc, session).await;
             });
         }
     }
@@ -227,20 +223,40 @@ pub struct ResHeader {
     pub status: Status,
     pub messages: Option<Vec<String>>,
     pub is_error: bool,
+    /// where the error is in the query, for errors caused by a query
+    pub diagnostic: Option<QueryDiagnostic>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Error {
     pub message: String,
-    pub query_message: Option<String>,
+    pub diagnostic: Option<QueryDiagnostic>,
     pub status: Status,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl From<Error> for Response {
+    fn from(error: Error) -> Self {
+        Response {
+            body: None,
+            header: ResHeader {
+                status: error.status,
+                messages: Some(vec![error.message]),
+                is_error: true,
+                diagnostic: error.diagnostic,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum Status {
     Ok,
     Inserted,
     Updated,
+    Skipped,
+    Queued,
+    Event,
+    Message,
 
     // ----
     InvalidQuery,
@@ -252,6 +268,12 @@ pub enum Status {
     NotAuthorized,
     Reserved,
     SyntaxError,
+    TypeMismatch,
+    Conflict,
+    ReadOnly,
+    QuotaExceeded,
+    SchemaViolation,
+    ConstraintViolation,
 
     // ----
     InternalError,
@@ -268,6 +290,7 @@ fn process_request(buf: &[u8]) -> Result<Request, Response> {
                     status: Status::BadBson,
                     messages: Some(vec![e.to_string()]),
                     is_error: true,
+                    diagnostic: None,
                 },
             };
 
@@ -301,21 +324,50 @@ where
     Ok(request_bytes)
 }
 
-async fn handle_connection<F, Fut, IO>(mut socket: IO, callback: F)
+async fn handle_connection<T, IO>(mut socket: IO, svc: WirewaveServer<T>, mut session: Session)
 where
-    F: Fn(Request) -> Fut,
-    Fut: Future<Output = Result<Response, Error>>,
+    T: Wirewave,
     IO: AsyncRead + AsyncWrite + Unpin,
 {
     let mut buffer = vec![0; BUFFER_SIZE];
+    let mailbox = session.mailbox.clone();
 
     loop {
-        let request_bytes = read_socket(&mut socket, &mut buffer).await.unwrap();
+        // the frames pushed by watches are written between responses, as soon as they are queued
+        let read = tokio::select! {
+            read = socket.read(&mut buffer) => Some(read),
+            _ = mailbox.wait() => None,
+        };
+
+        let n = match read {
+            Some(Ok(n)) => n,
+            Some(Err(_)) => break,
+            None => {
+                for frame in mailbox.take() {
+                    let frame = bson::to_vec(&bson::to_bson(&frame).unwrap()).unwrap();
 
-        if request_bytes.is_empty() {
+                    socket.write_all(&frame).await.ok();
+                }
+
+                continue;
+            }
+        };
+
+        if n == 0 {
+            socket.shutdown().await.ok();
+            socket.flush().await.ok();
             break;
         }
 
+        let mut request_bytes = buffer[..n].to_vec();
+
+        if n == BUFFER_SIZE {
+            match read_socket(&mut socket, &mut buffer).await {
+                Ok(rest) => request_bytes.extend_from_slice(&rest),
+                Err(_) => break,
+            }
+        }
+
         match process_request(&request_bytes[..]) {
             Ok(request) => {
                 let request = match request.header.type_ {
@@ -326,6 +378,7 @@ where
                                 status: Status::Ok,
                                 messages: None,
                                 is_error: false,
+                                diagnostic: None,
                             },
                         };
 
@@ -340,16 +393,9 @@ where
                     _ => request,
                 };
 
-                let response = match callback(request).await {
+                let response = match svc.inner.0.request(request, &mut session).await {
                     Ok(response) => response,
-                    Err(error) => Response {
-                        body: None,
-                        header: ResHeader {
-                            status: error.status,
-                            messages: Some(vec![error.message]),
-                            is_error: true,
-                        },
-                    },
+                    Err(error) => Response::from(error),
                 };
 
                 let response = bson::to_bson(&response).unwrap();
@@ -364,4 +410,6 @@ where
             }
         }
     }
+
+    mailbox.close();
 }
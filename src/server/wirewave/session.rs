@@ -0,0 +1,67 @@
+use bson::Document;
+use std::sync::{Arc, RwLock};
+
+use super::authorization::UserPermission;
+use super::mailbox::Mailbox;
+use crate::server::engine::transaction::Transaction;
+
+/// The state of a connection, kept from one request to the next
+#[derive(Default)]
+pub struct Session {
+    /// the authenticated user, `None` when the server has no users
+    pub username: Option<String>,
+    /// the permission of the user, resolved again before each request, see `refresh_permission`
+    pub permission: Option<UserPermission>,
+    /// the database selected with `use`
+    pub database: Option<String>,
+    /// the variables set with `name = value`, bound to placeholders not sent in `params`
+    pub variables: Document,
+    /// the transaction opened with `begin`, dropped with the connection if it is not committed
+    pub transaction: Option<Transaction>,
+    /// the frames pushed by the watches of the connection
+    pub mailbox: Arc<Mailbox>,
+}
+
+impl Session {
+    /// `new` starts the session of a connection
+    ///
+    /// Arguments:
+    ///
+    /// * `username`: The authenticated user, if the server requires authentication.
+    /// * `system_db`: The database the users are stored in.
+    ///
+    /// Returns:
+    ///
+    /// A session with the permission of the user and no database selected.
+    pub fn new(username: Option<String>, system_db: &RwLock<dustdata::DustData>) -> Self {
+        let permission = resolve_permission(username.as_deref(), system_db);
+
+        Self {
+            username,
+            permission,
+            database: None,
+            variables: Document::new(),
+            transaction: None,
+            mailbox: Arc::new(Mailbox::default()),
+        }
+    }
+
+    /// `refresh_permission` reads the permission of the user again, so a user updated or
+    /// deleted while the connection is open doesn't keep the rights it had when it connected
+    pub fn refresh_permission(&mut self, system_db: &RwLock<dustdata::DustData>) {
+        self.permission = resolve_permission(self.username.as_deref(), system_db);
+    }
+}
+
+fn resolve_permission(
+    username: Option<&str>,
+    system_db: &RwLock<dustdata::DustData>,
+) -> Option<UserPermission> {
+    let username = username?;
+    let dd = system_db.read().unwrap();
+    let user = dd.get(username).ok()??;
+
+    let permission = user.as_document()?.get_i32("permission").ok()?;
+
+    UserPermission::from_i32(permission).ok()
+}
@@ -5,3 +5,104 @@ pub fn get_current_path() -> PathBuf {
 
     Path::new(&exe).parent().unwrap().to_path_buf()
 }
+
+/// `to_hex` encodes bytes as lowercase hexadecimal
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// `from_hex` decodes hexadecimal, returning `None` if it is not valid hexadecimal
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some((hex_digit(*high)? << 4) | hex_digit(*low)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// `glob_match` checks if a text matches a glob pattern
+///
+/// Arguments:
+///
+/// * `pattern`: The pattern, where `*` matches any run of characters, `?` one character, `[a-z]`
+///   one character of a set (`[!a-z]` one outside of it) and `\` escapes the next character.
+/// * `text`: The text to match.
+///
+/// Returns:
+///
+/// Whether the whole text matches the pattern.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    let (mut p, mut t) = (0, 0);
+
+    // where to resume after the last `*`, to let it match one more character
+    let mut backtrack = None;
+
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match_class(&pattern[p..], text[t]),
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(2),
+            Some(c) => (*c == text[t]).then_some(1),
+            None => None,
+        };
+
+        match (step, backtrack) {
+            (Some(step), _) => {
+                p += step;
+                t += 1;
+            }
+            (None, Some((star, from))) => {
+                p = star + 1;
+                t = from + 1;
+                backtrack = Some((star, from + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// matches a `[...]` class at the start of `pattern`, returning its length if `c` is in it; an
+// unclosed `[` is a literal
+fn match_class(pattern: &[char], c: char) -> Option<usize> {
+    let end = match pattern.iter().skip(2).position(|c| *c == ']') {
+        Some(end) => end + 2,
+        None => return (c == '[').then_some(1),
+    };
+
+    let (negated, set) = match pattern[1] {
+        '!' | '^' => (true, &pattern[2..end]),
+        _ => (false, &pattern[1..end]),
+    };
+
+    let mut matched = false;
+    let mut i = 0;
+
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == '-' {
+            matched |= set[i] <= c && c <= set[i + 2];
+            i += 3;
+        } else {
+            matched |= set[i] == c;
+            i += 1;
+        }
+    }
+
+    (matched != negated).then_some(end + 1)
+}